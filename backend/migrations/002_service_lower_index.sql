@@ -0,0 +1,2 @@
+-- Expression index backing case-insensitive service filtering (service_ci=true)
+CREATE INDEX IF NOT EXISTS idx_logs_service_lower ON logs(LOWER(service));
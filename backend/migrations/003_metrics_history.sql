@@ -0,0 +1,11 @@
+-- Periodic snapshots of /metrics, kept independently of the logs they summarize
+CREATE TABLE IF NOT EXISTS metrics_history (
+    id BIGSERIAL PRIMARY KEY,
+    taken_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    total_logs BIGINT NOT NULL,
+    error_rate DOUBLE PRECISION NOT NULL,
+    services JSONB NOT NULL DEFAULT '{}',
+    levels JSONB NOT NULL DEFAULT '{}'
+);
+
+CREATE INDEX IF NOT EXISTS idx_metrics_history_taken_at ON metrics_history(taken_at);
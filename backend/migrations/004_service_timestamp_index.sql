@@ -0,0 +1,3 @@
+-- Serves service-scoped, time-ordered reads (ORDER BY timestamp DESC with service = $n,
+-- optionally bounded by from/to) without sorting every row of the service
+CREATE INDEX IF NOT EXISTS idx_logs_service_timestamp ON logs(service, timestamp);
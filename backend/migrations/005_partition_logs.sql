@@ -0,0 +1,41 @@
+-- Range-partition logs by timestamp so retention can drop whole partitions instead of
+-- deleting rows. The existing table becomes the default partition, which keeps every row
+-- written before this migration and anything no time partition covers; the partition
+-- maintenance task creates the time partitions new logs go into.
+ALTER TABLE logs RENAME TO logs_default;
+-- Replaced by the parent's (id, timestamp) key, whose index still serves lookups by id
+ALTER TABLE logs_default DROP CONSTRAINT logs_pkey;
+ALTER INDEX idx_logs_timestamp RENAME TO logs_default_timestamp_idx;
+ALTER INDEX idx_logs_service RENAME TO logs_default_service_idx;
+ALTER INDEX idx_logs_level RENAME TO logs_default_level_idx;
+ALTER INDEX idx_logs_service_level RENAME TO logs_default_service_level_idx;
+ALTER INDEX idx_logs_created_at RENAME TO logs_default_created_at_idx;
+ALTER INDEX idx_logs_metadata RENAME TO logs_default_metadata_idx;
+ALTER INDEX idx_logs_service_lower RENAME TO logs_default_service_lower_idx;
+ALTER INDEX idx_logs_service_timestamp RENAME TO logs_default_service_timestamp_idx;
+
+-- Unique constraints on a partitioned table must include the partition key; ids are
+-- random UUIDs, so uniqueness of id alone is not at risk
+CREATE TABLE logs (
+    id UUID NOT NULL DEFAULT uuid_generate_v4(),
+    timestamp TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    service VARCHAR(255) NOT NULL,
+    level VARCHAR(50) NOT NULL,
+    message TEXT NOT NULL,
+    metadata JSONB DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (id, timestamp)
+) PARTITION BY RANGE (timestamp);
+
+-- Declared on the parent so every partition gets them; the renamed indexes of the
+-- default partition are attached to these rather than rebuilt
+CREATE INDEX idx_logs_timestamp ON logs(timestamp);
+CREATE INDEX idx_logs_service ON logs(service);
+CREATE INDEX idx_logs_level ON logs(level);
+CREATE INDEX idx_logs_service_level ON logs(service, level);
+CREATE INDEX idx_logs_created_at ON logs(created_at);
+CREATE INDEX idx_logs_metadata ON logs USING GIN(metadata);
+CREATE INDEX idx_logs_service_lower ON logs(LOWER(service));
+CREATE INDEX idx_logs_service_timestamp ON logs(service, timestamp);
+
+ALTER TABLE logs ATTACH PARTITION logs_default DEFAULT;
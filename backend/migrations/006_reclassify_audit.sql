@@ -0,0 +1,20 @@
+-- One row per POST /logs/reclassify, with the level every affected log had before, so
+-- an operation can be rolled back
+CREATE TABLE IF NOT EXISTS reclassify_operations (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    message_pattern TEXT NOT NULL,
+    service VARCHAR(255),
+    old_level VARCHAR(50) NOT NULL,
+    new_level VARCHAR(50) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    rolled_back_at TIMESTAMPTZ
+);
+
+-- Logs are keyed by (id, timestamp) since partitioning, so both are kept
+CREATE TABLE IF NOT EXISTS reclassify_changes (
+    operation_id UUID NOT NULL REFERENCES reclassify_operations(id) ON DELETE CASCADE,
+    log_id UUID NOT NULL,
+    log_timestamp TIMESTAMPTZ NOT NULL,
+    previous_level VARCHAR(50) NOT NULL,
+    PRIMARY KEY (operation_id, log_id, log_timestamp)
+);
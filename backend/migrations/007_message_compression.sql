@@ -0,0 +1,3 @@
+-- Gzip of the full message for messages stored compressed, which then keep only their
+-- beginning in `message`; NULL for every other log
+ALTER TABLE logs ADD COLUMN IF NOT EXISTS message_gzip BYTEA;
@@ -0,0 +1,7 @@
+-- Named filter sets for GET /queries/{name}/logs. `filters` holds the LogFilters JSON
+-- with unset filters left out.
+CREATE TABLE IF NOT EXISTS saved_queries (
+    name VARCHAR(100) PRIMARY KEY,
+    filters JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,46 @@
+-- no-transaction
+-- Server-assigned insert order, independent of clock timestamps, for the after_seq
+-- cursor. Adding the column without a default rewrites no partition, so existing rows are
+-- then numbered one partition at a time, each in its own transaction, before new inserts
+-- start taking numbers from the sequence; the index is built the same way. It is all one
+-- statement because a multi-statement migration runs in an implicit transaction, where the
+-- block could not commit between partitions.
+DO $$
+DECLARE
+    part TEXT;
+BEGIN
+    ALTER TABLE logs ADD COLUMN IF NOT EXISTS seq BIGINT;
+    CREATE SEQUENCE IF NOT EXISTS logs_seq_seq OWNED BY logs.seq;
+    -- Not unique: unique indexes on a partitioned table must include the partition key
+    CREATE INDEX IF NOT EXISTS idx_logs_seq ON ONLY logs (seq);
+    COMMIT;
+
+    FOR part IN
+        SELECT c.relname FROM pg_inherits p JOIN pg_class c ON c.oid = p.inhrelid
+        WHERE p.inhparent = 'logs'::regclass ORDER BY 1
+    LOOP
+        EXECUTE format('UPDATE %I SET seq = nextval(''logs_seq_seq'') WHERE seq IS NULL', part);
+        COMMIT;
+    END LOOP;
+
+    ALTER TABLE logs ALTER COLUMN seq SET DEFAULT nextval('logs_seq_seq');
+    COMMIT;
+    -- Logs inserted while the partitions were being numbered
+    UPDATE logs SET seq = nextval('logs_seq_seq') WHERE seq IS NULL;
+    COMMIT;
+
+    -- Partitions attached after the parent index was created already have theirs
+    FOR part IN
+        SELECT c.relname FROM pg_inherits p JOIN pg_class c ON c.oid = p.inhrelid
+        WHERE p.inhparent = 'logs'::regclass
+          AND NOT EXISTS (
+              SELECT 1 FROM pg_inherits i JOIN pg_index x ON x.indexrelid = i.inhrelid
+              WHERE i.inhparent = 'idx_logs_seq'::regclass AND x.indrelid = p.inhrelid
+          )
+        ORDER BY 1
+    LOOP
+        EXECUTE format('CREATE INDEX IF NOT EXISTS %I ON %I (seq)', part || '_seq_idx', part);
+        EXECUTE format('ALTER INDEX idx_logs_seq ATTACH PARTITION %I', part || '_seq_idx');
+        COMMIT;
+    END LOOP;
+END $$;
@@ -0,0 +1,11 @@
+-- One row per hour of logs exported to S3 by the archive task; the task resumes after
+-- the latest period_end
+CREATE TABLE IF NOT EXISTS archive_exports (
+    period_start TIMESTAMPTZ PRIMARY KEY,
+    period_end TIMESTAMPTZ NOT NULL,
+    object_key TEXT NOT NULL,
+    log_count BIGINT NOT NULL,
+    exported_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- Set once the hour's logs were deleted locally (ARCHIVE_DELETE)
+    deleted_at TIMESTAMPTZ
+);
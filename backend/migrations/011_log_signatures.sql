@@ -0,0 +1,3 @@
+-- Hex HMAC-SHA256 of a log's service, level, message and timestamp, set at ingestion
+-- while SIGNING_KEY is configured; NULL for logs stored without it
+ALTER TABLE logs ADD COLUMN IF NOT EXISTS signature TEXT;
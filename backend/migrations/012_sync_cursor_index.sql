@@ -0,0 +1,2 @@
+-- Serves GET /logs/sync, which pages through logs in (created_at, id) order
+CREATE INDEX IF NOT EXISTS idx_logs_created_at_id ON logs (created_at, id);
@@ -0,0 +1,7 @@
+-- The created_at up to which an archived hour's logs are in its object. Logs stored later
+-- with a timestamp in the hour are exported on a later run, and only logs up to this point
+-- are deleted locally. Hours exported before this column existed count as complete at
+-- their export time.
+ALTER TABLE archive_exports ADD COLUMN IF NOT EXISTS exported_through TIMESTAMPTZ;
+UPDATE archive_exports SET exported_through = exported_at WHERE exported_through IS NULL;
+ALTER TABLE archive_exports ALTER COLUMN exported_through SET NOT NULL;
@@ -0,0 +1,251 @@
+//! Webhook alerts for services that stop logging.
+//!
+//! A silent service is often a worse sign than an erroring one, and nothing else notices
+//! an absence of logs. With `ALERT_WEBHOOK_URL` set, a background task checks every
+//! `ALERT_CHECK_INTERVAL_SECS` when each service that logged within the last
+//! `SILENCE_LOOKBACK_HOURS` was last seen, and posts an alert once a service has been
+//! silent for longer than its threshold. `SILENCE_ALERT_SECS` is the default threshold and
+//! `SILENCE_ALERT_OVERRIDES` (`service=secs,...`) overrides it per service, e.g.
+//! `payments=300,nightly-report=90000`; `0` never alerts for that service. The lookback is
+//! raised to an hour past the longest threshold when it is shorter, since a service that
+//! dropped out of it could never be alerted. Services are grouped under their canonical
+//! name.
+//!
+//! Each silence is alerted once. The service is re-armed when it logs again, and alerts
+//! that could not be delivered are retried on the next check. Which services were alerted
+//! is kept in memory, so a restart alerts again for services that are still silent.
+
+use crate::{env_or, AppState};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::Row;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Longest a webhook call may take before it counts as failed.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct SilenceAlerts {
+    webhook_url: String,
+    client: reqwest::Client,
+    default_secs: Option<i64>,
+    overrides: HashMap<String, i64>,
+    lookback_hours: i64,
+    interval: Duration,
+}
+
+#[derive(Serialize)]
+struct SilenceAlert<'a> {
+    alert: &'static str,
+    service: &'a str,
+    last_seen: DateTime<Utc>,
+    silent_secs: i64,
+    threshold_secs: i64,
+}
+
+impl SilenceAlerts {
+    /// `None` unless `ALERT_WEBHOOK_URL` is set along with a default or at least one
+    /// override.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Some(webhook_url) = std::env::var("ALERT_WEBHOOK_URL").ok().filter(|u| !u.is_empty()) else {
+            return Ok(None);
+        };
+        let default_secs = std::env::var("SILENCE_ALERT_SECS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .filter(|&secs| secs > 0);
+        let overrides = parse_overrides(&std::env::var("SILENCE_ALERT_OVERRIDES").unwrap_or_default());
+        if default_secs.is_none() && overrides.values().all(|&secs| secs == 0) {
+            warn!("ALERT_WEBHOOK_URL is set but no silence threshold is; not alerting");
+            return Ok(None);
+        }
+        let client = reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build()?;
+        Ok(Some(Self {
+            webhook_url,
+            client,
+            lookback_hours: lookback_hours(env_or("SILENCE_LOOKBACK_HOURS", 24i64), default_secs, &overrides),
+            default_secs,
+            overrides,
+            interval: Duration::from_secs(env_or("ALERT_CHECK_INTERVAL_SECS", 60).max(1)),
+        }))
+    }
+
+    /// Seconds of silence after which `service` is alerted, `None` when it never is.
+    fn threshold(&self, service: &str) -> Option<i64> {
+        match self.overrides.get(service) {
+            Some(0) => None,
+            Some(&secs) => Some(secs),
+            None => self.default_secs,
+        }
+    }
+
+    async fn send(&self, alert: &SilenceAlert<'_>) -> Result<(), reqwest::Error> {
+        self.client
+            .post(&self.webhook_url)
+            .json(alert)
+            .send()
+            .await?
+            .error_for_status()
+            .map(|_| ())
+    }
+}
+
+/// `service=secs` pairs of `SILENCE_ALERT_OVERRIDES`, skipping invalid ones.
+fn parse_overrides(spec: &str) -> HashMap<String, i64> {
+    let mut overrides = HashMap::new();
+    for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match pair.split_once('=').map(|(s, secs)| (s.trim(), secs.trim().parse::<i64>())) {
+            Some((service, Ok(secs))) if !service.is_empty() && secs >= 0 => {
+                overrides.insert(service.to_string(), secs);
+            }
+            _ => warn!("Ignoring invalid SILENCE_ALERT_OVERRIDES entry '{}'", pair),
+        }
+    }
+    overrides
+}
+
+/// The configured lookback, raised to an hour past the longest threshold so that a
+/// service is still watched once it has been silent for that long.
+fn lookback_hours(configured: i64, default_secs: Option<i64>, overrides: &HashMap<String, i64>) -> i64 {
+    let longest = default_secs.into_iter().chain(overrides.values().copied()).max().unwrap_or(0);
+    let needed = longest / 3600 + 2;
+    if configured < needed && longest > 0 {
+        warn!(
+            "SILENCE_LOOKBACK_HOURS={} is shorter than the longest silence threshold ({}s); looking back {} hours",
+            configured, longest, needed
+        );
+        return needed;
+    }
+    configured.max(1)
+}
+
+pub async fn run(state: AppState, alerts: SilenceAlerts) {
+    info!(
+        "Alerting on services silent for over {:?} seconds ({} overrides), checking every {:?}",
+        alerts.default_secs,
+        alerts.overrides.len(),
+        alerts.interval
+    );
+    let mut alerted: HashSet<String> = HashSet::new();
+    let mut ticker = tokio::time::interval(alerts.interval);
+    loop {
+        ticker.tick().await;
+        if state.maintenance.load(Ordering::Relaxed) {
+            continue;
+        }
+        if let Err(e) = check(&state, &alerts, &mut alerted).await {
+            error!("Checking for silent services failed: {}", e);
+        }
+    }
+}
+
+async fn check(state: &AppState, alerts: &SilenceAlerts, alerted: &mut HashSet<String>) -> Result<(), sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT service, MAX(timestamp) AS last_seen, NOW() AS now FROM logs
+        WHERE timestamp > NOW() - make_interval(hours => $1)
+        GROUP BY service
+        "#
+    )
+        .bind(alerts.lookback_hours as i32)
+        .fetch_all(&state.pool)
+        .await?;
+
+    // Aliases of one service count as a single service, seen when any of them was
+    let mut last_seen: HashMap<String, DateTime<Utc>> = HashMap::new();
+    let mut now = Utc::now();
+    for row in &rows {
+        now = row.get("now");
+        let service: String = row.get("service");
+        let seen: DateTime<Utc> = row.get("last_seen");
+        let entry = last_seen.entry(state.service_aliases.canonical(&service).to_string()).or_insert(seen);
+        *entry = (*entry).max(seen);
+    }
+
+    for (service, seen) in &last_seen {
+        let Some(threshold) = alerts.threshold(service) else {
+            continue;
+        };
+        let silent_secs = (now - *seen).num_seconds();
+        if silent_secs <= threshold {
+            alerted.remove(service);
+            continue;
+        }
+        if alerted.contains(service) {
+            continue;
+        }
+        let alert = SilenceAlert {
+            alert: "service_silent",
+            service,
+            last_seen: *seen,
+            silent_secs,
+            threshold_secs: threshold,
+        };
+        match alerts.send(&alert).await {
+            Ok(()) => {
+                warn!("Service {} has been silent for {}s, last seen {}; alert sent", service, silent_secs, seen);
+                alerted.insert(service.clone());
+            }
+            Err(e) => error!("Failed to send silence alert for {}, retrying on the next check: {}", service, e),
+        }
+    }
+    // Services that aged out of the lookback are re-armed for when they return
+    alerted.retain(|service| last_seen.contains_key(service));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alerts(default_secs: Option<i64>, overrides: &str) -> SilenceAlerts {
+        let overrides = parse_overrides(overrides);
+        SilenceAlerts {
+            webhook_url: "http://localhost/alerts".to_string(),
+            client: reqwest::Client::new(),
+            lookback_hours: lookback_hours(24, default_secs, &overrides),
+            default_secs,
+            overrides,
+            interval: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn parses_overrides_and_skips_invalid_entries() {
+        let overrides = parse_overrides(" payments = 300, nightly-report=90000,,quiet=0,bad,=5,neg=-1,nan=x ");
+        assert_eq!(overrides.len(), 3);
+        assert_eq!(overrides["payments"], 300);
+        assert_eq!(overrides["nightly-report"], 90000);
+        assert_eq!(overrides["quiet"], 0);
+        assert!(parse_overrides("").is_empty());
+    }
+
+    #[test]
+    fn overrides_take_precedence_over_the_default() {
+        let alerts = alerts(Some(600), "payments=300,quiet=0");
+        assert_eq!(alerts.threshold("payments"), Some(300));
+        assert_eq!(alerts.threshold("quiet"), None);
+        assert_eq!(alerts.threshold("api"), Some(600));
+    }
+
+    #[test]
+    fn only_overridden_services_alert_without_a_default() {
+        let alerts = alerts(None, "payments=300");
+        assert_eq!(alerts.threshold("payments"), Some(300));
+        assert_eq!(alerts.threshold("api"), None);
+    }
+
+    #[test]
+    fn the_lookback_outlasts_the_longest_threshold() {
+        assert_eq!(alerts(Some(600), "payments=300").lookback_hours, 24);
+        let alerts = alerts(Some(600), "nightly-report=90000");
+        assert_eq!(alerts.lookback_hours, 27);
+        assert!(alerts.lookback_hours * 3600 > 90000 + alerts.interval.as_secs() as i64);
+
+        assert_eq!(lookback_hours(48, Some(86400), &HashMap::new()), 48);
+        assert_eq!(lookback_hours(24, Some(86400), &HashMap::new()), 26);
+        assert_eq!(lookback_hours(0, None, &parse_overrides("quiet=0")), 1);
+    }
+}
@@ -0,0 +1,354 @@
+//! Tiered storage: old logs exported to S3 as gzipped NDJSON.
+//!
+//! With `ARCHIVE_S3_BUCKET` set, a background task runs every `ARCHIVE_INTERVAL_SECS` and
+//! exports the logs older than `ARCHIVE_AFTER_DAYS`, one UTC hour per object, to
+//! `<ARCHIVE_S3_PREFIX>YYYY/MM/DD/HH.ndjson.gz`. Every exported hour is recorded in
+//! `archive_exports` once its object is stored, and the next run resumes after the last
+//! recorded hour. An hour is only exported once it is complete, and the key depends only
+//! on the hour, so an export interrupted by a crash is repeated in full with the same
+//! object replaced. Nothing is duplicated or lost. With `ARCHIVE_DELETE`, the exported rows
+//! are then deleted locally in batches of `DELETE_BATCH_SIZE`; an hour whose delete was
+//! interrupted is finished on the next run.
+//!
+//! A log can still be stored with a timestamp in an hour that was already exported, e.g.
+//! from the retry queue. Each export records the `created_at` it covers up to, taken
+//! `SYNC_SETTLE_MS` in the past so that inserts still running are not counted, and only
+//! logs up to it are deleted. Every run looks for logs stored within the last
+//! `ARCHIVE_LATE_LOOKBACK_DAYS` past the hour's export and exports that hour again: the
+//! object already stored is merged with the hour's local logs, so it keeps logs that were
+//! deleted locally. Logs that arrive later than the lookback stay local until retention
+//! removes them.
+//!
+//! `ARCHIVE_S3_ENDPOINT` points at S3-compatible storage instead of AWS, addressed
+//! path-style. Credentials come from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` or the
+//! AWS profile. An hour's compressed export is held in memory while it is uploaded.
+
+use crate::{env_flag, env_or, log_from_row, AppState};
+use anyhow::{bail, Context};
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::TryStreamExt;
+use s3::creds::Credentials;
+use s3::{Bucket, Region};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tidelogs_backend::query::LOG_COLUMNS;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+pub struct Archiver {
+    bucket: Box<Bucket>,
+    prefix: String,
+    after_days: i64,
+    late_lookback_days: i64,
+    delete: bool,
+    interval: Duration,
+}
+
+/// Where a line of an archived object sorts, as its export wrote them.
+#[derive(Deserialize)]
+struct ArchivedLog {
+    timestamp: DateTime<Utc>,
+    id: Uuid,
+}
+
+impl Archiver {
+    /// `None` unless `ARCHIVE_S3_BUCKET` is set; an unusable region or missing
+    /// credentials fail startup.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Some(name) = std::env::var("ARCHIVE_S3_BUCKET").ok().filter(|b| !b.is_empty()) else {
+            return Ok(None);
+        };
+        let region_name = std::env::var("ARCHIVE_S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+        let endpoint = std::env::var("ARCHIVE_S3_ENDPOINT").ok().filter(|e| !e.is_empty());
+        let region = match &endpoint {
+            Some(endpoint) => Region::Custom {
+                region: region_name,
+                endpoint: endpoint.clone(),
+            },
+            None => region_name.parse().with_context(|| format!("invalid ARCHIVE_S3_REGION '{}'", region_name))?,
+        };
+        let credentials = Credentials::default().context("no S3 credentials for ARCHIVE_S3_BUCKET")?;
+        let mut bucket = Bucket::new(&name, region, credentials)?;
+        if endpoint.is_some() {
+            bucket = bucket.with_path_style();
+        }
+
+        let mut prefix = std::env::var("ARCHIVE_S3_PREFIX").unwrap_or_else(|_| "tidelogs/".to_string());
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        let archiver = Self {
+            bucket,
+            prefix,
+            after_days: env_or("ARCHIVE_AFTER_DAYS", 30i64).max(1),
+            late_lookback_days: env_or("ARCHIVE_LATE_LOOKBACK_DAYS", 7i64).max(1),
+            delete: env_flag("ARCHIVE_DELETE", false),
+            interval: Duration::from_secs(env_or("ARCHIVE_INTERVAL_SECS", 3600).max(1)),
+        };
+        info!(
+            "Archiving logs older than {} days to s3://{}/{} every {:?}{}",
+            archiver.after_days,
+            name,
+            archiver.prefix,
+            archiver.interval,
+            if archiver.delete { ", deleting them locally" } else { "" }
+        );
+        Ok(Some(archiver))
+    }
+
+    fn object_key(&self, hour: DateTime<Utc>) -> String {
+        format!("{}{}.ndjson.gz", self.prefix, hour.format("%Y/%m/%d/%H"))
+    }
+}
+
+pub async fn run(state: AppState, archiver: Archiver) {
+    // Retention deletes such logs before they are old enough to be archived
+    if let Some(days) = state.retention.default_days().filter(|&d| i64::from(d) <= archiver.after_days) {
+        warn!(
+            "RETENTION_DAYS ({}) is not longer than ARCHIVE_AFTER_DAYS ({}); logs are deleted unarchived",
+            days, archiver.after_days
+        );
+    }
+    let (names, days) = state.retention.overrides(&state);
+    for (name, days) in names.iter().zip(days).filter(|&(_, d)| i64::from(d) <= archiver.after_days) {
+        warn!(
+            "Retention of {} days for service '{}' is not longer than ARCHIVE_AFTER_DAYS ({}); its logs are deleted unarchived",
+            days, name, archiver.after_days
+        );
+    }
+
+    let mut ticker = tokio::time::interval(archiver.interval);
+    loop {
+        ticker.tick().await;
+        if state.maintenance.load(Ordering::Relaxed) {
+            continue;
+        }
+        if let Err(e) = archive_pending(&state, &archiver).await {
+            error!("Archiving logs failed, retrying on the next run: {:#}", e);
+        }
+    }
+}
+
+/// Finishes interrupted deletes, exports again the hours that received logs after their
+/// export, then exports every complete hour past the threshold that has not been exported
+/// yet, oldest first.
+async fn archive_pending(state: &AppState, archiver: &Archiver) -> anyhow::Result<()> {
+    if archiver.delete {
+        let undeleted: Vec<DateTime<Utc>> = sqlx::query_scalar(
+            "SELECT period_start FROM archive_exports WHERE deleted_at IS NULL ORDER BY period_start",
+        )
+            .fetch_all(&state.pool)
+            .await?;
+        for hour in undeleted {
+            delete_hour(state, hour).await?;
+        }
+    }
+
+    let hour = TimeDelta::hours(1);
+    let cutoff = (Utc::now() - TimeDelta::days(archiver.after_days)).duration_trunc(hour)?;
+    // Logs stored up to here are committed unless their insert has run for SYNC_SETTLE_MS
+    let through: DateTime<Utc> = sqlx::query_scalar("SELECT NOW() - $1 * INTERVAL '1 second'")
+        .bind(state.sync_settle.as_secs_f64())
+        .fetch_one(&state.pool)
+        .await?;
+
+    let mut resume: Option<DateTime<Utc>> = sqlx::query_scalar("SELECT MAX(period_end) FROM archive_exports")
+        .fetch_one(&state.pool)
+        .await?;
+
+    // Every hour before `resume` was exported or had no logs then. Only partitions before
+    // it are scanned, through their created_at index.
+    let late: Vec<DateTime<Utc>> = match resume {
+        Some(resume) => sqlx::query_scalar(
+            r#"
+            SELECT DISTINCT date_bin('1 hour', l.timestamp, TIMESTAMPTZ '2000-01-01 00:00:00+00') AS hour
+            FROM logs l
+            LEFT JOIN archive_exports e ON l.timestamp >= e.period_start AND l.timestamp < e.period_end
+            WHERE l.timestamp < $1 AND l.created_at > NOW() - $2 * INTERVAL '1 day' AND l.created_at <= $3
+              AND (e.exported_through IS NULL OR l.created_at > e.exported_through)
+            ORDER BY hour
+            "#
+        )
+            .bind(resume)
+            .bind(archiver.late_lookback_days as f64)
+            .bind(through)
+            .fetch_all(&state.pool)
+            .await?,
+        None => Vec::new(),
+    };
+    for start in late {
+        export_hour(state, archiver, start, through).await?;
+        if archiver.delete {
+            delete_hour(state, start).await?;
+        }
+    }
+
+    loop {
+        // Hours without logs are skipped rather than exported empty; one that gets logs
+        // later is exported with the late logs of a later run
+        let oldest: Option<DateTime<Utc>> = sqlx::query_scalar(
+            "SELECT MIN(timestamp) FROM logs WHERE ($1::timestamptz IS NULL OR timestamp >= $1) AND timestamp < $2",
+        )
+            .bind(resume)
+            .bind(cutoff)
+            .fetch_one(&state.pool)
+            .await?;
+        let Some(oldest) = oldest else {
+            return Ok(());
+        };
+        let start = oldest.duration_trunc(hour)?;
+        export_hour(state, archiver, start, through).await?;
+        if archiver.delete {
+            delete_hour(state, start).await?;
+        }
+        resume = Some(start + hour);
+    }
+}
+
+/// Exports the hour's logs stored up to `through`, merged with what an earlier export of
+/// the hour stored, which is then held in memory.
+async fn export_hour(
+    state: &AppState,
+    archiver: &Archiver,
+    start: DateTime<Utc>,
+    through: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let end = start + TimeDelta::hours(1);
+    let key = archiver.object_key(start);
+    let exported: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM archive_exports WHERE period_start = $1)")
+        .bind(start)
+        .fetch_one(&state.pool)
+        .await?;
+    let mut archived = if exported {
+        archived_lines(archiver, &key).await?
+    } else {
+        BTreeMap::new()
+    }
+    .into_iter()
+    .peekable();
+
+    let statement = format!(
+        "SELECT {} FROM logs WHERE timestamp >= $1 AND timestamp < $2 AND created_at <= $3 ORDER BY timestamp, id",
+        LOG_COLUMNS
+    );
+    let mut rows = sqlx::query(&statement).bind(start).bind(end).bind(through).fetch(&state.pool);
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let mut count: i64 = 0;
+    while let Some(row) = rows.try_next().await? {
+        let mut entry = log_from_row(&row);
+        state.service_aliases.canonicalize(&mut entry.service);
+        let position = (entry.timestamp.unwrap_or_default().to_utc(), entry.id.unwrap_or_default());
+        // Both are in (timestamp, id) order; a log still stored locally replaces its copy
+        while let Some((archived_at, line)) = archived.next_if(|(archived_at, _)| *archived_at <= position) {
+            if archived_at != position {
+                encoder.write_all(line.as_bytes())?;
+                encoder.write_all(b"\n")?;
+                count += 1;
+            }
+        }
+        serde_json::to_writer(&mut encoder, &entry)?;
+        encoder.write_all(b"\n")?;
+        count += 1;
+    }
+    drop(rows);
+    for (_, line) in archived {
+        encoder.write_all(line.as_bytes())?;
+        encoder.write_all(b"\n")?;
+        count += 1;
+    }
+    let body = encoder.finish()?;
+
+    let response = archiver
+        .bucket
+        .put_object_with_content_type(&key, &body, "application/gzip")
+        .await
+        .with_context(|| format!("failed to upload {}", key))?;
+    if !(200..300).contains(&response.status_code()) {
+        bail!("uploading {} failed with status {}", key, response.status_code());
+    }
+
+    // Cleared deleted_at lets the delete pass remove the logs exported this time
+    sqlx::query(
+        r#"
+        INSERT INTO archive_exports (period_start, period_end, object_key, log_count, exported_through)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (period_start) DO UPDATE
+            SET object_key = EXCLUDED.object_key, log_count = EXCLUDED.log_count, exported_at = NOW(),
+                exported_through = EXCLUDED.exported_through, deleted_at = NULL
+        "#
+    )
+        .bind(start)
+        .bind(end)
+        .bind(&key)
+        .bind(count)
+        .bind(through)
+        .execute(&state.pool)
+        .await?;
+    info!("Archived {} logs from {} to {} ({} bytes)", count, start, key, body.len());
+    Ok(())
+}
+
+/// The lines of an archived object by `(timestamp, id)`.
+async fn archived_lines(archiver: &Archiver, key: &str) -> anyhow::Result<BTreeMap<(DateTime<Utc>, Uuid), String>> {
+    let response = archiver
+        .bucket
+        .get_object(key)
+        .await
+        .with_context(|| format!("failed to download {}", key))?;
+    if !(200..300).contains(&response.status_code()) {
+        bail!("downloading {} failed with status {}", key, response.status_code());
+    }
+    let mut lines = BTreeMap::new();
+    for line in BufReader::new(MultiGzDecoder::new(response.bytes().as_ref())).lines() {
+        let line = line.with_context(|| format!("{} is not gzipped NDJSON", key))?;
+        let position = archived_position(&line).with_context(|| format!("{} holds a line that is not a log", key))?;
+        lines.insert(position, line);
+    }
+    Ok(lines)
+}
+
+fn archived_position(line: &str) -> Option<(DateTime<Utc>, Uuid)> {
+    let log: ArchivedLog = serde_json::from_str(line).ok()?;
+    Some((log.timestamp, log.id))
+}
+
+/// Deletes an exported hour's logs in batches and marks the hour deleted. Logs stored
+/// after the export are left for the next one.
+async fn delete_hour(state: &AppState, start: DateTime<Utc>) -> anyhow::Result<()> {
+    let end = start + TimeDelta::hours(1);
+    let mut deleted: u64 = 0;
+    loop {
+        let batch = sqlx::query(
+            r#"
+            DELETE FROM logs WHERE (id, timestamp) IN (
+                SELECT id, timestamp FROM logs
+                WHERE timestamp >= $1 AND timestamp < $2
+                  AND created_at <= (SELECT exported_through FROM archive_exports WHERE period_start = $1)
+                LIMIT $3
+            )
+            "#
+        )
+            .bind(start)
+            .bind(end)
+            .bind(state.delete_batch_size)
+            .execute(&state.pool)
+            .await?
+            .rows_affected();
+        deleted += batch;
+        if batch < state.delete_batch_size as u64 {
+            break;
+        }
+    }
+    sqlx::query("UPDATE archive_exports SET deleted_at = NOW() WHERE period_start = $1")
+        .bind(start)
+        .execute(&state.pool)
+        .await?;
+    info!("Deleted {} archived logs from the hour starting {}", deleted, start);
+    Ok(())
+}
@@ -0,0 +1,276 @@
+//! Optional coalescing of concurrent single-log inserts.
+//!
+//! With `INSERT_BATCHING` enabled, `POST /logs` hands its validated row to a background
+//! task instead of inserting it directly. The task collects rows arriving within
+//! `INSERT_BATCH_WINDOW_MS` of the first one (up to `INSERT_BATCH_MAX_SIZE`), writes them
+//! with one multi-row `INSERT`, and answers every waiting request with its own stored
+//! row. Callers still only get a response once their row is committed. When one row's own
+//! data makes the statement fail, the rows are written again one at a time, so only the
+//! request that sent it fails.
+
+use crate::retry_queue::InsertFailure;
+use crate::{env_or, log_from_row, LogEntry};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info};
+use uuid::Uuid;
+
+const QUEUE_CAPACITY: usize = 10_000;
+
+#[derive(Clone)]
+pub struct NewLog {
+    pub service: String,
+    pub level: String,
+    pub message: String,
+    pub metadata: Value,
+    /// Set by the server when the log is signed; the database picks it otherwise
+    pub timestamp: Option<DateTime<Utc>>,
+    pub signature: Option<String>,
+}
+
+struct Pending {
+    id: Uuid,
+    log: NewLog,
+    reply: oneshot::Sender<Result<LogEntry, InsertFailure>>,
+}
+
+pub struct InsertBatcher {
+    tx: mpsc::Sender<Pending>,
+}
+
+impl InsertBatcher {
+    /// Starts the batching task when `INSERT_BATCHING` is enabled.
+    pub fn from_env(pool: PgPool) -> Option<Self> {
+        if !crate::env_flag("INSERT_BATCHING", false) {
+            return None;
+        }
+        let window = Duration::from_millis(env_or("INSERT_BATCH_WINDOW_MS", 5));
+        let max_size = env_or("INSERT_BATCH_MAX_SIZE", 500usize).max(1);
+        info!("Batching inserts within {:?}, at most {} per statement", window, max_size);
+
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(run(pool, rx, window, max_size));
+        Some(Self { tx })
+    }
+
+    /// Inserts `log` as part of the next batch. On failure the cause has already been
+    /// logged.
+    pub async fn insert(&self, log: NewLog) -> Result<LogEntry, InsertFailure> {
+        let (reply, response) = oneshot::channel();
+        let pending = Pending {
+            id: Uuid::new_v4(),
+            log,
+            reply,
+        };
+        let failed = InsertFailure { transient: false };
+        self.tx.send(pending).await.map_err(|_| failed)?;
+        response.await.unwrap_or(Err(failed))
+    }
+}
+
+async fn run(pool: PgPool, mut rx: mpsc::Receiver<Pending>, window: Duration, max_size: usize) {
+    while let Some(first) = rx.recv().await {
+        let mut batch = vec![first];
+        let deadline = tokio::time::sleep(window);
+        tokio::pin!(deadline);
+        while batch.len() < max_size {
+            tokio::select! {
+                _ = &mut deadline => break,
+                next = rx.recv() => match next {
+                    Some(pending) => batch.push(pending),
+                    None => break,
+                },
+            }
+        }
+        flush(&pool, batch).await;
+    }
+}
+
+async fn flush(pool: &PgPool, batch: Vec<Pending>) {
+    answer(batch, |rows| insert_rows(pool, rows)).await;
+}
+
+/// Writes `batch` with `insert` and answers every caller with its own row. A failure
+/// caused by the rows themselves, such as one caller's service being too long, fails the
+/// whole statement, so the rows are then written one at a time and each caller gets the
+/// result of its own.
+async fn answer<F, Fut>(batch: Vec<Pending>, insert: F)
+where
+    F: Fn(Vec<(Uuid, NewLog)>) -> Fut,
+    Fut: Future<Output = Result<HashMap<Uuid, LogEntry>, sqlx::Error>>,
+{
+    let missing = InsertFailure { transient: false };
+    let e = match insert(batch.iter().map(|pending| (pending.id, pending.log.clone())).collect()).await {
+        Ok(mut stored) => {
+            for pending in batch {
+                // The caller may have gone away; its row is stored regardless
+                let _ = pending.reply.send(stored.remove(&pending.id).ok_or(missing));
+            }
+            return;
+        }
+        Err(e) => e,
+    };
+
+    let failure = InsertFailure::from(&e);
+    if failure.transient || batch.len() == 1 {
+        error!("Failed to insert batch of {} logs: {}", batch.len(), e);
+        for pending in batch {
+            let _ = pending.reply.send(Err(failure));
+        }
+        return;
+    }
+    error!("Failed to insert batch of {} logs, inserting them one at a time: {}", batch.len(), e);
+    for Pending { id, log, reply } in batch {
+        let result = match insert(vec![(id, log)]).await {
+            Ok(mut stored) => stored.remove(&id).ok_or(missing),
+            Err(e) => {
+                error!("Failed to insert log: {}", e);
+                Err(InsertFailure::from(&e))
+            }
+        };
+        let _ = reply.send(result);
+    }
+}
+
+/// Inserts `rows` in one statement, returning the stored rows by id.
+async fn insert_rows(pool: &PgPool, rows: Vec<(Uuid, NewLog)>) -> Result<HashMap<Uuid, LogEntry>, sqlx::Error> {
+    let mut ids = Vec::with_capacity(rows.len());
+    let mut services = Vec::with_capacity(rows.len());
+    let mut levels = Vec::with_capacity(rows.len());
+    let mut messages = Vec::with_capacity(rows.len());
+    let mut metadata = Vec::with_capacity(rows.len());
+    let mut timestamps = Vec::with_capacity(rows.len());
+    let mut signatures = Vec::with_capacity(rows.len());
+    for (id, log) in rows {
+        ids.push(id);
+        services.push(log.service);
+        levels.push(log.level);
+        messages.push(log.message);
+        metadata.push(log.metadata);
+        timestamps.push(log.timestamp);
+        signatures.push(log.signature);
+    }
+
+    // Ids are assigned here so each returned row can be matched back to its caller
+    let rows = sqlx::query(
+        r#"
+        INSERT INTO logs (id, service, level, message, metadata, timestamp, signature)
+        SELECT id, service, level, message, metadata, COALESCE(timestamp, NOW()), signature
+        FROM UNNEST($1::uuid[], $2::text[], $3::text[], $4::text[], $5::jsonb[], $6::timestamptz[], $7::text[])
+            AS batch(id, service, level, message, metadata, timestamp, signature)
+        RETURNING id, timestamp, service, level, message, metadata, created_at, seq
+        "#
+    )
+        .bind(&ids)
+        .bind(&services)
+        .bind(&levels)
+        .bind(&messages)
+        .bind(&metadata)
+        .bind(&timestamps)
+        .bind(&signatures)
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.iter()
+        .map(log_from_row)
+        .filter_map(|entry| Some((entry.id?, entry)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn pending(service: &str) -> (Pending, oneshot::Receiver<Result<LogEntry, InsertFailure>>) {
+        let (reply, response) = oneshot::channel();
+        let log = NewLog {
+            service: service.to_string(),
+            level: "INFO".to_string(),
+            message: "started".to_string(),
+            metadata: serde_json::json!({}),
+            timestamp: None,
+            signature: None,
+        };
+        (Pending { id: Uuid::new_v4(), log, reply }, response)
+    }
+
+    /// Stores every row, or fails the whole statement when one has the service `bad`.
+    async fn store(rows: Vec<(Uuid, NewLog)>, error: fn() -> sqlx::Error) -> Result<HashMap<Uuid, LogEntry>, sqlx::Error> {
+        if rows.iter().any(|(_, log)| log.service == "bad") {
+            return Err(error());
+        }
+        Ok(rows
+            .into_iter()
+            .map(|(id, log)| {
+                let entry = LogEntry {
+                    id: Some(id),
+                    timestamp: None,
+                    service: log.service,
+                    level: log.level,
+                    message: log.message,
+                    metadata: Some(log.metadata),
+                    created_at: None,
+                    seq: None,
+                    match_snippet: None,
+                };
+                (id, entry)
+            })
+            .collect())
+    }
+
+    fn row_error() -> sqlx::Error {
+        sqlx::Error::ColumnNotFound("service".to_string())
+    }
+
+    #[tokio::test]
+    async fn answers_each_caller_with_its_own_row() {
+        let (first, first_response) = pending("api");
+        let (second, second_response) = pending("worker");
+        let statements = AtomicUsize::new(0);
+        answer(vec![first, second], |rows| {
+            statements.fetch_add(1, Ordering::Relaxed);
+            store(rows, row_error)
+        })
+        .await;
+        assert_eq!(statements.load(Ordering::Relaxed), 1);
+        assert_eq!(first_response.await.unwrap().ok().unwrap().service, "api");
+        assert_eq!(second_response.await.unwrap().ok().unwrap().service, "worker");
+    }
+
+    #[tokio::test]
+    async fn a_bad_row_only_fails_its_own_caller() {
+        let (first, first_response) = pending("api");
+        let (bad, bad_response) = pending("bad");
+        let (last, last_response) = pending("worker");
+        let statements = AtomicUsize::new(0);
+        answer(vec![first, bad, last], |rows| {
+            statements.fetch_add(1, Ordering::Relaxed);
+            store(rows, row_error)
+        })
+        .await;
+        assert_eq!(statements.load(Ordering::Relaxed), 4);
+        assert_eq!(first_response.await.unwrap().ok().unwrap().service, "api");
+        assert!(bad_response.await.unwrap().is_err_and(|failure| !failure.transient));
+        assert_eq!(last_response.await.unwrap().ok().unwrap().service, "worker");
+    }
+
+    #[tokio::test]
+    async fn transient_failures_fail_the_batch_without_retrying() {
+        let (first, first_response) = pending("api");
+        let (bad, bad_response) = pending("bad");
+        let statements = AtomicUsize::new(0);
+        answer(vec![first, bad], |rows| {
+            statements.fetch_add(1, Ordering::Relaxed);
+            store(rows, || sqlx::Error::PoolTimedOut)
+        })
+        .await;
+        assert_eq!(statements.load(Ordering::Relaxed), 1);
+        assert!(first_response.await.unwrap().is_err_and(|failure| failure.transient));
+        assert!(bad_response.await.unwrap().is_err_and(|failure| failure.transient));
+    }
+}
@@ -0,0 +1,262 @@
+//! A circuit breaker that stops sending requests to a failing database.
+//!
+//! When Postgres is struggling, every request still waits for a pool connection and adds
+//! load. With `CIRCUIT_BREAKER_FAILURES` set, that many failures to reach the database in
+//! a row open the breaker. Only connection and pool errors and Postgres' connection,
+//! overload and shutdown errors count, as for `SELF_MONITOR`; a request that fails on its
+//! own bad input neither opens nor closes the breaker. While it is open, requests are
+//! answered with `503` and a `Retry-After` header straight away, for
+//! `CIRCUIT_BREAKER_COOLDOWN_MS`. After the cooldown, one request at a time is let through
+//! as a probe. The breaker closes on the first database operation that succeeds and opens
+//! again for another cooldown when the probe fails.
+//!
+//! The health checks and the OpenAPI document are never short-circuited, and neither are
+//! tail streams, which do not use the database. Background tasks don't go through it.
+
+use crate::ApiError;
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const BYPASS_PATHS: &[&str] = &["/health", "/health/db", "/health/ready", "/openapi.json", "/logs/tail"];
+
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    consecutive_failures: u32,
+    /// When the breaker last opened; `None` while it is closed
+    opened_at: Option<Instant>,
+    /// Whether a probe request is in flight after the cooldown
+    probing: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Serialize)]
+pub struct BreakerStatus {
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+}
+
+/// Lets the next probe through once the probing request is done.
+struct Probe(Arc<CircuitBreaker>);
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        self.0.lock().probing = false;
+    }
+}
+
+impl CircuitBreaker {
+    /// `None` unless `CIRCUIT_BREAKER_FAILURES` is at least 1.
+    pub fn from_env() -> Option<Self> {
+        let threshold = std::env::var("CIRCUIT_BREAKER_FAILURES")
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .filter(|&n| n > 0)?;
+        let cooldown = Duration::from_millis(crate::env_or("CIRCUIT_BREAKER_COOLDOWN_MS", 5000).max(1));
+        info!(
+            "Short-circuiting requests for {:?} after {} consecutive database failures",
+            cooldown, threshold
+        );
+        Some(Self {
+            threshold,
+            cooldown,
+            inner: Mutex::new(Inner::default()),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Counts the outcome of one database operation.
+    pub fn record(&self, ok: bool) {
+        let mut inner = self.lock();
+        if ok {
+            inner.consecutive_failures = 0;
+            if inner.opened_at.take().is_some() {
+                info!("Database operations succeed again; closing the circuit breaker");
+            }
+            return;
+        }
+        inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+        let reopen = match inner.opened_at {
+            // A failed probe; failures of requests let in before it opened don't extend it
+            Some(opened_at) => opened_at.elapsed() >= self.cooldown,
+            None => inner.consecutive_failures >= self.threshold,
+        };
+        if reopen {
+            warn!(
+                "{} consecutive database failures; rejecting requests for {:?}",
+                inner.consecutive_failures, self.cooldown
+            );
+            inner.opened_at = Some(Instant::now());
+        }
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let inner = self.lock();
+        let state = match inner.opened_at {
+            None => BreakerState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        };
+        BreakerStatus {
+            state,
+            consecutive_failures: inner.consecutive_failures,
+        }
+    }
+
+    /// Whether a request may go ahead: `Ok` with the probe to hold while it runs after
+    /// the cooldown, or `Err` with how long the caller should wait.
+    fn admit(self: &Arc<Self>) -> Result<Option<Probe>, Duration> {
+        let mut inner = self.lock();
+        let Some(opened_at) = inner.opened_at else {
+            return Ok(None);
+        };
+        let elapsed = opened_at.elapsed();
+        if elapsed < self.cooldown {
+            return Err(self.cooldown - elapsed);
+        }
+        if inner.probing {
+            return Err(Duration::from_secs(1));
+        }
+        inner.probing = true;
+        Ok(Some(Probe(self.clone())))
+    }
+}
+
+/// Answers `503` while the breaker is open, and lets one probe at a time through after
+/// the cooldown.
+pub async fn enforce(State(breaker): State<Arc<CircuitBreaker>>, request: Request, next: Next) -> Response {
+    if BYPASS_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    match breaker.admit() {
+        Ok(_probe) => next.run(request).await,
+        Err(wait) => {
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            (
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "the database is failing; requests are paused until it recovers",
+                ),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(threshold: u32) -> Arc<CircuitBreaker> {
+        Arc::new(CircuitBreaker {
+            threshold,
+            cooldown: Duration::from_secs(60),
+            inner: Mutex::new(Inner::default()),
+        })
+    }
+
+    /// Moves the breaker's opening back past its cooldown.
+    fn expire_cooldown(breaker: &CircuitBreaker) {
+        let mut inner = breaker.lock();
+        inner.opened_at = inner.opened_at.map(|opened_at| opened_at - breaker.cooldown);
+    }
+
+    #[test]
+    fn opens_after_threshold_consecutive_failures() {
+        let breaker = breaker(3);
+        breaker.record(false);
+        breaker.record(false);
+        assert_eq!(breaker.status().state, BreakerState::Closed);
+        assert!(breaker.admit().is_ok_and(|probe| probe.is_none()));
+
+        breaker.record(false);
+        let status = breaker.status();
+        assert_eq!(status.state, BreakerState::Open);
+        assert_eq!(status.consecutive_failures, 3);
+        let wait = breaker.admit().err().unwrap();
+        assert!(wait > Duration::from_secs(59) && wait <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn a_success_resets_the_count() {
+        let breaker = breaker(2);
+        breaker.record(false);
+        breaker.record(true);
+        breaker.record(false);
+        assert_eq!(breaker.status().state, BreakerState::Closed);
+        assert_eq!(breaker.status().consecutive_failures, 1);
+    }
+
+    #[test]
+    fn failures_while_open_do_not_extend_the_cooldown() {
+        let breaker = breaker(1);
+        breaker.record(false);
+        let opened_at = breaker.lock().opened_at;
+        breaker.record(false);
+        assert_eq!(breaker.lock().opened_at, opened_at);
+        assert_eq!(breaker.status().consecutive_failures, 2);
+    }
+
+    #[test]
+    fn lets_one_probe_through_after_the_cooldown() {
+        let breaker = breaker(1);
+        breaker.record(false);
+        expire_cooldown(&breaker);
+        assert_eq!(breaker.status().state, BreakerState::HalfOpen);
+
+        let probe = breaker.admit().unwrap();
+        assert!(probe.is_some());
+        assert_eq!(breaker.admit().err(), Some(Duration::from_secs(1)));
+        drop(probe);
+        assert!(breaker.admit().is_ok_and(|probe| probe.is_some()));
+    }
+
+    #[test]
+    fn a_successful_probe_closes_the_breaker() {
+        let breaker = breaker(1);
+        breaker.record(false);
+        expire_cooldown(&breaker);
+        let _probe = breaker.admit().unwrap();
+        breaker.record(true);
+        let status = breaker.status();
+        assert_eq!(status.state, BreakerState::Closed);
+        assert_eq!(status.consecutive_failures, 0);
+        assert!(breaker.admit().is_ok_and(|probe| probe.is_none()));
+    }
+
+    #[test]
+    fn a_failed_probe_opens_it_again() {
+        let breaker = breaker(1);
+        breaker.record(false);
+        expire_cooldown(&breaker);
+        let probe = breaker.admit().unwrap();
+        breaker.record(false);
+        drop(probe);
+        assert_eq!(breaker.status().state, BreakerState::Open);
+        assert!(breaker.admit().is_err());
+    }
+}
@@ -0,0 +1,120 @@
+//! Optional fan-out of ingested logs to a Kafka topic.
+//!
+//! Publishing never blocks ingestion: entries are queued on a bounded channel and a
+//! background task ships them to Kafka. When the queue is full or Kafka rejects a
+//! batch, the entries are dropped and counted in `tidelogs_bus_dropped_total`.
+
+use crate::telemetry::Telemetry;
+use crate::LogEntry;
+use rskafka::client::partition::{Compression, PartitionClient, UnknownTopicHandling};
+use rskafka::client::ClientBuilder;
+use rskafka::record::Record;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+const QUEUE_CAPACITY: usize = 10_000;
+const MAX_BATCH: usize = 500;
+
+pub struct BusPublisher {
+    tx: mpsc::Sender<Record>,
+    telemetry: Arc<Telemetry>,
+}
+
+impl BusPublisher {
+    /// Starts the publisher when both `KAFKA_BROKERS` (comma-separated) and
+    /// `KAFKA_TOPIC` are set; `KAFKA_PARTITION` selects the partition (default 0).
+    pub fn from_env(telemetry: Arc<Telemetry>) -> Option<Self> {
+        let brokers: Vec<String> = std::env::var("KAFKA_BROKERS")
+            .ok()?
+            .split(',')
+            .map(|b| b.trim().to_string())
+            .filter(|b| !b.is_empty())
+            .collect();
+        let topic = std::env::var("KAFKA_TOPIC").ok().filter(|t| !t.is_empty())?;
+        if brokers.is_empty() {
+            return None;
+        }
+        let partition = crate::env_or("KAFKA_PARTITION", 0);
+
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(run(brokers, topic, partition, rx, telemetry.clone()));
+
+        Some(Self { tx, telemetry })
+    }
+
+    /// Queues `entry` for publishing without waiting on Kafka.
+    pub fn publish(&self, entry: &LogEntry) {
+        let value = match serde_json::to_vec(entry) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("Failed to serialize log for Kafka: {}", e);
+                self.telemetry.bus_dropped.inc();
+                return;
+            }
+        };
+        let record = Record {
+            key: Some(entry.service.clone().into_bytes()),
+            value: Some(value),
+            headers: BTreeMap::new(),
+            timestamp: entry
+                .timestamp
+                .map(|t| t.with_timezone(&chrono::Utc))
+                .unwrap_or_else(chrono::Utc::now),
+        };
+        if self.tx.try_send(record).is_err() {
+            self.telemetry.bus_dropped.inc();
+        }
+    }
+}
+
+async fn connect(
+    brokers: &[String],
+    topic: &str,
+    partition: i32,
+) -> Result<PartitionClient, rskafka::client::error::Error> {
+    let client = ClientBuilder::new(brokers.to_vec()).build().await?;
+    client
+        .partition_client(topic, partition, UnknownTopicHandling::Retry)
+        .await
+}
+
+async fn run(
+    brokers: Vec<String>,
+    topic: String,
+    partition: i32,
+    mut rx: mpsc::Receiver<Record>,
+    telemetry: Arc<Telemetry>,
+) {
+    let client = loop {
+        match connect(&brokers, &topic, partition).await {
+            Ok(client) => break client,
+            Err(e) => {
+                warn!("Failed to connect to Kafka, retrying in 5s: {}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    };
+    info!("Publishing logs to Kafka topic '{}' (partition {})", topic, partition);
+
+    while let Some(record) = rx.recv().await {
+        let mut batch = vec![record];
+        while batch.len() < MAX_BATCH {
+            match rx.try_recv() {
+                Ok(record) => batch.push(record),
+                Err(_) => break,
+            }
+        }
+
+        let count = batch.len() as u64;
+        match client.produce(batch, Compression::NoCompression).await {
+            Ok(_) => telemetry.bus_published.add(count),
+            Err(e) => {
+                warn!("Failed to publish {} logs to Kafka: {}", count, e);
+                telemetry.bus_dropped.add(count);
+            }
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! Optional cheaper compression of very large messages.
+//!
+//! Postgres already compresses a message once its row outgrows about 2 kB, keeping it
+//! whole in the `message` column, so every SQL filter (`search`, `not_search`, reclassify
+//! patterns) sees the full text. With `MESSAGE_COMPRESSION` enabled, the column of `logs`
+//! and of each of its partitions is switched to lz4, which compresses and decompresses
+//! much faster than the default pglz; new partitions take the setting over from `logs`.
+//! Only messages written afterwards are stored with lz4, and turning the option off
+//! leaves the column as it is.
+//!
+//! Earlier versions kept a gzip of an over-long message in `message_gzip` and only its
+//! beginning in `message`. Reads still decompress such rows, and a background task puts
+//! their full message back into `message` so that filters see it too.
+
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use sqlx::PgPool;
+use std::io::Read;
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Legacy rows restored per statement.
+const RESTORE_BATCH_SIZE: i64 = 500;
+
+pub struct MessageCompression;
+
+impl MessageCompression {
+    pub fn from_env() -> Option<Self> {
+        crate::env_flag("MESSAGE_COMPRESSION", false).then_some(Self)
+    }
+
+    /// Switches the message column of `logs` and its partitions to lz4.
+    pub async fn apply(&self, pool: &PgPool) {
+        let partitions: Vec<String> = match sqlx::query_scalar(
+            r#"
+            SELECT c.relname::text FROM pg_inherits i
+            JOIN pg_class c ON c.oid = i.inhrelid
+            WHERE i.inhparent = 'logs'::regclass
+            "#
+        )
+            .fetch_all(pool)
+            .await
+        {
+            Ok(names) => names,
+            Err(e) => {
+                warn!("Failed to list log partitions, not compressing messages with lz4: {}", e);
+                return;
+            }
+        };
+        // DDL takes no bind parameters; the names come from the catalog
+        for table in std::iter::once("logs".to_string()).chain(partitions) {
+            let sql = format!("ALTER TABLE {} ALTER COLUMN message SET COMPRESSION lz4", quote_ident(&table));
+            if let Err(e) = sqlx::query(&sql).execute(pool).await {
+                warn!("Failed to compress messages of {} with lz4: {}", table, e);
+                return;
+            }
+        }
+        info!("Compressing large messages with lz4");
+    }
+}
+
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+pub fn decompress(compressed: &[u8]) -> std::io::Result<String> {
+    let mut message = String::new();
+    GzDecoder::new(compressed).read_to_string(&mut message)?;
+    Ok(message)
+}
+
+/// Puts the full message of every log stored gzipped by an earlier version back into
+/// `message`, in batches, oldest first.
+pub async fn restore_legacy(pool: PgPool) {
+    let mut after: Option<(DateTime<Utc>, Uuid)> = None;
+    let mut restored = 0u64;
+    loop {
+        let rows: Vec<(DateTime<Utc>, Uuid, Vec<u8>)> = match sqlx::query_as(
+            r#"
+            SELECT timestamp, id, message_gzip FROM logs
+            WHERE message_gzip IS NOT NULL AND ($1::timestamptz IS NULL OR (timestamp, id) > ($1, $2))
+            ORDER BY timestamp, id
+            LIMIT $3
+            "#
+        )
+            .bind(after.map(|(timestamp, _)| timestamp))
+            .bind(after.map(|(_, id)| id))
+            .bind(RESTORE_BATCH_SIZE)
+            .fetch_all(&pool)
+            .await
+        {
+            Ok(rows) => rows,
+            Err(e) => {
+                warn!("Failed to find logs stored gzipped, retrying in a minute: {}", e);
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                continue;
+            }
+        };
+        let Some((timestamp, id, _)) = rows.last() else {
+            break;
+        };
+        after = Some((*timestamp, *id));
+
+        for (timestamp, id, compressed) in rows {
+            let message = match decompress(&compressed) {
+                Ok(message) => message,
+                Err(e) => {
+                    warn!("Failed to decompress the stored message of log {}, leaving it gzipped: {}", id, e);
+                    continue;
+                }
+            };
+            let result = sqlx::query("UPDATE logs SET message = $1, message_gzip = NULL WHERE timestamp = $2 AND id = $3")
+                .bind(&message)
+                .bind(timestamp)
+                .bind(id)
+                .execute(&pool)
+                .await;
+            match result {
+                Ok(_) => restored += 1,
+                Err(e) => warn!("Failed to restore the full message of log {}: {}", id, e),
+            }
+        }
+    }
+    if restored > 0 {
+        info!("Restored the full message of {} logs stored gzipped", restored);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn gzip(message: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(message.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decompresses_gzipped_messages() {
+        let message = "panicked at 'index out of bounds'\n".repeat(1000);
+        assert_eq!(decompress(&gzip(&message)).unwrap(), message);
+        assert_eq!(decompress(&gzip("")).unwrap(), "");
+    }
+
+    #[test]
+    fn keeps_multibyte_characters_intact() {
+        let message = "Zahlung für Bestellung fehlgeschlagen — 支付失败 🚨 ".repeat(500);
+        assert_eq!(decompress(&gzip(&message)).unwrap(), message);
+    }
+
+    #[test]
+    fn rejects_what_is_not_gzipped_text() {
+        assert!(decompress(b"plain text").is_err());
+        let mut truncated = gzip(&"stack frame\n".repeat(100));
+        truncated.truncate(truncated.len() / 2);
+        assert!(decompress(&truncated).is_err());
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0xff, 0xfe, 0xfd]).unwrap();
+        assert!(decompress(&encoder.finish().unwrap()).is_err());
+    }
+
+    #[test]
+    fn quotes_table_names() {
+        assert_eq!(quote_ident("logs_p20261014"), "\"logs_p20261014\"");
+        assert_eq!(quote_ident("odd\"name"), "\"odd\"\"name\"");
+    }
+}
@@ -0,0 +1,87 @@
+//! Separate limits on concurrently running write and read requests.
+//!
+//! `MAX_CONCURRENT_WRITES` bounds the requests with a `POST`, `PUT`, `PATCH` or `DELETE`
+//! method and `MAX_CONCURRENT_READS` all the others, so a spike of either kind can't
+//! take every database connection from the other. A request over its limit waits up to
+//! `CONCURRENCY_QUEUE_MS` for a slot and is then answered with `503`. The health checks
+//! are never limited, so an overloaded instance is reported as such rather than as down.
+//! Streamed responses (tail, replay, export) give up their slot once their headers are
+//! sent; `POST /logs/stream-ingest` keeps its slot until the body ends.
+
+use crate::{env_or, ApiError};
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+const UNLIMITED_PATHS: &[&str] = &["/health", "/health/db", "/health/ready"];
+
+pub struct ConcurrencyLimits {
+    writes: Option<Arc<Semaphore>>,
+    reads: Option<Arc<Semaphore>>,
+    wait: Duration,
+}
+
+impl ConcurrencyLimits {
+    /// `None` when neither limit is set (or both are 0).
+    pub fn from_env() -> Option<Self> {
+        let limit = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .filter(|&n| n > 0)
+        };
+        let (writes, reads) = (limit("MAX_CONCURRENT_WRITES"), limit("MAX_CONCURRENT_READS"));
+        if writes.is_none() && reads.is_none() {
+            return None;
+        }
+        let wait = Duration::from_millis(env_or("CONCURRENCY_QUEUE_MS", 100));
+        info!(
+            "Limiting concurrent requests to {:?} writes and {:?} reads, queueing up to {:?}",
+            writes, reads, wait
+        );
+        Some(Self {
+            writes: writes.map(|n| Arc::new(Semaphore::new(n))),
+            reads: reads.map(|n| Arc::new(Semaphore::new(n))),
+            wait,
+        })
+    }
+}
+
+/// Runs the request once a slot of its kind is free, holding the slot until the response
+/// is ready.
+pub async fn enforce(State(limits): State<Arc<ConcurrencyLimits>>, request: Request, next: Next) -> Response {
+    if UNLIMITED_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    let write = matches!(*request.method(), Method::POST | Method::PUT | Method::PATCH | Method::DELETE);
+    let Some(slots) = (if write { &limits.writes } else { &limits.reads }) else {
+        return next.run(request).await;
+    };
+
+    let _permit = match tokio::time::timeout(limits.wait, slots.clone().acquire_owned()).await {
+        Ok(Ok(permit)) => permit,
+        _ => {
+            let kind = if write { "write" } else { "read" };
+            warn!(
+                "Rejected {} {}: no {} slot freed up within {:?}",
+                request.method(),
+                request.uri().path(),
+                kind,
+                limits.wait
+            );
+            return ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("too many concurrent {} requests; retry shortly", kind),
+            )
+            .into_response();
+        }
+    };
+    next.run(request).await
+}
@@ -0,0 +1,165 @@
+//! Per-request deadlines.
+//!
+//! A gateway in front of TideLogs sends `X-Request-Deadline` with the moment it will stop
+//! waiting, either as an RFC 3339 timestamp or as Unix epoch milliseconds. Each request is
+//! given whatever time remains until then, or `REQUEST_TIMEOUT_MS` when the header is
+//! absent. A request still running at its deadline is answered with `504` and its handler
+//! is dropped, so it issues no further queries. Dropping a query does not stop Postgres
+//! from running it, though, so every connection the request acquires from the pool gets a
+//! `statement_timeout` of the time remaining (plus a tenth of a second, so the `504`
+//! wins the race): a statement still running then is cancelled by the database itself.
+//! Connections used outside requests, by background tasks and streamed bodies, have the
+//! limit lifted again.
+//!
+//! Streamed bodies (tail, replay, export) are only covered until their headers are sent,
+//! and `POST /logs/stream-ingest`, whose request lasts as long as the agent keeps sending,
+//! is only bounded by an explicit header.
+//!
+//! A `504` says nothing about whether a write took effect: an insert can commit just before
+//! the deadline is noticed, so a client that retries an ingestion request after a `504` may
+//! store the log twice.
+
+use crate::ApiError;
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use sqlx::PgConnection;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+const HEADER: &str = "x-request-deadline";
+
+/// How much longer than its request a statement may run.
+const STATEMENT_TIMEOUT_GRACE: Duration = Duration::from_millis(100);
+
+tokio::task_local! {
+    /// When the request being handled on this task is abandoned
+    static DEADLINE: Instant;
+}
+
+/// Long-lived requests the default timeout does not apply to.
+const UNBOUNDED_PATHS: &[&str] = &["/logs/stream-ingest"];
+
+/// The deadline a header value names, or `None` if it is neither format.
+fn parse(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(millis) = value.parse::<i64>() {
+        return DateTime::from_timestamp_millis(millis);
+    }
+    DateTime::parse_from_rfc3339(value).ok().map(|t| t.with_timezone(&Utc))
+}
+
+/// Runs the request under its deadline. `default` applies without the header; `None`
+/// leaves such requests unbounded.
+pub async fn enforce(State(default): State<Option<Duration>>, request: Request, next: Next) -> Response {
+    let budget = match request.headers().get(HEADER) {
+        Some(value) => {
+            let Some(deadline) = value.to_str().ok().and_then(parse) else {
+                return ApiError::bad_request(
+                    "X-Request-Deadline must be an RFC 3339 timestamp or Unix epoch milliseconds",
+                )
+                .into_response();
+            };
+            // Already passed: the caller has given up, so don't start at all
+            match (deadline - Utc::now()).to_std() {
+                Ok(budget) if !budget.is_zero() => Some(budget),
+                _ => return expired(),
+            }
+        }
+        None if UNBOUNDED_PATHS.contains(&request.uri().path()) => None,
+        None => default,
+    };
+    let Some(budget) = budget else {
+        return next.run(request).await;
+    };
+
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let deadline = Instant::now() + budget;
+    match DEADLINE.scope(deadline, tokio::time::timeout_at(deadline, next.run(request))).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!("{} {} abandoned at its deadline after {:?}", method, path, budget);
+            expired()
+        }
+    }
+}
+
+fn expired() -> Response {
+    ApiError::new(StatusCode::GATEWAY_TIMEOUT, "request deadline exceeded").into_response()
+}
+
+/// The time left until the deadline of the request handled on this task, if it has one.
+pub fn remaining() -> Option<Duration> {
+    DEADLINE.try_with(|deadline| deadline.saturating_duration_since(Instant::now())).ok()
+}
+
+/// Sets the `statement_timeout` of `connection` for the request acquiring it, from the time
+/// its deadline leaves, or resets it when acquired outside a request with a deadline. Runs
+/// whenever the pool hands out a connection.
+pub async fn limit_statements(connection: &mut PgConnection) -> Result<(), sqlx::Error> {
+    match remaining() {
+        Some(remaining) => {
+            let millis = (remaining + STATEMENT_TIMEOUT_GRACE).as_millis().to_string();
+            sqlx::query("SELECT set_config('statement_timeout', $1, false)")
+                .bind(millis)
+                .execute(connection)
+                .await?;
+        }
+        None => {
+            sqlx::query("RESET statement_timeout").execute(connection).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_epoch_milliseconds() {
+        assert_eq!(parse("1705314600000"), DateTime::from_timestamp_millis(1_705_314_600_000));
+        assert_eq!(parse(" 1705314600123 "), DateTime::from_timestamp_millis(1_705_314_600_123));
+        assert_eq!(parse("0"), DateTime::from_timestamp_millis(0));
+    }
+
+    #[test]
+    fn parses_rfc3339_in_any_offset() {
+        let expected = DateTime::from_timestamp_millis(1_705_314_600_500);
+        assert_eq!(parse("2024-01-15T10:30:00.5Z"), expected);
+        assert_eq!(parse("2024-01-15T12:30:00.500+02:00"), expected);
+    }
+
+    #[test]
+    fn rejects_other_values() {
+        for value in ["", "soon", "2024-01-15", "2024-01-15 10:30:00", "1.5e12", "9223372036854775807"] {
+            assert_eq!(parse(value), None, "{}", value);
+        }
+    }
+
+    #[tokio::test]
+    async fn remaining_is_the_time_left_in_the_request() {
+        assert_eq!(remaining(), None);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let left = DEADLINE.scope(deadline, async { remaining() }).await.unwrap();
+        assert!(left > Duration::from_secs(4) && left <= Duration::from_secs(5));
+        assert_eq!(remaining(), None);
+    }
+
+    #[tokio::test]
+    async fn remaining_is_zero_past_the_deadline() {
+        let deadline = Instant::now() - Duration::from_millis(10);
+        assert_eq!(DEADLINE.scope(deadline, async { remaining() }).await, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn expired_requests_get_504() {
+        assert_eq!(expired().status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+}
@@ -0,0 +1,91 @@
+//! Response body encoding, negotiated from the `Accept` header and the query string.
+//!
+//! JSON is the default. Clients that list `application/msgpack` (or the older
+//! `application/x-msgpack`) get the same document encoded as MessagePack instead, and
+//! `pretty=true` in the query string asks for indented JSON for reading by hand.
+
+use axum::extract::{FromRequestParts, Query};
+use axum::http::{header, request::Parts, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Json, Response};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use tracing::warn;
+use utoipa::IntoParams;
+
+const MSGPACK: &str = "application/msgpack";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Json,
+    PrettyJson,
+    MessagePack,
+}
+
+/// The query parameter read by `ResponseFormat`, for routes' OpenAPI `params`.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PrettyParam {
+    /// Indent the JSON response
+    pretty: Option<bool>,
+}
+
+impl ResponseFormat {
+    fn from_accept(accept: &str) -> Self {
+        let wants_msgpack = accept
+            .split(',')
+            .filter_map(|range| range.split(';').next())
+            .map(str::trim)
+            .any(|media| media.eq_ignore_ascii_case(MSGPACK) || media.eq_ignore_ascii_case("application/x-msgpack"));
+        if wants_msgpack {
+            Self::MessagePack
+        } else {
+            Self::Json
+        }
+    }
+
+    pub fn respond<T: Serialize>(self, body: &T) -> Response {
+        let mut response = match self {
+            Self::Json => Json(body).into_response(),
+            Self::PrettyJson => match serde_json::to_vec_pretty(body) {
+                Ok(bytes) => ([(header::CONTENT_TYPE, "application/json")], bytes).into_response(),
+                Err(e) => {
+                    warn!("Failed to encode JSON response: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+            Self::MessagePack => match encode_msgpack(body) {
+                Ok(bytes) => ([(header::CONTENT_TYPE, MSGPACK)], bytes).into_response(),
+                Err(e) => {
+                    warn!("Failed to encode MessagePack response: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+        };
+        response.headers_mut().insert(header::VARY, HeaderValue::from_static("accept"));
+        response
+    }
+}
+
+/// Encodes with struct fields as map keys and in human-readable mode, so ids and
+/// timestamps are strings exactly as in the JSON form.
+fn encode_msgpack<T: Serialize>(body: &T) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    let mut bytes = Vec::new();
+    let mut serializer = rmp_serde::Serializer::new(&mut bytes).with_struct_map().with_human_readable();
+    body.serialize(&mut serializer)?;
+    Ok(bytes)
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ResponseFormat {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let format = parts
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .map_or(Self::Json, Self::from_accept);
+        let pretty = Query::<PrettyParam>::try_from_uri(&parts.uri)
+            .is_ok_and(|Query(param)| param.pretty.unwrap_or(false));
+        Ok(if format == Self::Json && pretty { Self::PrettyJson } else { format })
+    }
+}
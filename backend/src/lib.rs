@@ -0,0 +1,5 @@
+//! TideLogs as a library, for Rust services that query the logs table without going
+//! through the HTTP API. The server is built on the same modules.
+
+pub mod query;
+pub mod service_aliases;
@@ -1,54 +1,1001 @@
+mod alerts;
+mod archive;
+mod batcher;
+mod breaker;
+mod bus;
+mod compression;
+mod concurrency;
+mod cursor_tokens;
+mod deadline;
+mod encoding;
+mod metadata_index;
+mod metadata_types;
+mod partitions;
+mod query_cache;
+mod quota;
+mod retention;
+mod retry_queue;
+mod self_monitor;
+mod shared_store;
+mod shedding;
+mod signing;
+mod strict_json;
+mod tail;
+mod telemetry;
+
 use axum::{
-    extract::{Query, State},
-    http::{HeaderValue, Method, StatusCode},
-    response::Json,
-    routing::{get, post},
+    body::Body,
+    extract::{rejection::{JsonRejection, StringRejection}, ConnectInfo, FromRequestParts, OriginalUri, Path, Query, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Json, Response,
+    },
+    routing::{delete, get, head, post, put},
     Router,
 };
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, FixedOffset, SecondsFormat, Utc};
+use chrono_tz::Tz;
+use futures_util::{StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use sqlx::{PgPool, Row};
-use std::collections::HashMap;
+use sqlx::{postgres::{PgPoolOptions, PgRow}, PgPool, QueryBuilder, Row};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::future::Future;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use alerts::SilenceAlerts;
+use archive::Archiver;
+use batcher::{InsertBatcher, NewLog};
+use breaker::CircuitBreaker;
+use bus::BusPublisher;
+use compression::MessageCompression;
+use concurrency::ConcurrencyLimits;
+use cursor_tokens::CursorTokens;
+use encoding::{PrettyParam, ResponseFormat};
+use metadata_index::QueryableKeys;
+use metadata_types::MetadataSchema;
+use partitions::PartitionManager;
+use query_cache::QueryCache;
+use quota::{QuotaExceeded, Quotas, Reservation};
+use retention::RetentionPolicy;
+use retry_queue::{is_transient, InsertFailure, RetryQueue};
+use tidelogs_backend::query::{self, comma_list, has_filters, push_filters, LogFilters, RelativeWindow};
+use tidelogs_backend::service_aliases::ServiceAliases;
+use self_monitor::ErrorMonitor;
+use shared_store::SharedStore;
+use shedding::LoadShedder;
+use signing::LogSigner;
+use strict_json::IngestJson;
+use tail::{TailHub, TailLimit};
+use telemetry::Telemetry;
+use tokio::sync::{broadcast::error::RecvError, OwnedSemaphorePermit, RwLock, Semaphore};
 use tower_http::cors::{Any, CorsLayer};
 use tracing::{info, warn, error};
+use utoipa::{IntoParams, OpenApi, ToSchema};
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Canonical levels accepted on ingestion.
+const LEVELS: &[&str] = &["ERROR", "WARN", "INFO", "DEBUG", "METRIC"];
+
+/// Service and level used by `POST /logs/text` when the query omits them.
+const DEFAULT_TEXT_SERVICE: &str = "shell";
+const DEFAULT_TEXT_LEVEL: &str = "INFO";
+
+/// Built-in level aliases; `LEVEL_ALIASES` can add to or override these.
+const DEFAULT_LEVEL_ALIASES: &[(&str, &str)] = &[
+    ("WARNING", "WARN"),
+    ("ERR", "ERROR"),
+    ("CRIT", "ERROR"),
+    ("CRITICAL", "ERROR"),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 struct LogEntry {
     id: Option<Uuid>,
-    timestamp: Option<DateTime<Utc>>,
+    timestamp: Option<DateTime<FixedOffset>>,
+    service: String,
+    level: String,
+    message: String,
+    #[schema(value_type = Option<Object>)]
+    metadata: Option<Value>,
+    created_at: Option<DateTime<FixedOffset>>,
+    /// Server-assigned position taken when the insert starts, for `after_seq`; a slow
+    /// insert can commit after logs with a higher one
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    seq: Option<i64>,
+    /// Excerpt of the message around the search match, with `highlight=true`
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    match_snippet: Option<String>,
+}
+
+/// Body of `POST /logs/envelope`.
+#[derive(Debug, Deserialize, ToSchema)]
+struct LogEnvelope {
+    /// Service of every event
     service: String,
+    /// Metadata shared by every event; an event's own metadata keys take precedence
+    #[schema(value_type = Option<Object>)]
+    common_metadata: Option<Value>,
+    events: Vec<EnvelopeEvent>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct EnvelopeEvent {
     level: String,
     message: String,
+    #[schema(value_type = Option<Object>)]
     metadata: Option<Value>,
-    created_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Deserialize)]
-struct LogFilters {
+impl LogEntry {
+    /// Re-expresses the timestamps in `tz`; the instants themselves are unchanged.
+    fn in_timezone(mut self, tz: Tz) -> Self {
+        self.timestamp = self.timestamp.map(|t| t.with_timezone(&tz).fixed_offset());
+        self.created_at = self.created_at.map(|t| t.with_timezone(&tz).fixed_offset());
+        self
+    }
+}
+
+/// `LogFilters` from the query string together with its `metadata.<key>=value` filters,
+/// each matching logs whose top-level `key` has exactly that string value. Only keys in
+/// `QUERYABLE_METADATA_KEYS` may be named; any other is rejected with `400`.
+struct Filters(LogFilters);
+
+impl FromRequestParts<AppState> for Filters {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let Query(mut filters) = Query::<LogFilters>::try_from_uri(&parts.uri).map_err(IntoResponse::into_response)?;
+        let Query(pairs) =
+            Query::<Vec<(String, String)>>::try_from_uri(&parts.uri).map_err(IntoResponse::into_response)?;
+        let metadata = pairs
+            .into_iter()
+            .filter_map(|(name, value)| Some((name.strip_prefix("metadata.")?.to_string(), value)));
+        add_metadata_filters(state, &mut filters, metadata).map_err(IntoResponse::into_response)?;
+        filters.validate().map_err(|e| ApiError::bad_request(e).into_response())?;
+        normalize_level_filters(state, &mut filters);
+        Ok(Self(filters))
+    }
+}
+
+/// Normalizes `level` and `exclude_level` the way `validate_log` normalizes stored levels,
+/// so `level=warning` and `exclude_level=Warning` both match logs stored as `WARN`.
+fn normalize_level_filters(state: &AppState, filters: &mut LogFilters) {
+    if let Some(level) = &mut filters.level {
+        *level = normalize_level(level, &state.level_aliases);
+    }
+    if let Some(levels) = filters.exclude_level.as_deref().and_then(comma_list) {
+        let levels: Vec<String> = levels.iter().map(|level| normalize_level(level, &state.level_aliases)).collect();
+        filters.exclude_level = Some(levels.join(","));
+    }
+}
+
+/// Adds `key = value` metadata filters, failing with `400` on the first key that is not in
+/// `QUERYABLE_METADATA_KEYS`.
+fn add_metadata_filters(
+    state: &AppState,
+    filters: &mut LogFilters,
+    metadata: impl IntoIterator<Item = (String, String)>,
+) -> Result<(), ApiError> {
+    for (key, value) in metadata {
+        if !state.queryable_metadata_keys.contains(&key) {
+            let allowed = state.queryable_metadata_keys.sorted();
+            let message = if allowed.is_empty() {
+                format!("cannot filter on metadata key '{}': no metadata keys are queryable", key)
+            } else {
+                format!("cannot filter on metadata key '{}': queryable keys are {}", key, allowed.join(", "))
+            };
+            return Err(ApiError::bad_request(message));
+        }
+        filters.metadata.insert(key, value);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TailParams {
+    service: Option<String>,
+    level: Option<String>,
+    /// Fraction of the matching logs to forward, picked at random, e.g. `0.05` for about
+    /// 5% (default 1, every log)
+    sample: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DeleteParams {
+    /// Only count the logs the filters match, returning `{"would_delete": N}`
+    dry_run: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ReplayParams {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    service: Option<String>,
+    /// Maximum number of logs emitted per second; unthrottled when absent.
+    rate: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SyncParams {
+    /// `created_at` of the last log already synced, usually the previous
+    /// `next_since_created`; from the oldest log when absent
+    since_created: Option<DateTime<Utc>>,
+    /// Id of the last log already synced, usually the previous `next_since_id`; requires
+    /// `since_created`
+    since_id: Option<Uuid>,
+    /// With `CURSOR_SECRET` set, the previous `next_cursor`, in place of `since_created`
+    /// and `since_id`
+    cursor: Option<String>,
+    /// Logs to return (default 100, max 1000)
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct SyncResponse {
+    /// Logs in ascending `(created_at, id)` order
+    logs: Vec<LogEntry>,
+    /// Cursor of the last log in this page (or the request's cursor when the page is
+    /// empty), to pass back as `since_created`/`since_id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_since_created: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_since_id: Option<Uuid>,
+    /// With `CURSOR_SECRET` set, the same cursor as an opaque token, to pass back as `cursor`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+    /// Whether the page was full, so more logs may already be waiting
+    has_more: bool,
+}
+
+/// What a `GET /logs/sync` cursor token holds: `(created_at, id)` of the last log synced.
+#[derive(Serialize, Deserialize)]
+struct SyncCursor(DateTime<Utc>, Option<Uuid>);
+
+/// What a `GET /logs` cursor token holds.
+#[derive(Serialize, Deserialize)]
+enum LogCursor {
+    /// `since` and `since_id`
+    Since(Option<DateTime<Utc>>, Option<Uuid>),
+    /// `after_seq`
+    Seq(i64),
+}
+
+/// The opaque cursor `GET /logs` takes with `CURSOR_SECRET` set.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CursorParam {
+    /// With `CURSOR_SECRET` set, the previous `next_cursor`, in place of `since`/`since_id`
+    /// or `after_seq`
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ExportParams {
+    /// Comma-separated metadata keys exported as their own columns, in place of the
+    /// `metadata` JSON column (at most 100)
+    metadata_cols: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CreateLogParams {
+    /// `sync` (default) acknowledges only once the commit is flushed to disk; `async`
+    /// acknowledges sooner, at the risk of losing the log if the database crashes
+    durability: Option<Durability>,
+}
+
+/// How durable a log must be before its insert is acknowledged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum Durability {
+    #[default]
+    Sync,
+    /// Commits with `synchronous_commit = off`: Postgres acknowledges before the WAL
+    /// reaches disk, so a crash can lose the most recent such logs (never corrupt them)
+    Async,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TextLogParams {
+    /// Defaults to `shell`
     service: Option<String>,
+    /// Defaults to `INFO`
     level: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ReplayLogParams {
+    /// Store the log again as a new row (`true`) instead of only re-broadcasting it
+    /// (`false`, the default)
+    insert: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ContextParams {
+    /// Logs to return before the target (default 20, max 500)
+    before: Option<i64>,
+    /// Logs to return after the target (default 20, max 500)
+    after: Option<i64>,
+    /// Only include logs from the target's service
+    same_service: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct BatchGetParams {
+    /// Comma-separated log ids (at most `MAX_BATCH_GET_IDS`)
+    ids: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct BatchGetResponse {
+    /// The logs found, in the order their ids were requested
+    logs: Vec<LogEntry>,
+    /// Requested ids with no stored log
+    missing: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct LogContext {
+    /// Preceding logs, oldest first
+    before: Vec<LogEntry>,
+    target: LogEntry,
+    /// Following logs, oldest first
+    after: Vec<LogEntry>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct LogVerification {
+    id: Uuid,
+    /// `valid`, `invalid` when a signed field changed after ingestion (or the key did),
+    /// or `unsigned` for logs stored while signing was off
+    status: &'static str,
+}
+
+/// Optional `[from, to)` bound on log timestamps.
+#[derive(Debug, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TimeWindow {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ServiceSummary {
+    service: String,
+    count: i64,
+    latest_log: LogEntry,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AnomalyParams {
+    /// Maximum number of rows returned (default 100, max 1000)
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct Anomaly {
+    /// `created_before_timestamp` or `outside_retention`
+    reason: String,
+    #[serde(flatten)]
+    log: LogEntry,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+struct ReclassifyRequest {
+    /// SQL `LIKE` pattern the message must match, e.g. `Payment retry%`
+    message_pattern: String,
+    old_level: String,
+    new_level: String,
+    /// Only reclassify this service's logs
+    service: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+struct TagRequest {
+    /// The logs to tag, as filters in the same form `POST /queries` saves; at least one
+    /// is required
+    #[schema(value_type = Object)]
+    filters: Value,
+    /// Metadata key to set on every matching log, replacing any value it had
+    key: String,
+    value: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct SaveQueryRequest {
+    /// Letters, digits, `-` and `_`, at most 100 characters
+    name: String,
+    /// The same filters `GET /logs` takes, e.g. `{"service": "api", "level": "ERROR"}`,
+    /// with `metadata.<key>` filters as a `metadata` object of key to value
+    #[schema(value_type = Object)]
+    filters: Value,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct SavedQuery {
+    name: String,
+    /// The saved filters, unset ones left out
+    #[schema(value_type = Object)]
+    filters: Value,
+    created_at: DateTime<Utc>,
+}
+
+/// Paging for `GET /queries/{name}/logs`, applied on top of the saved filters.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SavedQueryPaging {
     limit: Option<i64>,
     offset: Option<i64>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+    /// Cursors replacing any saved with the query, as followed by the `Link` header
+    since: Option<DateTime<Utc>>,
+    since_id: Option<Uuid>,
+    after_seq: Option<i64>,
+    /// With `CURSOR_SECRET` set, the previous `next_cursor` in place of the cursors above
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+struct MaintenanceMode {
+    /// Whether writes are being rejected
+    enabled: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct PurgeRequest {
+    /// Must match the server's `PURGE_TOKEN`
+    token: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 struct LogResponse {
     logs: Vec<LogEntry>,
     total: i64,
+    page: i64,
+    per_page: i64,
+    total_pages: i64,
+    /// Cursor of the newest log in this page (or the request's cursor when the page is
+    /// empty), to pass back as `since`/`since_id` on the next poll
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_since: Option<DateTime<FixedOffset>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_since_id: Option<Uuid>,
+    /// With `after_seq`, the `seq` of the last log in this page (or the request's
+    /// `after_seq` when the page is empty), to pass back on the next poll
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_after_seq: Option<i64>,
+    /// With `CURSOR_SECRET` set, the cursor for the next poll as an opaque token, to pass
+    /// back as `cursor`, in place of the fields above
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+    /// Served from the `QUERY_CACHE_TTL_MS` cache; the `Age` header says how old it is
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    cached: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct MetricsParams {
+    /// Recompute instead of serving the cached snapshot
+    fresh: Option<bool>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 struct MetricsResponse {
+    total_logs: i64,
+    /// False when no logs have been stored yet; the maps are then empty
+    has_data: bool,
+    /// Share of logs (0.0–1.0) whose level is one of `ERROR_LEVELS`
+    error_rate: f64,
+    services: HashMap<String, i64>,
+    levels: HashMap<String, i64>,
+    computed_at: DateTime<Utc>,
+    /// True when some of the queries ran past `METRICS_QUERY_TIMEOUT_MS`
+    partial: bool,
+    /// What could not be computed in time, among `total_logs`, `services`, `levels` and
+    /// `error_rate`; those fields then hold zeroes or empty maps
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    unavailable: Vec<String>,
+}
+
+/// A metrics snapshot together with its `ETag`, so conditional requests are answered
+/// without re-serializing anything.
+#[derive(Clone)]
+struct CachedMetrics {
+    metrics: MetricsResponse,
+    etag: String,
+}
+
+impl CachedMetrics {
+    /// The tag covers everything but `computed_at`, so a recompute that finds the same
+    /// numbers keeps the same tag.
+    fn new(metrics: MetricsResponse) -> Self {
+        let mut value = serde_json::to_value(&metrics).unwrap_or_default();
+        if let Some(object) = value.as_object_mut() {
+            object.remove("computed_at");
+        }
+        let mut hasher = DefaultHasher::new();
+        value.to_string().hash(&mut hasher);
+        Self {
+            metrics,
+            etag: format!("\"{:016x}\"", hasher.finish()),
+        }
+    }
+
+    /// Whether an `If-None-Match` header value names this snapshot.
+    fn matches(&self, if_none_match: &str) -> bool {
+        if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == self.etag)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HistoryParams {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    /// Snapshots per page (default 100, max 1000)
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct MetricsSnapshot {
+    taken_at: DateTime<Utc>,
+    total_logs: i64,
+    error_rate: f64,
+    #[schema(value_type = Object)]
+    services: Value,
+    #[schema(value_type = Object)]
+    levels: Value,
+}
+
+#[derive(Debug, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HourlyParams {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    service: Option<String>,
+    /// IANA timezone the hours are counted in (default UTC)
+    tz: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct GroupByParams {
+    /// Top-level metadata key to group by; must be listed in `GROUP_BY_KEYS`
+    key: String,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct GroupByResponse {
+    key: String,
+    /// Log count per value of the key
+    counts: HashMap<String, i64>,
+    /// Logs without the key, or with it set to `null`
+    null: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DeltaParams {
+    /// Count logs stored strictly after this time, usually the previous `next_since`
+    since: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct MetricsDelta {
+    /// Logs stored in `(since, next_since]`
     total_logs: i64,
     services: HashMap<String, i64>,
     levels: HashMap<String, i64>,
+    /// Database time the counts run up to; pass it as `since` on the next poll
+    next_since: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DistinctParams {
+    /// Column to list the values of: `service` or `level`
+    column: String,
+    /// Include each value's number of logs (default true); without them values come in
+    /// alphabetical order
+    counts: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct IncidentParams {
+    /// Longest quiet period within one incident, e.g. `5m` (the default); an error after a
+    /// longer gap starts the next incident
+    #[param(value_type = Option<String>)]
+    gap: Option<RelativeWindow>,
+    /// Fewest errors an incident needs to be reported (default 1)
+    min_size: Option<i64>,
+}
+
+/// A burst of errors with no gap longer than `gap` between consecutive ones.
+#[derive(Debug, Serialize, ToSchema)]
+struct Incident {
+    /// Timestamp of the first error
+    start: DateTime<Utc>,
+    /// Timestamp of the last error
+    end: DateTime<Utc>,
+    count: i64,
+    /// Services that logged the errors, by canonical name
+    services: Vec<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct DistinctValue {
+    value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ServiceLag {
+    service: String,
+    /// Timestamp of the service's newest log
+    last_seen: DateTime<Utc>,
+    /// Seconds between `last_seen` and now
+    lag_seconds: f64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct HourBucket {
+    /// Hour of the day, 0–23
+    hour: u32,
+    count: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct HistoryResponse {
+    snapshots: Vec<MetricsSnapshot>,
+    /// Snapshots in the window across all pages
+    total: i64,
 }
 
 #[derive(Clone)]
 struct AppState {
     pool: PgPool,
+    telemetry: Arc<Telemetry>,
+    slow_insert_threshold: Duration,
+    max_metadata_depth: usize,
+    level_aliases: Arc<HashMap<String, String>>,
+    metrics_cache: Arc<RwLock<Option<CachedMetrics>>>,
+    metrics_query_timeout: Option<Duration>,
+    bus: Option<Arc<BusPublisher>>,
+    batcher: Option<Arc<InsertBatcher>>,
+    retry_queue: Option<Arc<RetryQueue>>,
+    load_shedder: Option<Arc<LoadShedder>>,
+    tail: Arc<TailHub>,
+    reject_duplicate_keys: bool,
+    quotas: Arc<Quotas>,
+    required_metadata: Arc<HashMap<String, Vec<String>>>,
+    /// `DEFAULT_METADATA` keys added to every ingested log that does not set them
+    default_metadata: Arc<serde_json::Map<String, Value>>,
+    service_aliases: Arc<ServiceAliases>,
+    metadata_schema: Option<Arc<MetadataSchema>>,
+    /// Level stored in place of an unrecognized one; unknown levels are rejected when unset
+    unknown_level_fallback: Option<String>,
+    service_naming: ServiceNaming,
+    delete_batch_size: i64,
+    max_batch_get_ids: usize,
+    max_batch_size: usize,
+    stream_batch_size: usize,
+    signer: Option<Arc<LogSigner>>,
+    stream_flush_interval: Duration,
+    error_levels: Arc<Vec<String>>,
+    group_by_keys: Arc<Vec<String>>,
+    queryable_metadata_keys: Arc<QueryableKeys>,
+    purge_token: Option<String>,
+    admin_token: Option<String>,
+    health_secret: Option<String>,
+    retention: Arc<RetentionPolicy>,
+    max_table_bytes: Option<u64>,
+    /// Last measured size of the logs table, including indexes and TOAST
+    table_bytes: Arc<AtomicU64>,
+    /// Rejects writes with `503` while set, see `ensure_writable`
+    maintenance: Arc<AtomicBool>,
+    /// Bounds how many expensive reads run at once, so reporting load cannot starve
+    /// ingestion of pool connections
+    expensive_reads: Arc<Semaphore>,
+    expensive_read_wait: Duration,
+    /// Bounds how many `/logs/export` streams run at once. Kept apart from
+    /// `expensive_reads` because an export holds its slot until the download finishes.
+    exports: Arc<Semaphore>,
+    debug_errors: bool,
+    /// Set when `SLOW_QUERY_LOG` is on
+    slow_query_threshold: Option<Duration>,
+    /// How old a log's `created_at` must be before `/logs/sync` returns it
+    sync_settle: Duration,
+    cursor_tokens: Option<Arc<CursorTokens>>,
+    /// Deepest `offset + limit` that `GET /logs` serves; unlimited when `None`
+    max_result_window: Option<i64>,
+    log_query_cache: Option<Arc<QueryCache<LogResponse>>>,
+    /// Set with `SELF_MONITOR`; decides `/health/ready`
+    error_monitor: Option<Arc<ErrorMonitor>>,
+    breaker: Option<Arc<CircuitBreaker>>,
+}
+
+/// Handler error carrying a status code and a message returned as `{"error": ...}`.
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+    errors: Vec<FieldError>,
+    debug: Option<Value>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            errors: Vec::new(),
+            debug: None,
+        }
+    }
+
+    /// A `400` listing every problem found; `error` joins them for clients that only
+    /// read that field.
+    fn validation(errors: Vec<FieldError>) -> Self {
+        let message = errors.iter().map(|e| e.message.as_str()).collect::<Vec<_>>().join("; ");
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            message,
+            errors,
+            debug: None,
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+}
+
+impl From<StatusCode> for ApiError {
+    fn from(status: StatusCode) -> Self {
+        Self::new(status, status.canonical_reason().unwrap_or("error"))
+    }
+}
+
+/// Body of every error response.
+#[derive(Debug, Serialize, ToSchema)]
+struct ErrorBody {
+    error: String,
+    /// Every validation problem, for rejected logs
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+    /// The applied filters and the kind of failure, only with `DEBUG_ERRORS`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debug: Option<Value>,
+}
+
+/// One problem with a submitted log.
+#[derive(Debug, Serialize, ToSchema)]
+struct FieldError {
+    /// `service`, `level`, `message`, `metadata` or `metadata.<key>`, prefixed with the
+    /// log's index (e.g. `[2].level`) for batches
+    field: String,
+    message: String,
+}
+
+impl FieldError {
+    fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// A `500` for a failed read query. With `DEBUG_ERRORS` the body also echoes the
+/// applied `filters` and what kind of failure it was; the SQL and connection details are
+/// never included.
+fn read_failed(state: &AppState, what: &str, filters: &impl Serialize, e: sqlx::Error) -> ApiError {
+    warn!("Failed to {}: {}", what, e);
+    let mut error = ApiError::from(StatusCode::INTERNAL_SERVER_ERROR);
+    if state.debug_errors {
+        error.debug = Some(serde_json::json!({
+            "operation": what,
+            "filters": given_filters(filters),
+            "failure": describe_db_error(&e),
+        }));
+    }
+    error
+}
+
+/// `filters` as JSON with the unset ones left out.
+fn given_filters(filters: &impl Serialize) -> Value {
+    let mut filters = serde_json::to_value(filters).unwrap_or(Value::Null);
+    if let Value::Object(map) = &mut filters {
+        map.retain(|_, value| !value.is_null());
+    }
+    filters
+}
+
+/// Awaits `query`, logging it at `warn!` with its endpoint and filters when it takes
+/// longer than `SLOW_QUERY_THRESHOLD_MS` (only with `SLOW_QUERY_LOG` on).
+async fn timed<T>(
+    state: &AppState,
+    endpoint: &str,
+    filters: &impl Serialize,
+    query: impl Future<Output = Result<T, sqlx::Error>>,
+) -> Result<T, sqlx::Error> {
+    let Some(threshold) = state.slow_query_threshold else {
+        let result = query.await;
+        record_db_outcome(state, db_outcome(&result));
+        return result;
+    };
+    let started = Instant::now();
+    let result = query.await;
+    let elapsed = started.elapsed();
+    record_db_outcome(state, db_outcome(&result));
+    if elapsed > threshold {
+        warn!(
+            "Slow query on {}: took {:?} (threshold {:?}) with filters {}",
+            endpoint,
+            elapsed,
+            threshold,
+            given_filters(filters)
+        );
+    }
+    result
+}
+
+/// Whether a database operation succeeded (`Some(true)`) or could not reach the database
+/// (`Some(false)`); `None` for an error the statement itself caused, such as a constraint
+/// violation, bad input or a cancelled statement, which says nothing about the database's
+/// health.
+fn db_outcome<T>(result: &Result<T, sqlx::Error>) -> Option<bool> {
+    match result {
+        Ok(_) => Some(true),
+        Err(e) => is_transient(e).then_some(false),
+    }
+}
+
+/// Counts a database operation towards the `SELF_MONITOR` error rate and the circuit
+/// breaker, given its `db_outcome`.
+fn record_db_outcome(state: &AppState, outcome: Option<bool>) {
+    let Some(ok) = outcome else {
+        return;
+    };
+    if let Some(monitor) = &state.error_monitor {
+        monitor.record(ok);
+    }
+    if let Some(breaker) = &state.breaker {
+        breaker.record(ok);
+    }
+}
+
+/// A description of `e` that is safe to return to clients. Database errors are reduced to
+/// their SQLSTATE and, for constraint violations, the constraint's name: Postgres
+/// messages can quote column values and other rows' data.
+fn describe_db_error(e: &sqlx::Error) -> String {
+    match e {
+        sqlx::Error::Database(db) => {
+            let mut description = match db.code() {
+                Some(code) => format!("database error {}", code),
+                None => "database error".to_string(),
+            };
+            if let Some(constraint) = db.constraint() {
+                description.push_str(&format!(" on constraint {}", constraint));
+            }
+            description
+        }
+        sqlx::Error::PoolTimedOut => "timed out waiting for a database connection".to_string(),
+        sqlx::Error::PoolClosed => "the connection pool is closed".to_string(),
+        sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::Protocol(_) => {
+            "lost the connection to the database".to_string()
+        }
+        sqlx::Error::ColumnDecode { .. } | sqlx::Error::Decode(_) => "failed to decode a result row".to_string(),
+        _ => "unexpected database error".to_string(),
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::new(rejection.status(), rejection.body_text())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: self.message,
+            errors: self.errors,
+            debug: self.debug,
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "TideLogs API"),
+    paths(
+        health_check,
+        readiness,
+        db_health,
+        get_maintenance,
+        set_maintenance,
+        create_log,
+        create_text_log,
+        create_log_batch,
+        create_log_envelope,
+        stream_ingest_logs,
+        get_logs,
+        head_logs,
+        get_log_count,
+        delete_logs,
+        reclassify_logs,
+        tag_logs,
+        rollback_reclassify,
+        purge_all_logs,
+        replay_logs,
+        sync_logs,
+        export_logs,
+        tail_logs,
+        get_log_summary,
+        get_log_context,
+        replay_log,
+        verify_log,
+        batch_get_logs,
+        get_anomalies,
+        get_distinct_values,
+        get_incidents,
+        save_query,
+        list_saved_queries,
+        delete_saved_query,
+        get_saved_query_logs,
+        get_metrics,
+        get_metrics_history,
+        get_metrics_delta,
+        get_metrics_lag,
+        get_hourly_distribution,
+        get_group_by,
+        get_metadata_cardinality,
+        get_prometheus_metrics,
+    ),
+    components(schemas(DbHealth, ReplicaStatus, LogEntry, LogEnvelope, EnvelopeEvent, Durability, QueuedResponse, StreamIngestSummary, StreamLineError, LogResponse, SyncResponse, ServiceSummary, LogContext, LogVerification, BatchGetResponse, Anomaly, DistinctValue, Incident, MetricsResponse, HistoryResponse, MetricsSnapshot, MetricsDelta, ServiceLag, HourBucket, GroupByResponse, ReclassifyRequest, TagRequest, SaveQueryRequest, SavedQuery, MaintenanceMode, PurgeRequest, ErrorBody, FieldError)),
+    modifiers(&AdminTokenScheme)
+)]
+struct ApiDoc;
+
+/// Registers the `ADMIN_TOKEN` bearer scheme referenced by admin routes.
+struct AdminTokenScheme;
+
+impl utoipa::Modify for AdminTokenScheme {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+        openapi.components.get_or_insert_with(Default::default).add_security_scheme(
+            "admin_token",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+    }
 }
 
 #[tokio::main]
@@ -65,15 +1012,29 @@ async fn main() -> anyhow::Result<()> {
 
     info!("Connecting to database at {}", database_url);
 
-    // Retry connection logic
-    let mut retries = 5;
+    // Retry connection logic: exponential backoff with jitter so many instances starting
+    // together don't reconnect in lockstep
+    let max_retries: u32 = env_or("DB_CONNECT_MAX_RETRIES", 5);
+    let base_delay = Duration::from_millis(env_or("DB_CONNECT_BASE_DELAY_MS", 1000));
+    let mut attempt = 0;
     let pool = loop {
-        match PgPool::connect(&database_url).await {
+        let options = PgPoolOptions::new()
+            .after_connect(|connection, _| Box::pin(deadline::limit_statements(connection)))
+            .before_acquire(|connection, _| {
+                Box::pin(async move { deadline::limit_statements(connection).await.map(|()| true) })
+            });
+        match options.connect(&database_url).await {
             Ok(pool) => break pool,
-            Err(e) if retries > 0 => {
-                warn!("Failed to connect to database, retrying... ({} attempts left)", retries);
-                retries -= 1;
-                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            Err(e) if attempt < max_retries => {
+                let delay = backoff_delay(base_delay, attempt);
+                attempt += 1;
+                warn!(
+                    "Failed to connect to database ({}), retrying in {:?} ({} attempts left)",
+                    e,
+                    delay,
+                    max_retries - attempt + 1
+                );
+                tokio::time::sleep(delay).await;
             }
             Err(e) => {
                 error!("Failed to connect to database after retries: {}", e);
@@ -93,254 +1054,4047 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
-    let state = AppState { pool };
+    let shared_store = SharedStore::connect_from_env().await?.map(Arc::new);
+    let quotas = match &shared_store {
+        Some(store) => Quotas::from_env().shared(store.clone()),
+        None => Quotas::from_env(),
+    };
+
+    let required_metadata = match std::env::var("METADATA_RULES_PATH") {
+        Ok(path) => load_metadata_rules(&path)?,
+        Err(_) => HashMap::new(),
+    };
+
+    let service_aliases = match std::env::var("SERVICE_ALIASES_PATH") {
+        Ok(path) => {
+            let aliases = ServiceAliases::load(&path, env_flag("SERVICE_ALIASES_AT_INGEST", false))?;
+            info!("Loaded {} service aliases", aliases.len());
+            aliases
+        }
+        Err(_) => ServiceAliases::default(),
+    };
+    let metadata_schema = match std::env::var("METADATA_SCHEMA_PATH") {
+        Ok(path) => {
+            let schema = MetadataSchema::load(&path, env_flag("METADATA_SCHEMA_STRICT", false))?;
+            info!("Loaded metadata type schema for {} keys", schema.len());
+            Some(Arc::new(schema))
+        }
+        Err(_) => None,
+    };
+
+    let telemetry = Arc::new(Telemetry::new());
+    let bus = BusPublisher::from_env(telemetry.clone()).map(Arc::new);
+    let batcher = InsertBatcher::from_env(pool.clone()).map(Arc::new);
+    let retry_queue = RetryQueue::from_env().map(Arc::new);
+
+    let state = AppState {
+        pool,
+        telemetry,
+        slow_insert_threshold: Duration::from_millis(env_or("SLOW_INSERT_THRESHOLD_MS", 500)),
+        max_metadata_depth: env_or("MAX_METADATA_DEPTH", 10),
+        level_aliases: Arc::new(load_level_aliases()),
+        metrics_cache: Arc::new(RwLock::new(None)),
+        metrics_query_timeout: Some(env_or("METRICS_QUERY_TIMEOUT_MS", 10_000u64))
+            .filter(|&ms| ms > 0)
+            .map(Duration::from_millis),
+        bus,
+        batcher,
+        retry_queue,
+        load_shedder: LoadShedder::from_env().map(Arc::new),
+        tail: Arc::new(TailHub::from_env()),
+        reject_duplicate_keys: env_flag("REJECT_DUPLICATE_KEYS", false),
+        quotas: Arc::new(quotas),
+        required_metadata: Arc::new(required_metadata),
+        default_metadata: Arc::new(load_default_metadata()?),
+        service_aliases: Arc::new(service_aliases),
+        metadata_schema,
+        unknown_level_fallback: load_unknown_level_fallback(),
+        service_naming: load_service_naming(),
+        delete_batch_size: env_or("DELETE_BATCH_SIZE", 10_000i64).max(1),
+        max_batch_get_ids: env_or("MAX_BATCH_GET_IDS", 100usize).max(1),
+        max_batch_size: env_or("MAX_BATCH_SIZE", 1000usize).max(1),
+        stream_batch_size: env_or("STREAM_INGEST_BATCH_SIZE", 500usize).max(1),
+        signer: LogSigner::from_env().map(Arc::new),
+        stream_flush_interval: Duration::from_millis(env_or("STREAM_INGEST_FLUSH_MS", 1000).max(1)),
+        error_levels: Arc::new(load_error_levels()),
+        group_by_keys: Arc::new(std::env::var("GROUP_BY_KEYS").ok().and_then(|v| comma_list(&v)).unwrap_or_default()),
+        queryable_metadata_keys: Arc::new(QueryableKeys::from_env()),
+        purge_token: std::env::var("PURGE_TOKEN").ok().filter(|t| !t.is_empty()),
+        admin_token: std::env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+        health_secret: std::env::var("HEALTH_SECRET").ok().filter(|t| !t.is_empty()),
+        retention: Arc::new(RetentionPolicy::from_env()),
+        max_table_bytes: std::env::var("MAX_TABLE_BYTES").ok().and_then(|v| v.parse().ok()),
+        table_bytes: Arc::new(AtomicU64::new(0)),
+        maintenance: Arc::new(AtomicBool::new(env_flag("MAINTENANCE_MODE", false))),
+        expensive_reads: Arc::new(Semaphore::new(env_or("MAX_EXPENSIVE_QUERIES", 4usize).max(1))),
+        expensive_read_wait: Duration::from_millis(env_or("EXPENSIVE_QUERY_WAIT_MS", 2000)),
+        exports: Arc::new(Semaphore::new(env_or("MAX_CONCURRENT_EXPORTS", 2usize).max(1))),
+        debug_errors: env_flag("DEBUG_ERRORS", false),
+        slow_query_threshold: env_flag("SLOW_QUERY_LOG", false)
+            .then(|| Duration::from_millis(env_or("SLOW_QUERY_THRESHOLD_MS", 1000))),
+        sync_settle: Duration::from_millis(env_or("SYNC_SETTLE_MS", 5000)),
+        cursor_tokens: CursorTokens::from_env().map(Arc::new),
+        max_result_window: Some(env_or("MAX_RESULT_WINDOW", 10_000i64)).filter(|&w| w > 0),
+        log_query_cache: QueryCache::from_env().map(Arc::new),
+        error_monitor: ErrorMonitor::from_env().map(Arc::new),
+        breaker: CircuitBreaker::from_env().map(Arc::new),
+    };
+
+    if state.maintenance.load(Ordering::Relaxed) {
+        warn!("Starting in maintenance mode: writes are rejected until it is turned off");
+    }
+    if let (Some(shedder), Some(store)) = (&state.load_shedder, shared_store) {
+        tokio::spawn(shedding::share(shedder.clone(), store));
+    }
+    if let Some(compression) = MessageCompression::from_env() {
+        compression.apply(&state.pool).await;
+    }
+    tokio::spawn(compression::restore_legacy(state.pool.clone()));
+    if let Some(manager) = PartitionManager::from_env(state.retention.longest()) {
+        tokio::spawn(partitions::run(state.pool.clone(), manager));
+    }
+    {
+        let (keys, pool) = (state.queryable_metadata_keys.clone(), state.pool.clone());
+        tokio::spawn(async move { keys.ensure_indexes(pool).await });
+    }
+    if env_flag("RETENTION_SWEEP", false) {
+        let interval = Duration::from_secs(env_or("RETENTION_SWEEP_SECS", 3600).max(1));
+        tokio::spawn(retention::run(state.clone(), interval));
+    }
+    if state.retry_queue.is_some() {
+        tokio::spawn(retry_queue::run(state.clone()));
+    }
+    if let Some(archiver) = Archiver::from_env()? {
+        tokio::spawn(archive::run(state.clone(), archiver));
+    }
+    if let Some(alerts) = SilenceAlerts::from_env()? {
+        tokio::spawn(alerts::run(state.clone(), alerts));
+    }
+    if let Some(max) = state.max_table_bytes {
+        info!("Rejecting logs once the logs table reaches {} bytes", max);
+        let interval = Duration::from_secs(env_or("TABLE_SIZE_CHECK_SECS", 60).max(1));
+        tokio::spawn(refresh_table_size(state.clone(), interval));
+    }
 
     // CORS configuration
     let cors = CorsLayer::new()
         .allow_origin("http://localhost:3000".parse::<HeaderValue>()?)
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE, Method::OPTIONS])
         .allow_headers(Any);
 
     // Build router
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/health", get(health_check))
+        .route("/health/db", get(db_health))
+        .route("/health/ready", get(readiness))
+        .route("/openapi.json", get(openapi_spec))
+        .route("/maintenance", get(get_maintenance))
+        .route("/maintenance", put(set_maintenance))
         .route("/logs", post(create_log))
+        .route("/logs/text", post(create_text_log))
+        .route("/logs/batch", post(create_log_batch))
+        .route("/logs/envelope", post(create_log_envelope))
+        .route("/logs/stream-ingest", post(stream_ingest_logs))
         .route("/logs", get(get_logs))
-        .route("/metrics", get(get_metrics))
+        .route("/logs", head(head_logs))
+        .route("/logs", delete(delete_logs))
+        .route("/logs/count", get(get_log_count))
+        .route("/logs/reclassify", post(reclassify_logs))
+        .route("/logs/tag", post(tag_logs))
+        .route("/logs/reclassify/{operation_id}/rollback", post(rollback_reclassify))
+        .route("/logs/purge-all", post(purge_all_logs))
+        .route("/logs/replay", get(replay_logs))
+        .route("/logs/sync", get(sync_logs))
+        .route("/logs/export", get(export_logs))
+        .route("/logs/tail", get(tail_logs))
+        .route("/logs/summary", get(get_log_summary))
+        .route("/logs/context/{id}", get(get_log_context))
+        .route("/logs/{id}/verify", get(verify_log))
+        .route("/logs/{id}/replay", post(replay_log))
+        .route("/logs/batch-get", get(batch_get_logs))
+        .route("/logs/anomalies", get(get_anomalies))
+        .route("/logs/distinct", get(get_distinct_values))
+        .route("/logs/incidents", get(get_incidents))
+        .route("/queries", post(save_query))
+        .route("/queries", get(list_saved_queries))
+        .route("/queries/{name}", delete(delete_saved_query))
+        .route("/queries/{name}/logs", get(get_saved_query_logs));
+
+    // Metrics routes are left out entirely (404) for ingestion-only deployments
+    if env_flag("ENABLE_METRICS", true) {
+        app = app
+            .route("/metrics", get(get_metrics))
+            .route("/metrics/prometheus", get(get_prometheus_metrics));
+
+        app = app
+            .route("/metrics/history", get(get_metrics_history))
+            .route("/metrics/delta", get(get_metrics_delta))
+            .route("/metrics/lag", get(get_metrics_lag))
+            .route("/metrics/hourly-distribution", get(get_hourly_distribution))
+            .route("/metrics/group-by", get(get_group_by))
+            .route("/metrics/metadata/{key}/cardinality", get(get_metadata_cardinality));
+
+        let refresh_interval = Duration::from_secs(env_or("METRICS_REFRESH_SECS", 30).max(1));
+        tokio::spawn(refresh_metrics_cache(state.clone(), refresh_interval));
+        let history_interval = Duration::from_secs(env_or("METRICS_HISTORY_INTERVAL_SECS", 3600).max(1));
+        tokio::spawn(record_metrics_history(state.clone(), history_interval));
+    } else {
+        info!("Metrics endpoints disabled via ENABLE_METRICS");
+    }
+
+    // 0 leaves requests without an X-Request-Deadline unbounded
+    let request_timeout = Some(Duration::from_millis(env_or("REQUEST_TIMEOUT_MS", 30_000))).filter(|t| !t.is_zero());
+    // Inside the deadline, so time spent queueing for a slot counts against it
+    let app = match ConcurrencyLimits::from_env() {
+        Some(limits) => app.layer(axum::middleware::from_fn_with_state(Arc::new(limits), concurrency::enforce)),
+        None => app,
+    };
+    // Outside the concurrency limits, so short-circuited requests never wait for a slot
+    let app = match &state.breaker {
+        Some(breaker) => app.layer(axum::middleware::from_fn_with_state(breaker.clone(), breaker::enforce)),
+        None => app,
+    };
+    let app = app
+        .layer(axum::middleware::from_fn_with_state(request_timeout, deadline::enforce))
         .layer(cors)
         .with_state(state);
 
     info!("🌊 TideLogs backend starting on 0.0.0.0:8080");
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
-    axum::serve(listener, app).await?;
+    // Client addresses are needed to enforce per-client tail stream limits
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
 
-async fn health_check() -> Json<serde_json::Value> {
-    Json(serde_json::json!({
-        "status": "healthy",
-        "timestamp": Utc::now()
-    }))
+/// Delay before retry number `attempt` (0-based): half of `base * 2^attempt`, capped at
+/// one minute, plus a random jitter of up to the same amount again.
+fn backoff_delay(base: Duration, attempt: u32) -> Duration {
+    let exp = base
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(Duration::from_secs(60));
+    let half = exp / 2;
+    half + half.mul_f64(rand::random::<f64>())
 }
 
-async fn create_log(
-    State(state): State<AppState>,
-    Json(log): Json<LogEntry>,
-) -> Result<Json<LogEntry>, StatusCode> {
-    // Validate input
-    if log.service.trim().is_empty() {
-        return Err(StatusCode::BAD_REQUEST);
+/// Parses `name` from the environment, falling back to `default` when unset or invalid.
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Reads a boolean flag such as `true`/`false`, `1`/`0` or `yes`/`no`.
+fn env_flag(name: &str, default: bool) -> bool {
+    match std::env::var(name).map(|v| v.trim().to_lowercase()) {
+        Ok(v) if ["1", "true", "yes", "on"].contains(&v.as_str()) => true,
+        Ok(v) if ["0", "false", "no", "off"].contains(&v.as_str()) => false,
+        _ => default,
     }
-    if log.message.trim().is_empty() {
-        return Err(StatusCode::BAD_REQUEST);
+}
+
+/// Liveness probe. With `HEALTH_SECRET` set, only requests presenting it in
+/// `X-Health-Secret` get the detailed fields; everyone else gets just the status.
+#[utoipa::path(
+    get,
+    path = "/health",
+    params(("X-Health-Secret" = Option<String>, Header, description = "Unlocks the detailed fields when `HEALTH_SECRET` is set")),
+    responses((status = 200, description = "Service is up", body = Object))
+)]
+async fn health_check(State(state): State<AppState>, headers: HeaderMap) -> Json<serde_json::Value> {
+    let detailed = match &state.health_secret {
+        Some(secret) => headers
+            .get("x-health-secret")
+            .is_some_and(|v| constant_time_eq(v.as_bytes(), secret.as_bytes())),
+        None => true,
+    };
+    if !detailed {
+        return Json(serde_json::json!({ "status": "healthy" }));
     }
-    if !["ERROR", "WARN", "INFO", "DEBUG"].contains(&log.level.as_str()) {
-        return Err(StatusCode::BAD_REQUEST);
+
+    let mut health = serde_json::json!({
+        "status": "healthy",
+        "timestamp": Utc::now(),
+        "version": env!("CARGO_PKG_VERSION"),
+        "maintenance": state.maintenance.load(Ordering::Relaxed)
+    });
+    if let Some(breaker) = &state.breaker {
+        health["circuit_breaker"] = serde_json::json!(breaker.status());
     }
+    Json(health)
+}
 
-    let metadata = log.metadata.unwrap_or(Value::Object(serde_json::Map::new()));
+#[derive(Debug, Serialize, ToSchema)]
+struct DbHealth {
+    /// Backends connected to this database, by `pg_stat_activity` state
+    connections: HashMap<String, i64>,
+    max_connections: i64,
+    /// Connections held by this instance's pool, and how many of them are idle
+    pool_size: u32,
+    pool_idle: usize,
+    /// True when connected to a standby
+    in_recovery: bool,
+    /// On a standby: seconds since the last replayed transaction
+    replica_lag_seconds: Option<f64>,
+    /// On a primary: one entry per attached standby
+    replicas: Vec<ReplicaStatus>,
+}
 
-    let row = sqlx::query(
-        r#"
-        INSERT INTO logs (service, level, message, metadata)
-        VALUES ($1, $2, $3, $4)
-        RETURNING id, timestamp, service, level, message, metadata, created_at
-        "#
+#[derive(Debug, Serialize, ToSchema)]
+struct ReplicaStatus {
+    application_name: String,
+    client_addr: Option<String>,
+    state: Option<String>,
+    replay_lag_seconds: Option<f64>,
+}
+
+/// Readiness probe: `503` while the `SELF_MONITOR` error rate is over its threshold,
+/// always ready without it.
+#[utoipa::path(
+    get,
+    path = "/health/ready",
+    responses(
+        (status = 200, description = "Ready to serve", body = Object),
+        (status = 503, description = "Too many recent database operations failed", body = Object),
     )
-        .bind(log.service.trim())
-        .bind(log.level.to_uppercase())
-        .bind(log.message.trim())
-        .bind(metadata)
-        .fetch_one(&state.pool)
-        .await
-        .map_err(|e| {
-            error!("Failed to insert log: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+)]
+async fn readiness(State(state): State<AppState>) -> Response {
+    let Some(monitor) = &state.error_monitor else {
+        return Json(serde_json::json!({ "status": "ready" })).into_response();
+    };
+    let current = monitor.current();
+    let status = if current.healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    let body = serde_json::json!({
+        "status": if current.healthy { "ready" } else { "unhealthy" },
+        "operations": current.operations,
+        "errors": current.errors,
+        "error_rate": current.error_rate,
+    });
+    (status, Json(body)).into_response()
+}
 
-    let response = LogEntry {
+/// Database-side health for correlating incidents: connection counts from
+/// `pg_stat_activity` and, when replication is set up, replica lag.
+#[utoipa::path(
+    get,
+    path = "/health/db",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Connection and replication statistics", body = DbHealth),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "`ADMIN_TOKEN` is not configured", body = ErrorBody),
+    )
+)]
+async fn db_health(State(state): State<AppState>, headers: HeaderMap) -> Result<Json<DbHealth>, ApiError> {
+    require_admin(&state, &headers)?;
+
+    let query = async {
+        let connection_rows = sqlx::query(
+            r#"
+            SELECT COALESCE(state, 'unknown') AS state, COUNT(*) AS count
+            FROM pg_stat_activity
+            WHERE datname = current_database()
+            GROUP BY 1
+            "#
+        )
+            .fetch_all(&state.pool)
+            .await?;
+        let max_connections: i64 =
+            sqlx::query_scalar("SELECT current_setting('max_connections')::bigint")
+                .fetch_one(&state.pool)
+                .await?;
+        let (in_recovery, replica_lag_seconds): (bool, Option<f64>) = sqlx::query_as(
+            r#"
+            SELECT pg_is_in_recovery(),
+                   EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp())::float8
+            "#
+        )
+            .fetch_one(&state.pool)
+            .await?;
+        let replica_rows = sqlx::query(
+            r#"
+            SELECT application_name, client_addr::text AS client_addr, state,
+                   EXTRACT(EPOCH FROM replay_lag)::float8 AS replay_lag_seconds
+            FROM pg_stat_replication
+            "#
+        )
+            .fetch_all(&state.pool)
+            .await?;
+        Ok::<_, sqlx::Error>((connection_rows, max_connections, in_recovery, replica_lag_seconds, replica_rows))
+    };
+
+    let (connection_rows, max_connections, in_recovery, replica_lag_seconds, replica_rows) =
+        query.await.map_err(|e| {
+            warn!("Failed to query database health: {}", e);
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+
+    Ok(Json(DbHealth {
+        connections: connection_rows
+            .iter()
+            .map(|row| (row.get("state"), row.get("count")))
+            .collect(),
+        max_connections,
+        pool_size: state.pool.size(),
+        pool_idle: state.pool.num_idle(),
+        in_recovery,
+        replica_lag_seconds,
+        replicas: replica_rows
+            .iter()
+            .map(|row| ReplicaStatus {
+                application_name: row.get("application_name"),
+                client_addr: row.get("client_addr"),
+                state: row.get("state"),
+                replay_lag_seconds: row.get("replay_lag_seconds"),
+            })
+            .collect(),
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/logs",
+    params(CreateLogParams),
+    request_body = LogEntry,
+    responses(
+        (status = 200, description = "The stored log", body = LogEntry),
+        (status = 202, description = "Not stored: `queued` for retry while the database is unreachable, or `dropped` by load shedding", body = QueuedResponse),
+        (status = 400, description = "Invalid log", body = ErrorBody),
+        (status = 429, description = "The service exceeded its daily quota", body = ErrorBody),
+        (status = 503, description = "Maintenance mode, or the database was unreachable and the retry queue is full", body = ErrorBody),
+        (status = 507, description = "The logs table reached MAX_TABLE_BYTES", body = ErrorBody),
+    )
+)]
+async fn create_log(
+    State(state): State<AppState>,
+    Query(params): Query<CreateLogParams>,
+    payload: Result<IngestJson<LogEntry>, ApiError>,
+) -> Result<Ingested, ApiError> {
+    let IngestJson(log) = payload.map_err(|e| rejected_body(&state, e))?;
+    store_log(&state, log, params.durability.unwrap_or_default()).await
+}
+
+/// `POST /logs/text`: the raw body is the message, service and level come from the query.
+#[utoipa::path(
+    post,
+    path = "/logs/text",
+    params(TextLogParams),
+    request_body(content = String, content_type = "text/plain"),
+    responses(
+        (status = 200, description = "The stored log", body = LogEntry),
+        (status = 202, description = "Not stored: `queued` for retry while the database is unreachable, or `dropped` by load shedding", body = QueuedResponse),
+        (status = 400, description = "Invalid log", body = ErrorBody),
+        (status = 429, description = "The service exceeded its daily quota", body = ErrorBody),
+        (status = 503, description = "Maintenance mode, or the database was unreachable and the retry queue is full", body = ErrorBody),
+        (status = 507, description = "The logs table reached MAX_TABLE_BYTES", body = ErrorBody),
+    )
+)]
+async fn create_text_log(
+    State(state): State<AppState>,
+    Query(params): Query<TextLogParams>,
+    body: Result<String, StringRejection>,
+) -> Result<Ingested, ApiError> {
+    let message = body.map_err(|e| rejected_body(&state, ApiError::new(e.status(), e.body_text())))?;
+    let log = LogEntry {
+        id: None,
+        timestamp: None,
+        service: params.service.unwrap_or_else(|| DEFAULT_TEXT_SERVICE.to_string()),
+        level: params.level.unwrap_or_else(|| DEFAULT_TEXT_LEVEL.to_string()),
+        message,
+        metadata: None,
+        created_at: None,
+        seq: None,
+        match_snippet: None,
+    };
+    store_log(&state, log, Durability::Sync).await
+}
+
+/// `POST /logs/batch`: an array of logs stored atomically and in order. Either every log
+/// is stored or none is, and their timestamps strictly increase in array order, so any
+/// read ordered by time returns them as sent.
+#[utoipa::path(
+    post,
+    path = "/logs/batch",
+    request_body = [LogEntry],
+    responses(
+        (status = 200, description = "The stored logs, in request order", body = [LogEntry]),
+        (status = 400, description = "Empty or oversized batch, or invalid logs (fields are prefixed with their index, e.g. `[2].level`)", body = ErrorBody),
+        (status = 429, description = "A service exceeded its daily quota", body = ErrorBody),
+        (status = 503, description = "Maintenance mode is on", body = ErrorBody),
+        (status = 507, description = "The logs table reached MAX_TABLE_BYTES", body = ErrorBody),
+    )
+)]
+async fn create_log_batch(
+    State(state): State<AppState>,
+    payload: Result<IngestJson<Vec<LogEntry>>, ApiError>,
+) -> Result<Json<Vec<LogEntry>>, ApiError> {
+    let IngestJson(logs) = payload.map_err(|e| rejected_body(&state, e))?;
+    store_batch(&state, logs, "").await.map(Json)
+}
+
+/// `POST /logs/envelope`: several events of one service under a shared envelope, stored
+/// like a batch. Each event's metadata is merged over `common_metadata`, so an event's own
+/// keys win.
+#[utoipa::path(
+    post,
+    path = "/logs/envelope",
+    request_body = LogEnvelope,
+    responses(
+        (status = 200, description = "The stored logs, in event order", body = [LogEntry]),
+        (status = 400, description = "No or too many events, or invalid events (fields are prefixed with their index, e.g. `events[2].level`)", body = ErrorBody),
+        (status = 429, description = "The service exceeded its daily quota", body = ErrorBody),
+        (status = 503, description = "Maintenance mode is on", body = ErrorBody),
+        (status = 507, description = "The logs table reached MAX_TABLE_BYTES", body = ErrorBody),
+    )
+)]
+async fn create_log_envelope(
+    State(state): State<AppState>,
+    payload: Result<IngestJson<LogEnvelope>, ApiError>,
+) -> Result<Json<Vec<LogEntry>>, ApiError> {
+    let IngestJson(envelope) = payload.map_err(|e| rejected_body(&state, e))?;
+    let common = match envelope.common_metadata {
+        None | Some(Value::Null) => serde_json::Map::new(),
+        Some(Value::Object(common)) => common,
+        Some(_) => {
+            state.telemetry.rejected.inc("malformed");
+            return Err(ApiError::validation(vec![FieldError::new(
+                "common_metadata",
+                "common_metadata must be an object",
+            )]));
+        }
+    };
+    let logs = envelope
+        .events
+        .into_iter()
+        .map(|event| {
+            let metadata = match event.metadata {
+                None | Some(Value::Null) if common.is_empty() => None,
+                None | Some(Value::Null) => Some(Value::Object(common.clone())),
+                Some(Value::Object(own)) => {
+                    let mut merged = common.clone();
+                    merged.extend(own);
+                    Some(Value::Object(merged))
+                }
+                // Nothing to merge into; stored as sent, like on the other routes
+                Some(other) => Some(other),
+            };
+            LogEntry {
+                id: None,
+                timestamp: None,
+                service: envelope.service.clone(),
+                level: event.level,
+                message: event.message,
+                metadata,
+                created_at: None,
+                seq: None,
+                match_snippet: None,
+            }
+        })
+        .collect();
+    store_batch(&state, logs, "events").await.map(Json)
+}
+
+/// Validates and stores `logs` all-or-nothing, in order. Problems name the position of
+/// their log under `field`, e.g. `events[2].level`.
+async fn store_batch(state: &AppState, logs: Vec<LogEntry>, field: &str) -> Result<Vec<LogEntry>, ApiError> {
+    ensure_writable(state)?;
+    let what = if field.is_empty() { "batch" } else { field };
+    if logs.is_empty() {
+        return Err(ApiError::bad_request(format!("{} must contain at least one log", what)));
+    }
+    if logs.len() > state.max_batch_size {
+        return Err(ApiError::bad_request(format!(
+            "{} has {} logs, more than the maximum of {}",
+            what,
+            logs.len(),
+            state.max_batch_size
+        )));
+    }
+
+    let mut rows = Vec::with_capacity(logs.len());
+    let mut problems = Vec::new();
+    for (index, log) in logs.into_iter().enumerate() {
+        match validate_log(state, log) {
+            Ok(row) => rows.push(row),
+            Err(found) => problems.extend(found.into_iter().map(|(reason, error)| {
+                let position = format!("{}[{}]", field, index);
+                let name = format!("{}.{}", position, error.field);
+                (reason, FieldError::new(name, format!("{}: {}", position, error.message)))
+            })),
+        }
+    }
+    if !problems.is_empty() {
+        return Err(reject(state, problems));
+    }
+    rows.retain(|row| !shed(state, &row.level));
+    if rows.is_empty() {
+        return Ok(Vec::new());
+    }
+    check_storage(state)?;
+    let reservations = consume_quotas(state, &rows).await?;
+
+    let result = insert_ordered(&state.pool, &rows).await;
+    record_db_outcome(state, db_outcome(&result));
+    let stored = match result {
+        Ok(stored) => stored,
+        Err(e) => {
+            error!("Failed to insert batch of {} logs: {}", rows.len(), e);
+            refund_quotas(state, reservations).await;
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+        }
+    };
+    for log in &stored {
+        announce(state, log);
+    }
+
+    info!("Created batch of {} log entries", stored.len());
+    Ok(stored)
+}
+
+/// Longest NDJSON line `POST /logs/stream-ingest` accepts, matching the body limit of
+/// the JSON routes.
+const MAX_STREAM_LINE_BYTES: usize = 2 * 1024 * 1024;
+
+/// Errors listed at most in a stream-ingest summary; `rejected` still counts them all.
+const MAX_STREAM_ERRORS: usize = 100;
+
+#[derive(Debug, Default, Serialize, ToSchema)]
+struct StreamIngestSummary {
+    /// Non-empty lines read
+    received: u64,
+    stored: u64,
+    rejected: u64,
+    /// Valid lines dropped by load shedding
+    shed: u64,
+    /// Why lines were rejected, the first 100 of them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<StreamLineError>,
+    /// Why the stream stopped early; lines after the last stored batch were not stored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct StreamLineError {
+    /// 1-based line number in the request body
+    line: u64,
+    error: String,
+}
+
+/// Reads an NDJSON body of logs as it arrives and stores it in batches of
+/// `STREAM_INGEST_BATCH_SIZE`, or whatever has arrived once `STREAM_INGEST_FLUSH_MS` has
+/// passed. Each line is validated like `POST /logs`; invalid lines are skipped and listed
+/// in the summary. No more of the body is read while a batch is being inserted, so a slow
+/// database slows the sender down through TCP backpressure.
+#[utoipa::path(
+    post,
+    path = "/logs/stream-ingest",
+    request_body(content = String, content_type = "application/x-ndjson", description = "One log object per line"),
+    responses(
+        (status = 200, description = "Summary of the stream once the body ends", body = StreamIngestSummary),
+        (status = 500, description = "A batch failed to insert; the summary tells what was stored", body = StreamIngestSummary),
+        (status = 503, description = "Maintenance mode is on; when it was turned on mid-stream, the summary tells what was stored", body = StreamIngestSummary),
+        (status = 507, description = "The logs table reached MAX_TABLE_BYTES; the summary tells what was stored", body = StreamIngestSummary),
+    )
+)]
+async fn stream_ingest_logs(State(state): State<AppState>, body: Body) -> Result<Response, ApiError> {
+    ensure_writable(&state)?;
+    let mut chunks = body.into_data_stream();
+    let mut summary = StreamIngestSummary::default();
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut line_number: u64 = 0;
+    // Bytes of an over-long line are dropped up to its newline
+    let mut discarding = false;
+    // Rows waiting for the next batch with their line numbers
+    let mut pending: Vec<(u64, NewLog)> = Vec::new();
+    let mut flush_at: Option<tokio::time::Instant> = None;
+
+    let status = 'read: loop {
+        let chunk = match flush_at {
+            Some(at) => tokio::select! {
+                chunk = chunks.next() => chunk,
+                _ = tokio::time::sleep_until(at) => {
+                    if let Err(status) = flush_stream_batch(&state, &mut pending, &mut summary).await {
+                        break 'read status;
+                    }
+                    flush_at = None;
+                    continue 'read;
+                }
+            },
+            None => chunks.next().await,
+        };
+
+        let bytes = match chunk {
+            Some(Ok(bytes)) => bytes,
+            Some(Err(e)) => {
+                // The sender went away; keep what arrived intact
+                warn!("Stream ingest body ended early: {}", e);
+                summary.error = Some("request body ended unexpectedly".to_string());
+                break StatusCode::OK;
+            }
+            None => {
+                if !buffer.is_empty() && !discarding {
+                    line_number += 1;
+                    let line = std::mem::take(&mut buffer);
+                    accept_stream_line(&state, &line, line_number, &mut summary, &mut pending);
+                }
+                break StatusCode::OK;
+            }
+        };
+
+        let mut rest = &bytes[..];
+        while let Some(pos) = rest.iter().position(|b| *b == b'\n') {
+            line_number += 1;
+            if discarding {
+                discarding = false;
+            } else {
+                buffer.extend_from_slice(&rest[..pos]);
+                let line = std::mem::take(&mut buffer);
+                accept_stream_line(&state, &line, line_number, &mut summary, &mut pending);
+            }
+            rest = &rest[pos + 1..];
+            if pending.len() >= state.stream_batch_size {
+                if let Err(status) = flush_stream_batch(&state, &mut pending, &mut summary).await {
+                    break 'read status;
+                }
+                flush_at = None;
+            }
+        }
+        if !discarding {
+            buffer.extend_from_slice(rest);
+            if buffer.len() > MAX_STREAM_LINE_BYTES {
+                buffer.clear();
+                discarding = true;
+                state.telemetry.rejected.inc("too_large");
+                summary.received += 1;
+                let message = format!("line is longer than {} bytes", MAX_STREAM_LINE_BYTES);
+                stream_line_rejected(&mut summary, line_number + 1, message);
+            }
+        }
+
+        if !pending.is_empty() && flush_at.is_none() {
+            flush_at = Some(tokio::time::Instant::now() + state.stream_flush_interval);
+        }
+    };
+
+    // Whatever is still pending once the body ends is stored before answering
+    let status = match status {
+        StatusCode::OK => flush_stream_batch(&state, &mut pending, &mut summary).await.err().unwrap_or(status),
+        failed => failed,
+    };
+    info!(
+        "Stream ingest finished: {} lines, {} stored, {} rejected, {} shed",
+        summary.received, summary.stored, summary.rejected, summary.shed
+    );
+    Ok((status, Json(summary)).into_response())
+}
+
+/// Parses and validates one NDJSON line, queueing the resulting row for the next batch.
+fn accept_stream_line(
+    state: &AppState,
+    line: &[u8],
+    line_number: u64,
+    summary: &mut StreamIngestSummary,
+    pending: &mut Vec<(u64, NewLog)>,
+) {
+    if line.iter().all(u8::is_ascii_whitespace) {
+        return;
+    }
+    summary.received += 1;
+    if state.reject_duplicate_keys {
+        if let Err(e) = strict_json::check_duplicate_keys(line) {
+            state.telemetry.rejected.inc("malformed");
+            return stream_line_rejected(summary, line_number, e.message);
+        }
+    }
+    let log: LogEntry = match serde_json::from_slice(line) {
+        Ok(log) => log,
+        Err(e) => {
+            state.telemetry.rejected.inc("malformed");
+            return stream_line_rejected(summary, line_number, e.to_string());
+        }
+    };
+    let row = match validate_log(state, log) {
+        Ok(row) => row,
+        Err(problems) => return stream_line_rejected(summary, line_number, reject(state, problems).message),
+    };
+    if shed(state, &row.level) {
+        summary.shed += 1;
+        return;
+    }
+    pending.push((line_number, row));
+}
+
+fn stream_line_rejected(summary: &mut StreamIngestSummary, line: u64, error: String) {
+    summary.rejected += 1;
+    if summary.errors.len() < MAX_STREAM_ERRORS {
+        summary.errors.push(StreamLineError { line, error });
+    }
+}
+
+/// Stores the pending rows in their arrival order. Rows of a service beyond what is left
+/// of its daily quota are rejected. On failure the summary's `error` is set and the status
+/// the stream should end with is returned.
+async fn flush_stream_batch(
+    state: &AppState,
+    pending: &mut Vec<(u64, NewLog)>,
+    summary: &mut StreamIngestSummary,
+) -> Result<(), StatusCode> {
+    if pending.is_empty() {
+        return Ok(());
+    }
+    // Maintenance may have been turned on since the stream started
+    if let Err(e) = ensure_writable(state).and_then(|()| check_storage(state)) {
+        summary.error = Some(e.message);
+        return Err(e.status);
+    }
+    let (rows, reservations) = consume_stream_quotas(state, std::mem::take(pending), summary).await;
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let result = insert_ordered(&state.pool, &rows).await;
+    record_db_outcome(state, db_outcome(&result));
+    match result {
+        Ok(stored) => {
+            for log in &stored {
+                announce(state, log);
+            }
+            summary.stored += stored.len() as u64;
+            Ok(())
+        }
+        Err(e) => {
+            error!("Failed to insert stream batch of {} logs: {}", rows.len(), e);
+            refund_quotas(state, reservations).await;
+            summary.error = Some("failed to store logs".to_string());
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Reserves quota for a stream batch one service at a time, keeping each service's rows in
+/// order while its quota lasts and rejecting the rest.
+async fn consume_stream_quotas(
+    state: &AppState,
+    pending: Vec<(u64, NewLog)>,
+    summary: &mut StreamIngestSummary,
+) -> (Vec<NewLog>, Vec<Reservation>) {
+    let mut per_service: BTreeMap<String, u64> = BTreeMap::new();
+    for (_, row) in &pending {
+        *per_service.entry(row.service.clone()).or_default() += 1;
+    }
+    let mut left = HashMap::with_capacity(per_service.len());
+    let mut reservations = Vec::with_capacity(per_service.len());
+    for (service, count) in per_service {
+        let mut reservation = state.quotas.reserve(&service, count).await;
+        let message = reservation.exceeded.take().map(|exceeded| quota_message(&service, 1, &exceeded));
+        left.insert(service, (reservation.granted, message));
+        reservations.push(reservation);
+    }
+
+    let mut rows = Vec::with_capacity(pending.len());
+    for (line, row) in pending {
+        let (granted, message) = left.get_mut(&row.service).expect("every pending service was reserved");
+        if *granted > 0 {
+            *granted -= 1;
+            rows.push(row);
+        } else {
+            state.telemetry.rejected.inc("over_quota");
+            stream_line_rejected(summary, line, message.clone().unwrap_or_default());
+        }
+    }
+    (rows, reservations)
+}
+
+/// Counts a request body that could not be read or parsed before passing its error on.
+fn rejected_body(state: &AppState, error: ApiError) -> ApiError {
+    let reason = if error.status == StatusCode::PAYLOAD_TOO_LARGE { "too_large" } else { "malformed" };
+    state.telemetry.rejected.inc(reason);
+    error
+}
+
+/// What became of an accepted log.
+enum Ingested {
+    Stored(LogEntry),
+    /// The database was unreachable; the log waits in the retry queue.
+    Queued,
+    /// Dropped by load shedding during an ingest storm.
+    Shed,
+}
+
+/// Body of a `202 Accepted` answer for a log that was not stored: `queued` when it waits
+/// in the retry queue, `dropped` when load shedding discarded it.
+#[derive(Serialize, ToSchema)]
+struct QueuedResponse {
+    status: &'static str,
+}
+
+impl IntoResponse for Ingested {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Stored(log) => Json(log).into_response(),
+            Self::Queued => (StatusCode::ACCEPTED, Json(QueuedResponse { status: "queued" })).into_response(),
+            Self::Shed => (StatusCode::ACCEPTED, Json(QueuedResponse { status: "dropped" })).into_response(),
+        }
+    }
+}
+
+/// Counts an arriving log towards the load shedder's rate, returning whether it should be
+/// dropped.
+fn shed(state: &AppState, level: &str) -> bool {
+    let shed = state.load_shedder.as_ref().is_some_and(|shedder| shedder.should_shed(level));
+    if shed {
+        state.telemetry.shed.inc(level);
+    }
+    shed
+}
+
+/// Validates and inserts one log, publishing it to the bus once stored. Shared by the
+/// single-log ingestion routes so they all apply the same rules.
+async fn store_log(state: &AppState, log: LogEntry, durability: Durability) -> Result<Ingested, ApiError> {
+    ensure_writable(state)?;
+    let new_log = validate_log(state, log).map_err(|problems| reject(state, problems))?;
+    if shed(state, &new_log.level) {
+        return Ok(Ingested::Shed);
+    }
+    check_storage(state)?;
+    let reservation = consume_quota(state, &new_log.service, 1).await?;
+
+    let started = Instant::now();
+    // Async-commit logs get a transaction of their own rather than joining a batch,
+    // whose commit must stay synchronous for everyone else in it
+    let result = match (&state.batcher, durability) {
+        (Some(batcher), Durability::Sync) => batcher.insert(new_log.clone()).await,
+        (_, durability) => {
+            let inserted = match durability {
+                Durability::Sync => insert_log(&state.pool, &new_log).await,
+                Durability::Async => insert_log_async_commit(&state.pool, &new_log).await,
+            };
+            inserted.map_err(|e| {
+                error!("Failed to insert log: {}", e);
+                InsertFailure::from(&e)
+            })
+        }
+    };
+    record_db_outcome(
+        state,
+        match &result {
+            Ok(_) => Some(true),
+            Err(failure) => failure.transient.then_some(false),
+        },
+    );
+    let response = match result {
+        Ok(response) => response,
+        Err(failure) => {
+            let Some(queue) = state.retry_queue.as_ref().filter(|_| failure.transient) else {
+                state.quotas.refund(reservation).await;
+                return Err(StatusCode::INTERNAL_SERVER_ERROR.into());
+            };
+            let service = new_log.service.clone();
+            if !queue.park(new_log) {
+                warn!("Insert retry queue is full; rejecting log for {}", service);
+                state.quotas.refund(reservation).await;
+                return Err(StatusCode::SERVICE_UNAVAILABLE.into());
+            }
+            warn!("Queued log for {} until the database is reachable", service);
+            return Ok(Ingested::Queued);
+        }
+    };
+
+    let elapsed = started.elapsed();
+    state.telemetry.insert_duration.observe(elapsed);
+    if elapsed > state.slow_insert_threshold {
+        warn!("Slow log insert: took {:?} (threshold {:?})", elapsed, state.slow_insert_threshold);
+    }
+
+    announce(state, &response);
+
+    info!("Created log entry: {} - {} - {}", response.service, response.level, response.message);
+    Ok(Ingested::Stored(response))
+}
+
+/// A broken ingestion rule, with the `tidelogs_rejected_total` reason it counts under.
+type Problem = (&'static str, FieldError);
+
+/// Applies every ingestion rule to `log` and turns it into the row to insert. All
+/// problems are collected, so a client sees them at once.
+fn validate_log(state: &AppState, mut log: LogEntry) -> Result<NewLog, Vec<Problem>> {
+    if state.service_naming != ServiceNaming::None {
+        // Before aliasing, so the alias file only needs to list normalized names
+        log.service = state.service_naming.apply(&log.service);
+    }
+    if state.service_aliases.at_ingest() {
+        // Before validation, so quotas and metadata rules see the canonical name
+        let service = state.service_aliases.canonical(log.service.trim()).to_string();
+        log.service = service;
+    }
+
+    let mut problems: Vec<Problem> = Vec::new();
+
+    if log.service.trim().is_empty() {
+        problems.push(("empty_service", FieldError::new("service", "service must not be empty")));
+    }
+    let mut level = normalize_level(&log.level, &state.level_aliases);
+    if !LEVELS.contains(&level.as_str()) {
+        match &state.unknown_level_fallback {
+            Some(fallback) => {
+                // Keep the log, recording what the client actually sent; non-object
+                // metadata has nowhere to put it
+                let metadata = log.metadata.get_or_insert_with(|| Value::Object(serde_json::Map::new()));
+                if let Value::Object(map) = metadata {
+                    map.insert("original_level".to_string(), Value::String(log.level.clone()));
+                }
+                level = fallback.clone();
+            }
+            None => problems.push((
+                "bad_level",
+                FieldError::new("level", format!("unknown level '{}'", log.level)),
+            )),
+        }
+    }
+    // METRIC events carry their payload in metadata, so they may omit the message
+    let metric_event = level == "METRIC" && has_metadata(&log.metadata);
+    if log.message.trim().is_empty() && !metric_event {
+        problems.push(("empty_message", FieldError::new("message", "message must not be empty")));
+    }
+    if !state.default_metadata.is_empty() {
+        // Before the metadata rules, so defaults can satisfy required keys; metadata that
+        // is not an object has no keys to merge into
+        let metadata = log.metadata.get_or_insert_with(|| Value::Object(serde_json::Map::new()));
+        if let Value::Object(map) = metadata {
+            for (key, value) in state.default_metadata.iter() {
+                map.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        }
+    }
+    if let Some(metadata) = &log.metadata {
+        if exceeds_depth(metadata, state.max_metadata_depth) {
+            problems.push((
+                "too_large",
+                FieldError::new(
+                    "metadata",
+                    format!("metadata exceeds the maximum nesting depth of {}", state.max_metadata_depth),
+                ),
+            ));
+        }
+    }
+
+    if let (Some(schema), Some(metadata)) = (&state.metadata_schema, &mut log.metadata) {
+        for (key, expected) in schema.apply(metadata) {
+            problems.push((
+                "bad_metadata_type",
+                FieldError::new(
+                    format!("metadata.{}", key),
+                    format!("metadata key '{}' must be of type {}", key, expected),
+                ),
+            ));
+        }
+    }
+
+    if let Some(required) = state.required_metadata.get(log.service.trim()) {
+        let missing: Vec<&str> = required
+            .iter()
+            .filter(|key| !matches!(&log.metadata, Some(Value::Object(map)) if map.contains_key(*key)))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            problems.push((
+                "missing_metadata",
+                FieldError::new(
+                    "metadata",
+                    format!(
+                        "metadata for service '{}' is missing required keys: {}",
+                        log.service.trim(),
+                        missing.join(", ")
+                    ),
+                ),
+            ));
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    let service = log.service.trim().to_string();
+    let message = log.message.trim().to_string();
+    let (timestamp, signature) = match &state.signer {
+        Some(signer) => {
+            let timestamp = signer.timestamp();
+            (Some(timestamp), Some(signer.sign(&service, &level, &message, timestamp)))
+        }
+        None => (None, None),
+    };
+    Ok(NewLog {
+        service,
+        level,
+        message,
+        metadata: log.metadata.unwrap_or(Value::Object(serde_json::Map::new())),
+        timestamp,
+        signature,
+    })
+}
+
+/// Counts each problem as a rejection and answers with all of them.
+fn reject(state: &AppState, problems: Vec<Problem>) -> ApiError {
+    let errors = problems
+        .into_iter()
+        .map(|(reason, error)| {
+            state.telemetry.rejected.inc(reason);
+            error
+        })
+        .collect();
+    ApiError::validation(errors)
+}
+
+/// Fails with `507` once the logs table has reached `MAX_TABLE_BYTES`.
+fn check_storage(state: &AppState) -> Result<(), ApiError> {
+    if let Some(max) = state.max_table_bytes {
+        let size = state.table_bytes.load(Ordering::Relaxed);
+        if size >= max {
+            state.telemetry.rejected.inc("storage_full");
+            return Err(ApiError::new(
+                StatusCode::INSUFFICIENT_STORAGE,
+                format!(
+                    "log storage is full ({} of {} bytes used); ingestion resumes once old logs are removed",
+                    size, max
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Counts `count` logs of `service` against its daily quota, all or nothing.
+async fn consume_quota(state: &AppState, service: &str, count: u64) -> Result<Reservation, ApiError> {
+    let mut reservation = state.quotas.reserve(service, count).await;
+    let Some(exceeded) = reservation.exceeded.take() else {
+        return Ok(reservation);
+    };
+    state.quotas.refund(reservation).await;
+    state.telemetry.rejected.inc("over_quota");
+    Err(ApiError::new(StatusCode::TOO_MANY_REQUESTS, quota_message(service, count, &exceeded)))
+}
+
+fn quota_message(service: &str, count: u64, exceeded: &QuotaExceeded) -> String {
+    let what = match count {
+        1 => "its daily quota".to_string(),
+        count => format!("what is left of its daily quota with {} logs", count),
+    };
+    format!(
+        "service '{}' exceeded {} of {} logs; it resets at {}",
+        service,
+        what,
+        exceeded.limit,
+        exceeded.resets_at.to_rfc3339()
+    )
+}
+
+/// Counts `rows` against their services' daily quotas, all or nothing.
+async fn consume_quotas(state: &AppState, rows: &[NewLog]) -> Result<Vec<Reservation>, ApiError> {
+    let mut per_service: BTreeMap<&str, u64> = BTreeMap::new();
+    for row in rows {
+        *per_service.entry(&row.service).or_default() += 1;
+    }
+    let mut reservations = Vec::with_capacity(per_service.len());
+    for (service, count) in per_service {
+        match consume_quota(state, service, count).await {
+            Ok(reservation) => reservations.push(reservation),
+            Err(e) => {
+                refund_quotas(state, reservations).await;
+                return Err(e);
+            }
+        }
+    }
+    Ok(reservations)
+}
+
+async fn refund_quotas(state: &AppState, reservations: Vec<Reservation>) {
+    for reservation in reservations {
+        state.quotas.refund(reservation).await;
+    }
+}
+
+async fn insert_log(pool: &PgPool, log: &NewLog) -> Result<LogEntry, sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO logs (service, level, message, metadata, timestamp, signature)
+        VALUES ($1, $2, $3, $4, COALESCE($5, NOW()), $6)
+        RETURNING id, timestamp, service, level, message, metadata, created_at, seq
+        "#
+    )
+        .bind(&log.service)
+        .bind(&log.level)
+        .bind(&log.message)
+        .bind(&log.metadata)
+        .bind(log.timestamp)
+        .bind(&log.signature)
+        .fetch_one(pool)
+        .await
+        .map(|row| log_from_row(&row))
+}
+
+/// Inserts `log` in a transaction committed with `synchronous_commit = off`, which returns
+/// without waiting for the WAL flush.
+async fn insert_log_async_commit(pool: &PgPool, log: &NewLog) -> Result<LogEntry, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("SET LOCAL synchronous_commit = off").execute(&mut *tx).await?;
+    let row = sqlx::query(
+        r#"
+        INSERT INTO logs (service, level, message, metadata, timestamp, signature)
+        VALUES ($1, $2, $3, $4, COALESCE($5, NOW()), $6)
+        RETURNING id, timestamp, service, level, message, metadata, created_at, seq
+        "#
+    )
+        .bind(&log.service)
+        .bind(&log.level)
+        .bind(&log.message)
+        .bind(&log.metadata)
+        .bind(log.timestamp)
+        .bind(&log.signature)
+        .fetch_one(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(log_from_row(&row))
+}
+
+/// Inserts `logs` in one statement, so the batch is all-or-nothing. Every row shares the
+/// statement's `NOW()`, offset by one microsecond per position, which makes timestamps
+/// (and `created_at`) strictly increase in array order. Signed logs keep the timestamp
+/// they were signed with, which already increases.
+async fn insert_ordered(pool: &PgPool, logs: &[NewLog]) -> Result<Vec<LogEntry>, sqlx::Error> {
+    let services: Vec<&str> = logs.iter().map(|l| l.service.as_str()).collect();
+    let levels: Vec<&str> = logs.iter().map(|l| l.level.as_str()).collect();
+    let messages: Vec<&str> = logs.iter().map(|l| l.message.as_str()).collect();
+    let metadata: Vec<&Value> = logs.iter().map(|l| &l.metadata).collect();
+    let timestamps: Vec<Option<DateTime<Utc>>> = logs.iter().map(|l| l.timestamp).collect();
+    let signatures: Vec<Option<&str>> = logs.iter().map(|l| l.signature.as_deref()).collect();
+
+    let rows = sqlx::query(
+        r#"
+        INSERT INTO logs (service, level, message, metadata, timestamp, created_at, signature)
+        SELECT service, level, message, metadata,
+               COALESCE(timestamp, NOW() + (position - 1) * INTERVAL '1 microsecond'),
+               NOW() + (position - 1) * INTERVAL '1 microsecond',
+               signature
+        FROM UNNEST($1::text[], $2::text[], $3::text[], $4::jsonb[], $5::timestamptz[], $6::text[])
+            WITH ORDINALITY AS batch(service, level, message, metadata, timestamp, signature, position)
+        ORDER BY position
+        RETURNING id, timestamp, service, level, message, metadata, created_at, seq
+        "#
+    )
+        .bind(&services)
+        .bind(&levels)
+        .bind(&messages)
+        .bind(&metadata)
+        .bind(&timestamps)
+        .bind(&signatures)
+        .fetch_all(pool)
+        .await?;
+
+    // RETURNING has no guaranteed order; the timestamps restore it
+    let mut stored: Vec<LogEntry> = rows.iter().map(log_from_row).collect();
+    stored.sort_by_key(|log| log.timestamp);
+    Ok(stored)
+}
+
+/// Hands a newly stored log to the event bus and any open tail streams.
+fn announce(state: &AppState, log: &LogEntry) {
+    if let Some(bus) = &state.bus {
+        bus.publish(log);
+    }
+    state.tail.publish(log);
+}
+
+fn log_from_row(row: &PgRow) -> LogEntry {
+    LogEntry {
         id: Some(row.get("id")),
         timestamp: Some(row.get("timestamp")),
         service: row.get("service"),
         level: row.get("level"),
-        message: row.get("message"),
+        message: message_from_row(row),
         metadata: Some(row.get("metadata")),
         created_at: Some(row.get("created_at")),
+        seq: row.try_get("seq").ok(),
+        match_snippet: None,
+    }
+}
+
+/// The full message of a row, decompressed when it was stored compressed. Rows read
+/// without `message_gzip` get the `message` column as it is.
+fn message_from_row(row: &PgRow) -> String {
+    match row.try_get::<Option<Vec<u8>>, _>("message_gzip") {
+        Ok(Some(compressed)) => compression::decompress(&compressed).unwrap_or_else(|e| {
+            warn!("Failed to decompress stored message, returning its beginning: {}", e);
+            row.get("message")
+        }),
+        _ => row.get("message"),
+    }
+}
+
+/// Levels counted as errors for `error_rate`, from the comma-separated `ERROR_LEVELS`
+/// (default just `ERROR`).
+fn load_error_levels() -> Vec<String> {
+    std::env::var("ERROR_LEVELS")
+        .ok()
+        .and_then(|v| comma_list(&v))
+        .map(|levels| levels.iter().map(|l| l.to_uppercase()).collect())
+        .unwrap_or_else(|| vec!["ERROR".to_string()])
+}
+
+/// Reads the per-service required metadata keys, a JSON object such as
+/// `{"billing": ["env", "version"]}`.
+fn load_metadata_rules(path: &str) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("failed to read METADATA_RULES_PATH '{}': {}", path, e))?;
+    let rules: HashMap<String, Vec<String>> = serde_json::from_str(&contents)
+        .map_err(|e| anyhow::anyhow!("invalid metadata rules in '{}': {}", path, e))?;
+    info!("Loaded required metadata rules for {} services", rules.len());
+    Ok(rules)
+}
+
+/// Reads `DEFAULT_METADATA`, a JSON object such as `{"env": "prod", "region": "eu-west-1"}`.
+fn load_default_metadata() -> anyhow::Result<serde_json::Map<String, Value>> {
+    let Some(spec) = std::env::var("DEFAULT_METADATA").ok().filter(|v| !v.trim().is_empty()) else {
+        return Ok(serde_json::Map::new());
+    };
+    let defaults: serde_json::Map<String, Value> = serde_json::from_str(&spec)
+        .map_err(|e| anyhow::anyhow!("DEFAULT_METADATA must be a JSON object: {}", e))?;
+    info!("Adding default metadata keys to every log: {}", defaults.keys().cloned().collect::<Vec<_>>().join(", "));
+    Ok(defaults)
+}
+
+/// Reads `UNKNOWN_LEVEL_FALLBACK`, ignoring it unless it names one of `LEVELS`.
+fn load_unknown_level_fallback() -> Option<String> {
+    let fallback = std::env::var("UNKNOWN_LEVEL_FALLBACK").ok()?.trim().to_uppercase();
+    if fallback.is_empty() {
+        return None;
+    }
+    if !LEVELS.contains(&fallback.as_str()) {
+        warn!("Ignoring UNKNOWN_LEVEL_FALLBACK '{}': not a valid level", fallback);
+        return None;
+    }
+    info!("Unknown levels will be stored as {}", fallback);
+    Some(fallback)
+}
+
+/// How service names are rewritten before new logs are stored, set by
+/// `SERVICE_NAME_NORMALIZATION`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ServiceNaming {
+    /// Stored as sent
+    None,
+    /// Trimmed, runs of whitespace collapsed to one space, lowercased: `Billing  API` is
+    /// stored as `billing api`
+    Lower,
+    /// Trimmed, lowercased, runs of whitespace, `_` and `-` turned into one `-`:
+    /// `Billing_API ` is stored as `billing-api`
+    Kebab,
+}
+
+impl ServiceNaming {
+    fn apply(self, service: &str) -> String {
+        match self {
+            ServiceNaming::None => service.to_string(),
+            ServiceNaming::Lower => service.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase(),
+            ServiceNaming::Kebab => service
+                .split(|c: char| c.is_whitespace() || c == '_' || c == '-')
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join("-")
+                .to_lowercase(),
+        }
+    }
+}
+
+/// Reads `SERVICE_NAME_NORMALIZATION` (`none`, `lower` or `kebab`), defaulting to `none`.
+fn load_service_naming() -> ServiceNaming {
+    let Ok(policy) = std::env::var("SERVICE_NAME_NORMALIZATION") else {
+        return ServiceNaming::None;
+    };
+    let naming = match policy.trim().to_lowercase().as_str() {
+        "" | "none" => ServiceNaming::None,
+        "lower" => ServiceNaming::Lower,
+        "kebab" => ServiceNaming::Kebab,
+        _ => {
+            warn!("Ignoring SERVICE_NAME_NORMALIZATION '{}': expected none, lower or kebab", policy);
+            return ServiceNaming::None;
+        }
+    };
+    if naming != ServiceNaming::None {
+        info!("Normalizing service names of new logs ({:?})", naming);
+    }
+    naming
+}
+
+/// Trims `level`, strips trailing punctuation (`error!`, `warn:`), uppercases it and maps
+/// known aliases (e.g. `WARNING`) onto canonical levels.
+fn normalize_level(level: &str, aliases: &HashMap<String, String>) -> String {
+    let level = level.trim().trim_end_matches(|c: char| c.is_ascii_punctuation()).trim_end().to_uppercase();
+    aliases.get(&level).cloned().unwrap_or(level)
+}
+
+/// Builds the alias map from the defaults plus `LEVEL_ALIASES`, a comma-separated
+/// list of `ALIAS=LEVEL` pairs such as `SEVERE=ERROR,TRACE=DEBUG`.
+fn load_level_aliases() -> HashMap<String, String> {
+    let mut aliases: HashMap<String, String> = DEFAULT_LEVEL_ALIASES
+        .iter()
+        .map(|(alias, level)| (alias.to_string(), level.to_string()))
+        .collect();
+
+    let spec = std::env::var("LEVEL_ALIASES").unwrap_or_default();
+    for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match pair.split_once('=') {
+            Some((alias, level)) if LEVELS.contains(&level.trim().to_uppercase().as_str()) => {
+                aliases.insert(alias.trim().to_uppercase(), level.trim().to_uppercase());
+            }
+            _ => warn!("Ignoring invalid LEVEL_ALIASES entry '{}'", pair),
+        }
+    }
+
+    aliases
+}
+
+/// Returns true when `value` nests objects/arrays more than `max` levels deep.
+/// The top-level metadata object counts as the first level.
+fn exceeds_depth(value: &Value, max: usize) -> bool {
+    match value {
+        Value::Object(map) => max == 0 || map.values().any(|v| exceeds_depth(v, max - 1)),
+        Value::Array(items) => max == 0 || items.iter().any(|v| exceeds_depth(v, max - 1)),
+        _ => false,
+    }
+}
+
+fn has_metadata(metadata: &Option<Value>) -> bool {
+    match metadata {
+        Some(Value::Object(map)) => !map.is_empty(),
+        Some(Value::Array(items)) => !items.is_empty(),
+        Some(Value::Null) | None => false,
+        Some(_) => true,
+    }
+}
+
+/// Rejects `last` combined with an explicit `from`/`to`, which would be ambiguous.
+fn check_time_window(filters: &LogFilters) -> Result<(), ApiError> {
+    if filters.last.is_some() && (filters.from.is_some() || filters.to.is_some()) {
+        return Err(ApiError::bad_request("last cannot be combined with from or to"));
+    }
+    Ok(())
+}
+
+/// Characters of context kept on each side of a match in `match_snippet`.
+const SNIPPET_CONTEXT: usize = 40;
+
+/// A window of the message around the first case-insensitive occurrence of `term`,
+/// with `…` marking where it was cut.
+fn match_snippet(message: &str, term: &str) -> Option<String> {
+    let needle = term.to_lowercase();
+    if needle.is_empty() {
+        return None;
+    }
+
+    // Lowercase the message once, remembering which original char each byte came from
+    let chars: Vec<char> = message.chars().collect();
+    let mut lower = String::with_capacity(message.len());
+    let mut origin = Vec::with_capacity(message.len());
+    for (index, c) in chars.iter().enumerate() {
+        for lc in c.to_lowercase() {
+            lower.push(lc);
+            origin.extend(std::iter::repeat_n(index, lc.len_utf8()));
+        }
+    }
+
+    let found = lower.find(&needle)?;
+    let first = origin[found];
+    let last = origin[found + needle.len() - 1];
+    let start = first.saturating_sub(SNIPPET_CONTEXT);
+    let end = (last + 1 + SNIPPET_CONTEXT).min(chars.len());
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    snippet.extend(&chars[start..end]);
+    if end < chars.len() {
+        snippet.push('…');
+    }
+    Some(snippet)
+}
+
+async fn count_logs(pool: &PgPool, filters: &LogFilters, aliases: &ServiceAliases) -> Result<i64, sqlx::Error> {
+    query::count(filters, aliases).build_query_scalar().fetch_one(pool).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/logs",
+    params(LogFilters, CursorParam, PrettyParam),
+    responses(
+        (status = 200, description = "Matching logs, newest first", content(
+            (LogResponse = "application/json"),
+            (LogResponse = "application/msgpack"),
+        )),
+        (status = 400, description = "Invalid filters or cursor token, a plain cursor while `CURSOR_SECRET` is set, or `offset + limit` beyond MAX_RESULT_WINDOW", body = ErrorBody),
+        (status = 503, description = "Too many expensive queries (searches) in progress", body = ErrorBody),
+    )
+)]
+async fn get_logs(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    OriginalUri(uri): OriginalUri,
+    Query(cursor): Query<CursorParam>,
+    Filters(mut filters): Filters,
+) -> Result<Response, ApiError> {
+    reject_plain_cursor(
+        &state,
+        filters.since.is_some() || filters.since_id.is_some() || filters.after_seq.is_some_and(|seq| seq > 0),
+    )?;
+    open_log_cursor(&state, &mut filters, cursor.cursor.as_deref())?;
+    list_logs(state, format, uri, filters).await
+}
+
+/// With `CURSOR_SECRET` set, `400` for a request that positions itself with a plain cursor
+/// parameter, which a client could pick at will. `after_seq=0`, which starts from the
+/// first log, stays allowed so a consumer can get its first token.
+fn reject_plain_cursor(state: &AppState, plain: bool) -> Result<(), ApiError> {
+    if plain && state.cursor_tokens.is_some() {
+        return Err(ApiError::bad_request("pass the next_cursor token of the previous response as cursor instead of a plain cursor"));
+    }
+    Ok(())
+}
+
+/// Replaces the cursor in `filters` with the one in the `cursor` token, if any.
+fn open_log_cursor(state: &AppState, filters: &mut LogFilters, token: Option<&str>) -> Result<(), ApiError> {
+    let Some(token) = token else {
+        return Ok(());
+    };
+    let Some(tokens) = &state.cursor_tokens else {
+        return Err(ApiError::bad_request("cursor tokens are not enabled; use since/since_id or after_seq"));
+    };
+    match tokens.open("logs", token) {
+        Some(LogCursor::Since(since, since_id)) => {
+            (filters.since, filters.since_id, filters.after_seq) = (since, since_id, None);
+        }
+        Some(LogCursor::Seq(after_seq)) => {
+            (filters.since, filters.since_id, filters.after_seq) = (None, None, Some(after_seq));
+        }
+        None => return Err(ApiError::bad_request("invalid cursor")),
+    }
+    Ok(())
+}
+
+async fn list_logs(state: AppState, format: ResponseFormat, uri: Uri, mut filters: LogFilters) -> Result<Response, ApiError> {
+    check_time_window(&filters)?;
+    // A seq is taken when its insert starts, so a poller stays behind inserts still running
+    if filters.after_seq.is_some() {
+        filters.settle = Some(state.sync_settle);
+    }
+    let tz = match &filters.tz {
+        Some(name) => Some(
+            name.parse::<Tz>()
+                .map_err(|_| ApiError::bad_request(format!("unknown timezone '{}'", name)))?,
+        ),
+        None => None,
+    };
+
+    let (limit, offset) = filters.limit_offset().map_err(ApiError::bad_request)?;
+    // Postgres reads and discards every skipped row, so deep offsets get slower and slower
+    if let Some(window) = state.max_result_window {
+        if offset.saturating_add(limit) > window && !filters.count_only.unwrap_or(false) {
+            return Err(ApiError::bad_request(format!(
+                "offset + limit must not exceed {}; page past it with the since/since_id or after_seq cursor, or narrow the results with from/to",
+                window
+            )));
+        }
+    }
+
+    let cache_key = state.log_query_cache.as_ref().map(|_| QueryCache::<LogResponse>::key(&filters));
+    if let (Some(cache), Some(key)) = (&state.log_query_cache, &cache_key) {
+        if let Some((cached, age)) = cache.get(key) {
+            let links = page_links(&uri, &filters, &cached, offset, state.max_result_window);
+            let headers = [(header::AGE, age.as_secs().to_string())];
+            return Ok((headers, links.map(|links| [(header::LINK, links)]), format.respond(&*cached)).into_response());
+        }
+    }
+
+    // Substring searches and ingest delays compare every row in the window, so they count
+    // against the expensive-read limit
+    let searches = |term: &Option<String>| term.as_deref().is_some_and(|term| !term.is_empty());
+    let _permit = if searches(&filters.search) || searches(&filters.not_search) || filters.min_ingest_delay.is_some() {
+        Some(expensive_read_permit(&state).await?)
+    } else {
+        None
+    };
+
+    let incremental = filters.is_incremental();
+    // A badge only needs the number, so count_only skips the row query entirely
+    let logs: Vec<LogEntry> = if filters.count_only.unwrap_or(false) {
+        Vec::new()
+    } else {
+        let mut query = query::select(&filters, &state.service_aliases).map_err(ApiError::bad_request)?;
+        let rows = timed(&state, "GET /logs", &filters, query.build().fetch_all(&state.pool))
+            .await
+            .map_err(|e| read_failed(&state, "fetch logs", &filters, e))?;
+
+        let highlight = filters.highlight.unwrap_or(false);
+        rows
+            .iter()
+            .map(|row| {
+                let mut entry = match tz {
+                    Some(tz) => log_from_row(row).in_timezone(tz),
+                    None => log_from_row(row),
+                };
+                state.service_aliases.canonicalize(&mut entry.service);
+                if let (true, Some(search)) = (highlight, &filters.search) {
+                    entry.match_snippet = match_snippet(&entry.message, search);
+                }
+                entry
+            })
+            .collect()
+    };
+
+    // Get total count for pagination
+    let total = timed(&state, "GET /logs", &filters, count_logs(&state.pool, &filters, &state.service_aliases))
+        .await
+        .map_err(|e| read_failed(&state, "count logs", &filters, e))?;
+
+    let (page, total_pages) = if limit > 0 {
+        (offset / limit + 1, (total + limit - 1) / limit)
+    } else {
+        (1, 0)
+    };
+
+    let next_after_seq = filters.after_seq.map(|after| logs.last().and_then(|log| log.seq).unwrap_or(after));
+    let newest = if incremental { logs.last() } else { logs.first() };
+    let (mut next_since, mut next_since_id) = match newest {
+        Some(log) => (log.timestamp, log.id),
+        None => (filters.since.map(|t| t.fixed_offset()), filters.since_id),
+    };
+    let mut next_after_seq = next_after_seq;
+    let mut next_cursor = None;
+    if let Some(tokens) = &state.cursor_tokens {
+        let cursor = match next_after_seq.take() {
+            Some(after_seq) => Some(LogCursor::Seq(after_seq)),
+            None if next_since.is_some() || next_since_id.is_some() => Some(LogCursor::Since(
+                next_since.take().map(|t| t.with_timezone(&Utc)),
+                next_since_id.take(),
+            )),
+            None => None,
+        };
+        next_cursor = cursor.map(|cursor| tokens.seal("logs", &cursor));
+    }
+
+    let response = LogResponse {
+        logs,
+        total,
+        page,
+        per_page: limit,
+        total_pages,
+        next_since,
+        next_since_id,
+        next_after_seq,
+        next_cursor,
+        cached: false,
+    };
+    if let (Some(cache), Some(key)) = (&state.log_query_cache, cache_key) {
+        cache.insert(key, LogResponse { cached: true, ..response.clone() });
+    }
+    let links = page_links(&uri, &filters, &response, offset, state.max_result_window);
+    Ok((links.map(|links| [(header::LINK, links)]), format.respond(&response)).into_response())
+}
+
+/// The RFC 8288 `Link` header value for a `GET /logs` response: `first`, `prev` and `next`
+/// pages with offset paging (in the request's `page`/`per_page` or `limit`/`offset` form),
+/// only `next` with a cursor. The other parameters of the request are kept as they were.
+fn page_links(uri: &Uri, filters: &LogFilters, response: &LogResponse, offset: i64, window: Option<i64>) -> Option<String> {
+    const PAGING: &[&str] = &["limit", "offset", "page", "per_page", "since", "since_id", "after_seq", "cursor"];
+    if filters.count_only.unwrap_or(false) {
+        return None;
+    }
+    let kept: Vec<(String, String)> = serde_urlencoded::from_str::<Vec<(String, String)>>(uri.query().unwrap_or(""))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(key, _)| !PAGING.contains(&key.as_str()))
+        .collect();
+    let link = |rel: &str, paging: Vec<(&str, String)>| {
+        let pairs = kept.iter().map(|(k, v)| (k.as_str(), v.clone())).chain(paging);
+        let query = serde_urlencoded::to_string(pairs.collect::<Vec<_>>()).unwrap_or_default();
+        format!("<{}?{}>; rel=\"{}\"", uri.path(), query, rel)
+    };
+    let limit = response.per_page;
+
+    let mut links = Vec::new();
+    if let (true, Some(cursor)) = (filters.is_incremental(), &response.next_cursor) {
+        links.push(link("next", vec![("cursor", cursor.clone()), ("limit", limit.to_string())]));
+    } else if let Some(after_seq) = response.next_after_seq {
+        links.push(link("next", vec![("after_seq", after_seq.to_string()), ("limit", limit.to_string())]));
+    } else if filters.is_incremental() {
+        let mut paging = vec![("limit", limit.to_string())];
+        if let Some(since) = response.next_since {
+            paging.push(("since", since.to_rfc3339_opts(SecondsFormat::AutoSi, true)));
+        }
+        if let Some(since_id) = response.next_since_id {
+            paging.push(("since_id", since_id.to_string()));
+        }
+        links.push(link("next", paging));
+    } else {
+        let by_page = filters.page.is_some() || filters.per_page.is_some();
+        let at = |offset: i64| {
+            if by_page {
+                vec![("page", (offset / limit + 1).to_string()), ("per_page", limit.to_string())]
+            } else {
+                vec![("limit", limit.to_string()), ("offset", offset.to_string())]
+            }
+        };
+        links.push(link("first", at(0)));
+        if offset > 0 {
+            links.push(link("prev", at((offset - limit).max(0))));
+        }
+        let next = offset + limit;
+        if next < response.total && window.is_none_or(|window| next + limit <= window) {
+            links.push(link("next", at(next)));
+        }
+    }
+    Some(links.join(", "))
+}
+
+/// Parses saved or to-be-saved filters the way `Filters` parses a query string, with
+/// `metadata` as an object of key to value.
+fn parse_saved_filters(state: &AppState, value: &Value) -> Result<LogFilters, ApiError> {
+    let mut filters: LogFilters = serde_json::from_value(value.clone())
+        .map_err(|e| ApiError::bad_request(format!("invalid filters: {}", e)))?;
+    let metadata = match value.get("metadata") {
+        None | Some(Value::Null) => serde_json::Map::new(),
+        Some(Value::Object(map)) => map.clone(),
+        Some(_) => return Err(ApiError::bad_request("invalid filters: metadata must be an object")),
+    };
+    let mut pairs = Vec::with_capacity(metadata.len());
+    for (key, value) in metadata {
+        match value {
+            Value::String(value) => pairs.push((key, value)),
+            _ => return Err(ApiError::bad_request(format!("invalid filters: metadata.{} must be a string", key))),
+        }
+    }
+    add_metadata_filters(state, &mut filters, pairs)?;
+    filters
+        .validate()
+        .map_err(|e| ApiError::bad_request(format!("invalid filters: {}", e)))?;
+    normalize_level_filters(state, &mut filters);
+    Ok(filters)
+}
+
+/// Saves a named filter set, which `GET /queries/{name}/logs` then runs.
+#[utoipa::path(
+    post,
+    path = "/queries",
+    request_body = SaveQueryRequest,
+    security(("admin_token" = [])),
+    responses(
+        (status = 201, description = "The saved query", body = SavedQuery),
+        (status = 400, description = "Invalid name or filters", body = ErrorBody),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "`ADMIN_TOKEN` is not configured", body = ErrorBody),
+        (status = 409, description = "A query with this name already exists", body = ErrorBody),
+    )
+)]
+async fn save_query(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<SaveQueryRequest>,
+) -> Result<(StatusCode, Json<SavedQuery>), ApiError> {
+    require_admin(&state, &headers)?;
+    let name = request.name.trim();
+    if name.is_empty()
+        || name.len() > 100
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+    {
+        return Err(ApiError::bad_request("name must be 1 to 100 letters, digits, '-' or '_'"));
+    }
+    let filters = parse_saved_filters(&state, &request.filters)?;
+    check_time_window(&filters)?;
+    if let Some(tz) = &filters.tz {
+        tz.parse::<Tz>().map_err(|_| ApiError::bad_request(format!("unknown timezone '{}'", tz)))?;
+    }
+
+    let filters = given_filters(&filters);
+    let created_at: Option<DateTime<Utc>> = sqlx::query_scalar(
+        r#"
+        INSERT INTO saved_queries (name, filters) VALUES ($1, $2)
+        ON CONFLICT (name) DO NOTHING
+        RETURNING created_at
+        "#
+    )
+        .bind(name)
+        .bind(&filters)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| {
+            error!("Failed to save query '{}': {}", name, e);
+            ApiError::from(StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+    let created_at = created_at
+        .ok_or_else(|| ApiError::new(StatusCode::CONFLICT, format!("a query named '{}' already exists", name)))?;
+
+    info!("Saved query '{}' with filters {}", name, filters);
+    Ok((
+        StatusCode::CREATED,
+        Json(SavedQuery {
+            name: name.to_string(),
+            filters,
+            created_at,
+        }),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/queries",
+    params(PrettyParam),
+    responses((status = 200, description = "Every saved query, by name", body = Vec<SavedQuery>))
+)]
+async fn list_saved_queries(State(state): State<AppState>, format: ResponseFormat) -> Result<Response, ApiError> {
+    let rows = sqlx::query("SELECT name, filters, created_at FROM saved_queries ORDER BY name")
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| read_failed(&state, "list saved queries", &(), e))?;
+    let queries: Vec<SavedQuery> = rows
+        .iter()
+        .map(|row| SavedQuery {
+            name: row.get("name"),
+            filters: row.get("filters"),
+            created_at: row.get("created_at"),
+        })
+        .collect();
+    Ok(format.respond(&queries))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/queries/{name}",
+    params(("name" = String, Path, description = "The saved query")),
+    security(("admin_token" = [])),
+    responses(
+        (status = 204, description = "The query was deleted"),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "`ADMIN_TOKEN` is not configured", body = ErrorBody),
+        (status = 404, description = "No query with this name", body = ErrorBody),
+    )
+)]
+async fn delete_saved_query(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    require_admin(&state, &headers)?;
+    let deleted = sqlx::query("DELETE FROM saved_queries WHERE name = $1")
+        .bind(&name)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| {
+            error!("Failed to delete saved query '{}': {}", name, e);
+            ApiError::from(StatusCode::INTERNAL_SERVER_ERROR)
+        })?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "saved query not found"));
+    }
+    info!("Deleted saved query '{}'", name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Runs a saved query through `GET /logs`, with this request's paging.
+#[utoipa::path(
+    get,
+    path = "/queries/{name}/logs",
+    params(("name" = String, Path, description = "The saved query"), SavedQueryPaging, PrettyParam),
+    responses(
+        (status = 200, description = "Logs matching the saved filters, as from `GET /logs`", body = LogResponse),
+        (status = 400, description = "The saved filters are no longer valid, e.g. a metadata key is not queryable any more", body = ErrorBody),
+        (status = 404, description = "No query with this name", body = ErrorBody),
+        (status = 503, description = "Too many expensive queries (searches) in progress", body = ErrorBody),
+    )
+)]
+async fn get_saved_query_logs(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Path(name): Path<String>,
+    OriginalUri(uri): OriginalUri,
+    Query(paging): Query<SavedQueryPaging>,
+) -> Result<Response, ApiError> {
+    let saved: Value = sqlx::query_scalar("SELECT filters FROM saved_queries WHERE name = $1")
+        .bind(&name)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| read_failed(&state, "load saved query", &(), e))?
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "saved query not found"))?;
+    let mut filters = parse_saved_filters(&state, &saved)?;
+    if paging.limit.is_some() || paging.offset.is_some() || paging.page.is_some() || paging.per_page.is_some() {
+        filters.limit = paging.limit;
+        filters.offset = paging.offset;
+        filters.page = paging.page;
+        filters.per_page = paging.per_page;
+    }
+    reject_plain_cursor(
+        &state,
+        paging.since.is_some() || paging.since_id.is_some() || paging.after_seq.is_some_and(|seq| seq > 0),
+    )?;
+    if paging.since.is_some() || paging.since_id.is_some() || paging.after_seq.is_some() {
+        filters.since = paging.since;
+        filters.since_id = paging.since_id;
+        filters.after_seq = paging.after_seq;
+    }
+    open_log_cursor(&state, &mut filters, paging.cursor.as_deref())?;
+    list_logs(state, format, uri, filters).await
+}
+
+/// `HEAD /logs`: the count of matching logs in `X-Total-Count`, without a body.
+#[utoipa::path(
+    head,
+    path = "/logs",
+    params(LogFilters),
+    responses((status = 200, description = "Count of matching logs in the X-Total-Count header"))
+)]
+async fn head_logs(
+    State(state): State<AppState>,
+    Filters(filters): Filters,
+) -> Result<impl IntoResponse, StatusCode> {
+    check_time_window(&filters).map_err(|e| e.status)?;
+    let counted = timed(&state, "HEAD /logs", &filters, count_logs(&state.pool, &filters, &state.service_aliases));
+    let count = counted.await.map_err(|e| {
+        warn!("Failed to count logs: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok([("x-total-count", count.to_string())])
+}
+
+#[utoipa::path(
+    get,
+    path = "/logs/count",
+    params(LogFilters, PrettyParam),
+    responses((status = 200, description = "`{\"count\": N}` for the matching logs", body = Object))
+)]
+async fn get_log_count(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Filters(filters): Filters,
+) -> Result<Response, ApiError> {
+    check_time_window(&filters)?;
+    let count = timed(&state, "GET /logs/count", &filters, count_logs(&state.pool, &filters, &state.service_aliases))
+        .await
+        .map_err(|e| read_failed(&state, "count logs", &filters, e))?;
+
+    Ok(format.respond(&serde_json::json!({ "count": count })))
+}
+
+/// Deletes the logs matching `filters` in batches of `DELETE_BATCH_SIZE` rows so no single
+/// statement holds locks for long. Progress is streamed as NDJSON after every batch. Once
+/// started, the delete runs to completion even if the client disconnects.
+#[utoipa::path(
+    delete,
+    path = "/logs",
+    params(LogFilters, DeleteParams),
+    responses(
+        (status = 200, description = "NDJSON progress lines `{\"deleted_so_far\": N}` followed by `{\"deleted\": N, \"done\": true}`; with `dry_run=true`, `{\"would_delete\": N}`", content_type = "application/x-ndjson"),
+        (status = 400, description = "No filter given", body = ErrorBody),
+        (status = 503, description = "Maintenance mode is on", body = ErrorBody),
+    )
+)]
+async fn delete_logs(
+    State(state): State<AppState>,
+    Filters(filters): Filters,
+    Query(params): Query<DeleteParams>,
+) -> Result<Response, ApiError> {
+    check_time_window(&filters)?;
+    if !has_filters(&filters) {
+        return Err(ApiError::bad_request("refusing to delete without at least one filter"));
+    }
+
+    if params.dry_run.unwrap_or(false) {
+        let counted = timed(&state, "DELETE /logs", &filters, count_logs(&state.pool, &filters, &state.service_aliases));
+        let would_delete = counted.await.map_err(|e| {
+            warn!("Failed to count logs for dry-run delete: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        return Ok(Json(serde_json::json!({ "would_delete": would_delete })).into_response());
+    }
+
+    ensure_writable(&state)?;
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<String>(16);
+    tokio::spawn(async move {
+        let mut deleted: u64 = 0;
+        loop {
+            let mut query = QueryBuilder::new("DELETE FROM logs WHERE id IN (SELECT id FROM logs");
+            push_filters(&mut query, &filters, &state.service_aliases);
+            query.push(" LIMIT ").push_bind(state.delete_batch_size).push(")");
+
+            let batch = match timed(&state, "DELETE /logs", &filters, query.build().execute(&state.pool)).await {
+                Ok(result) => result.rows_affected(),
+                Err(e) => {
+                    error!("Batched delete failed after {} rows: {}", deleted, e);
+                    let _ = tx
+                        .send(format!("{}\n", serde_json::json!({ "deleted": deleted, "error": "delete failed" })))
+                        .await;
+                    return;
+                }
+            };
+            deleted += batch;
+
+            if batch < state.delete_batch_size as u64 {
+                break;
+            }
+            let _ = tx.send(format!("{}\n", serde_json::json!({ "deleted_so_far": deleted }))).await;
+        }
+
+        info!("Deleted {} logs matching {:?}", deleted, filters);
+        let _ = tx
+            .send(format!("{}\n", serde_json::json!({ "deleted": deleted, "done": true })))
+            .await;
+    });
+
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|line| (Ok::<_, Infallible>(line), rx))
+    });
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(stream),
+    )
+        .into_response())
+}
+
+/// Changes the level of every log at `old_level` whose message matches the pattern,
+/// in one transaction. Both levels go through the same normalization as ingestion. The
+/// affected logs and their previous level are recorded under the returned
+/// `operation_id`, which `POST /logs/reclassify/{operation_id}/rollback` undoes.
+#[utoipa::path(
+    post,
+    path = "/logs/reclassify",
+    request_body = ReclassifyRequest,
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "`{\"updated\": N, \"operation_id\": ...}` with the number of rows changed", body = Object),
+        (status = 400, description = "Empty pattern or unknown level", body = ErrorBody),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "`ADMIN_TOKEN` is not configured", body = ErrorBody),
+        (status = 503, description = "Maintenance mode is on", body = ErrorBody),
+    )
+)]
+async fn reclassify_logs(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<ReclassifyRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    ensure_writable(&state)?;
+    if request.message_pattern.is_empty() {
+        return Err(ApiError::bad_request("message_pattern must not be empty"));
+    }
+    let old_level = normalize_level(&request.old_level, &state.level_aliases);
+    let new_level = normalize_level(&request.new_level, &state.level_aliases);
+    for (given, level) in [(&request.old_level, &old_level), (&request.new_level, &new_level)] {
+        if !LEVELS.contains(&level.as_str()) {
+            return Err(ApiError::bad_request(format!("unknown level '{}'", given)));
+        }
+    }
+
+    let reclassify = async {
+        let mut tx = state.pool.begin().await?;
+        let operation_id: Uuid = sqlx::query_scalar(
+            r#"
+            INSERT INTO reclassify_operations (message_pattern, service, old_level, new_level)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id
+            "#
+        )
+            .bind(&request.message_pattern)
+            .bind(&request.service)
+            .bind(&old_level)
+            .bind(&new_level)
+            .fetch_one(&mut *tx)
+            .await?;
+        // Every changed row was at old_level, which is therefore its previous level
+        let updated = sqlx::query(
+            r#"
+            WITH changed AS (
+                UPDATE logs SET level = $1
+                WHERE level = $2 AND message LIKE $3 AND ($4::text IS NULL OR service = $4)
+                RETURNING id, timestamp
+            )
+            INSERT INTO reclassify_changes (operation_id, log_id, log_timestamp, previous_level)
+            SELECT $5, id, timestamp, $2 FROM changed
+            "#
+        )
+            .bind(&new_level)
+            .bind(&old_level)
+            .bind(&request.message_pattern)
+            .bind(&request.service)
+            .bind(operation_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>((operation_id, updated))
+    };
+
+    let (operation_id, updated) = timed(&state, "POST /logs/reclassify", &request, reclassify).await.map_err(|e| {
+        error!("Failed to reclassify logs: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    info!(
+        "Reclassified {} logs matching '{}' from {} to {} (operation {})",
+        updated, request.message_pattern, old_level, new_level, operation_id
+    );
+
+    Ok(Json(serde_json::json!({ "updated": updated, "operation_id": operation_id })))
+}
+
+/// Restores the levels a reclassify operation changed. Logs whose level has been changed
+/// again since are left alone and counted as `skipped`. An operation can only be rolled
+/// back once.
+#[utoipa::path(
+    post,
+    path = "/logs/reclassify/{operation_id}/rollback",
+    params(("operation_id" = Uuid, Path, description = "The `operation_id` returned by the reclassify")),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "`{\"restored\": N, \"skipped\": M}`", body = Object),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "`ADMIN_TOKEN` is not configured", body = ErrorBody),
+        (status = 404, description = "No such operation", body = ErrorBody),
+        (status = 409, description = "The operation was already rolled back", body = ErrorBody),
+        (status = 503, description = "Maintenance mode is on", body = ErrorBody),
+    )
+)]
+async fn rollback_reclassify(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(operation_id): Path<Uuid>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    ensure_writable(&state)?;
+
+    let failed = |e: sqlx::Error| {
+        error!("Failed to roll back reclassify operation {}: {}", operation_id, e);
+        ApiError::from(StatusCode::INTERNAL_SERVER_ERROR)
+    };
+    let mut tx = state.pool.begin().await.map_err(failed)?;
+    // Locked so two concurrent rollbacks of the same operation can't both apply
+    let operation = sqlx::query(
+        "SELECT new_level, rolled_back_at FROM reclassify_operations WHERE id = $1 FOR UPDATE",
+    )
+        .bind(operation_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(failed)?
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "reclassify operation not found"))?;
+    if operation.get::<Option<DateTime<Utc>>, _>("rolled_back_at").is_some() {
+        return Err(ApiError::new(StatusCode::CONFLICT, "reclassify operation was already rolled back"));
+    }
+    let new_level: String = operation.get("new_level");
+
+    let restored = sqlx::query(
+        r#"
+        UPDATE logs SET level = c.previous_level
+        FROM reclassify_changes c
+        WHERE c.operation_id = $1 AND logs.id = c.log_id AND logs.timestamp = c.log_timestamp
+          AND logs.level = $2
+        "#
+    )
+        .bind(operation_id)
+        .bind(&new_level)
+        .execute(&mut *tx)
+        .await
+        .map_err(failed)?
+        .rows_affected();
+    let recorded: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM reclassify_changes WHERE operation_id = $1")
+        .bind(operation_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(failed)?;
+    sqlx::query("UPDATE reclassify_operations SET rolled_back_at = NOW() WHERE id = $1")
+        .bind(operation_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(failed)?;
+    tx.commit().await.map_err(failed)?;
+
+    // Logs deleted since the reclassify count as skipped too
+    let skipped = (recorded as u64).saturating_sub(restored);
+    info!(
+        "Rolled back reclassify operation {}: restored {} logs, skipped {}",
+        operation_id, restored, skipped
+    );
+    Ok(Json(serde_json::json!({ "restored": restored, "skipped": skipped })))
+}
+
+/// Sets one metadata key on every log matching the filters, in one transaction, e.g. to
+/// mark the logs of an incident with its investigation ID.
+#[utoipa::path(
+    post,
+    path = "/logs/tag",
+    request_body = TagRequest,
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "`{\"updated\": N}` with the number of logs tagged", body = Object),
+        (status = 400, description = "No filter given, invalid filters or an empty key", body = ErrorBody),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "`ADMIN_TOKEN` is not configured", body = ErrorBody),
+        (status = 503, description = "Maintenance mode is on", body = ErrorBody),
+    )
+)]
+async fn tag_logs(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<TagRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    ensure_writable(&state)?;
+    if request.key.is_empty() {
+        return Err(ApiError::bad_request("key must not be empty"));
+    }
+    let filters = parse_saved_filters(&state, &request.filters)?;
+    check_time_window(&filters)?;
+    if !has_filters(&filters) {
+        return Err(ApiError::bad_request("refusing to tag without at least one filter"));
+    }
+
+    let tag = async {
+        let mut tx = state.pool.begin().await?;
+        let mut query = QueryBuilder::new("UPDATE logs SET metadata = COALESCE(metadata, '{}'::jsonb) || jsonb_build_object(");
+        query
+            .push_bind(&request.key)
+            .push("::text, ")
+            .push_bind(&request.value)
+            .push("::text) WHERE id IN (SELECT id FROM logs");
+        push_filters(&mut query, &filters, &state.service_aliases);
+        // `||` on a JSON array or scalar would append to or wrap it rather than set a key
+        query.push(") AND (metadata IS NULL OR jsonb_typeof(metadata) = 'object')");
+        let updated = query.build().execute(&mut *tx).await?.rows_affected();
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(updated)
+    };
+
+    let updated = timed(&state, "POST /logs/tag", &request, tag).await.map_err(|e| {
+        error!("Failed to tag logs: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    info!("Tagged {} logs matching {:?} with {}={}", updated, filters, request.key, request.value);
+
+    Ok(Json(serde_json::json!({ "updated": updated })))
+}
+
+/// Truncates the whole table. Only allowed when the body's token matches `PURGE_TOKEN`;
+/// without a configured token the route always refuses.
+#[utoipa::path(
+    post,
+    path = "/logs/purge-all",
+    request_body = PurgeRequest,
+    responses(
+        (status = 200, description = "`{\"purged\": N}` with the number of rows removed", body = Object),
+        (status = 403, description = "Missing or wrong token", body = ErrorBody),
+        (status = 503, description = "Maintenance mode is on", body = ErrorBody),
+    )
+)]
+async fn purge_all_logs(
+    State(state): State<AppState>,
+    Json(request): Json<PurgeRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    ensure_writable(&state)?;
+    let authorized = state
+        .purge_token
+        .as_deref()
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), request.token.as_bytes()));
+    if !authorized {
+        warn!("Rejected purge-all request with an invalid token");
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "invalid purge token"));
+    }
+
+    let purge = async {
+        let mut tx = state.pool.begin().await?;
+        // Lock first so the count matches exactly what the truncate removes
+        sqlx::query("LOCK TABLE logs IN ACCESS EXCLUSIVE MODE")
+            .execute(&mut *tx)
+            .await?;
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM logs")
+            .fetch_one(&mut *tx)
+            .await?;
+        sqlx::query("TRUNCATE logs").execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(count)
+    };
+    let purged = purge.await.map_err(|e| {
+        error!("Failed to purge logs: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    warn!("Purged all {} logs", purged);
+    Ok(Json(serde_json::json!({ "purged": purged })))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Waits up to `EXPENSIVE_QUERY_WAIT_MS` for one of the `MAX_EXPENSIVE_QUERIES` slots,
+/// failing with `503` when none frees up in time. Hold the permit until the query is done.
+async fn expensive_read_permit(state: &AppState) -> Result<OwnedSemaphorePermit, ApiError> {
+    let busy = "too many expensive queries in progress; retry shortly";
+    slot_permit(state, &state.expensive_reads, "expensive read", busy).await
+}
+
+/// Waits up to `EXPENSIVE_QUERY_WAIT_MS` for one of the `MAX_CONCURRENT_EXPORTS` slots.
+/// An export holds it for as long as the client takes to download.
+async fn export_permit(state: &AppState) -> Result<OwnedSemaphorePermit, ApiError> {
+    slot_permit(state, &state.exports, "export", "too many exports in progress; retry shortly").await
+}
+
+async fn slot_permit(
+    state: &AppState,
+    slots: &Arc<Semaphore>,
+    what: &str,
+    busy: &'static str,
+) -> Result<OwnedSemaphorePermit, ApiError> {
+    let acquire = slots.clone().acquire_owned();
+    match tokio::time::timeout(state.expensive_read_wait, acquire).await {
+        Ok(Ok(permit)) => Ok(permit),
+        _ => {
+            warn!("Rejected {}: no slot freed up within {:?}", what, state.expensive_read_wait);
+            Err(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, busy))
+        }
+    }
+}
+
+/// Checks for `Authorization: Bearer <ADMIN_TOKEN>`. Admin routes refuse every request
+/// while no token is configured.
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(expected) = state.admin_token.as_deref() else {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "admin routes are disabled"));
+    };
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match presented {
+        Some(token) if constant_time_eq(expected.as_bytes(), token.trim().as_bytes()) => Ok(()),
+        _ => Err(ApiError::new(StatusCode::UNAUTHORIZED, "missing or invalid admin token")),
+    }
+}
+
+/// Fails with `503` while maintenance mode is on. Every route that writes to the logs
+/// table calls this first, so reads keep working during database maintenance.
+fn ensure_writable(state: &AppState) -> Result<(), ApiError> {
+    if state.maintenance.load(Ordering::Relaxed) {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "TideLogs is in maintenance mode; writes are rejected until it ends",
+        ));
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/maintenance",
+    responses((status = 200, description = "Whether maintenance mode is on", body = MaintenanceMode))
+)]
+async fn get_maintenance(State(state): State<AppState>) -> Json<MaintenanceMode> {
+    Json(MaintenanceMode {
+        enabled: state.maintenance.load(Ordering::Relaxed),
+    })
+}
+
+/// Turns maintenance mode on or off at runtime.
+#[utoipa::path(
+    put,
+    path = "/maintenance",
+    request_body = MaintenanceMode,
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "The new mode", body = MaintenanceMode),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "`ADMIN_TOKEN` is not configured", body = ErrorBody),
+    )
+)]
+async fn set_maintenance(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<MaintenanceMode>,
+) -> Result<Json<MaintenanceMode>, ApiError> {
+    require_admin(&state, &headers)?;
+    let was_enabled = state.maintenance.swap(request.enabled, Ordering::Relaxed);
+    match (was_enabled, request.enabled) {
+        (false, true) => warn!("Entered maintenance mode: writes are rejected"),
+        (true, false) => info!("Left maintenance mode: writes are accepted again"),
+        _ => {}
+    }
+    Ok(Json(request))
+}
+
+/// Data-quality diagnostics: logs stored before their own timestamp, and logs older
+/// than their service's retention that should already have been removed.
+#[utoipa::path(
+    get,
+    path = "/logs/anomalies",
+    params(AnomalyParams),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Anomalous logs, newest first", body = [Anomaly]),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 403, description = "`ADMIN_TOKEN` is not configured", body = ErrorBody),
+    )
+)]
+async fn get_anomalies(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<AnomalyParams>,
+) -> Result<Json<Vec<Anomaly>>, ApiError> {
+    require_admin(&state, &headers)?;
+
+    let (services, days) = state.retention.overrides(&state);
+    let rows = sqlx::query(
+        r#"
+        SELECT l.id, l.timestamp, l.service, l.level, l.message, l.message_gzip, l.metadata, l.created_at, l.seq,
+               CASE WHEN l.created_at < l.timestamp THEN 'created_before_timestamp'
+                    ELSE 'outside_retention' END AS reason
+        FROM logs l
+        LEFT JOIN UNNEST($1::text[], $2::int[]) AS r(service, days) ON r.service = l.service
+        WHERE l.created_at < l.timestamp
+           OR l.timestamp < NOW() - make_interval(days => COALESCE(r.days, $3))
+        ORDER BY l.timestamp DESC, l.id DESC
+        LIMIT $4
+        "#
+    )
+        .bind(services)
+        .bind(days)
+        .bind(state.retention.default_days())
+        .bind(params.limit.unwrap_or(100).clamp(0, 1000))
+        .fetch_all(&state.pool)
+        .await
+        .map_err(|e| {
+            warn!("Failed to query anomalies: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let anomalies = rows
+        .iter()
+        .map(|row| Anomaly {
+            reason: row.get("reason"),
+            log: log_from_row(row),
+        })
+        .collect();
+
+    Ok(Json(anomalies))
+}
+
+/// `ERROR` logs grouped into incidents: consecutive errors no more than `gap` apart belong
+/// to the same incident. Takes the log filters to narrow the errors considered, over the
+/// last 24 hours unless a time window is given; `limit`/`offset` page through incidents.
+#[utoipa::path(
+    get,
+    path = "/logs/incidents",
+    params(LogFilters, IncidentParams, PrettyParam),
+    responses(
+        (status = 200, description = "Incidents, most recent first", body = [Incident]),
+        (status = 400, description = "Invalid filters, a `level` other than `ERROR`, or a `min_size` below 1", body = ErrorBody),
+        (status = 503, description = "Too many expensive queries in progress", body = ErrorBody),
+    )
+)]
+async fn get_incidents(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Filters(mut filters): Filters,
+    Query(params): Query<IncidentParams>,
+) -> Result<Response, ApiError> {
+    check_time_window(&filters)?;
+    if filters.level.as_ref().is_some_and(|level| normalize_level(level, &state.level_aliases) != "ERROR") {
+        return Err(ApiError::bad_request("incidents are built from ERROR logs only"));
+    }
+    filters.level = Some("ERROR".to_string());
+    if filters.from.is_none() && filters.to.is_none() && filters.last.is_none() {
+        filters.last = RelativeWindow::try_from("24h".to_string()).ok();
+    }
+    let gap = params.gap.map_or(300, RelativeWindow::seconds);
+    let min_size = params.min_size.unwrap_or(1);
+    if min_size < 1 {
+        return Err(ApiError::bad_request("min_size must be at least 1"));
+    }
+    let (limit, offset) = filters.limit_offset().map_err(ApiError::bad_request)?;
+
+    // An error more than `gap` after the previous one starts a new incident; the running
+    // sum of those starts numbers the incidents
+    let mut query = QueryBuilder::new(
+        "WITH errors AS (SELECT timestamp, service, CASE WHEN timestamp - LAG(timestamp) OVER (ORDER BY timestamp) <= make_interval(secs => ",
+    );
+    query.push_bind(gap as f64).push(") THEN 0 ELSE 1 END AS starts FROM logs");
+    push_filters(&mut query, &filters, &state.service_aliases);
+    query.push(
+        r#"
+        ), numbered AS (
+            SELECT timestamp, service, SUM(starts) OVER (ORDER BY timestamp ROWS UNBOUNDED PRECEDING) AS incident
+            FROM errors
+        )
+        SELECT MIN(timestamp) AS start, MAX(timestamp) AS "end", COUNT(*) AS count,
+               ARRAY_AGG(DISTINCT service ORDER BY service) AS services
+        FROM numbered
+        GROUP BY incident
+        HAVING COUNT(*) >= "#,
+    );
+    query
+        .push_bind(min_size)
+        .push(" ORDER BY start DESC LIMIT ")
+        .push_bind(limit)
+        .push(" OFFSET ")
+        .push_bind(offset);
+
+    let _permit = expensive_read_permit(&state).await?;
+    let rows = timed(&state, "GET /logs/incidents", &filters, query.build().fetch_all(&state.pool))
+        .await
+        .map_err(|e| read_failed(&state, "cluster incidents", &filters, e))?;
+
+    let incidents: Vec<Incident> = rows
+        .iter()
+        .map(|row| {
+            let mut services: Vec<String> = row.get("services");
+            for service in &mut services {
+                state.service_aliases.canonicalize(service);
+            }
+            services.sort();
+            services.dedup();
+            Incident {
+                start: row.get("start"),
+                end: row.get("end"),
+                count: row.get("count"),
+                services,
+            }
+        })
+        .collect();
+    Ok(format.respond(&incidents))
+}
+
+/// Columns `/logs/distinct` can list; only low-cardinality ones, so the scan stays cheap
+/// to group.
+const DISTINCT_COLUMNS: &[&str] = &["service", "level"];
+
+/// The distinct values of a column, most frequent first, for filter dropdowns. Services
+/// are reported under their canonical name. Takes the log filters, over the last 24 hours
+/// unless a time window is given; `limit` caps the number of values.
+#[utoipa::path(
+    get,
+    path = "/logs/distinct",
+    params(DistinctParams, LogFilters, PrettyParam),
+    responses(
+        (status = 200, description = "Values with their log counts, most frequent first", body = [DistinctValue]),
+        (status = 400, description = "Column not in the allowlist, or invalid filters", body = ErrorBody),
+        (status = 503, description = "Too many expensive queries in progress", body = ErrorBody),
+    )
+)]
+async fn get_distinct_values(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Filters(mut filters): Filters,
+    Query(params): Query<DistinctParams>,
+) -> Result<Response, ApiError> {
+    // Checked against the allowlist, so the name can be spelled into the query
+    let Some(column) = DISTINCT_COLUMNS.iter().find(|&&c| c == params.column) else {
+        return Err(ApiError::bad_request(format!(
+            "unknown column '{}': expected one of {}",
+            params.column,
+            DISTINCT_COLUMNS.join(", ")
+        )));
+    };
+    check_time_window(&filters)?;
+    if filters.from.is_none() && filters.to.is_none() && filters.last.is_none() {
+        filters.last = RelativeWindow::try_from("24h".to_string()).ok();
+    }
+    let (limit, _) = filters.limit_offset().map_err(ApiError::bad_request)?;
+    let with_counts = params.counts.unwrap_or(true);
+
+    let mut query = QueryBuilder::new(if with_counts {
+        format!("SELECT {0}, COUNT(*) FROM logs", column)
+    } else {
+        format!("SELECT DISTINCT {0}, 0::BIGINT FROM logs", column)
+    });
+    push_filters(&mut query, &filters, &state.service_aliases);
+    if with_counts {
+        query.push(format!(" GROUP BY {}", column));
+    }
+
+    let _permit = expensive_read_permit(&state).await?;
+    let rows: Vec<(String, i64)> =
+        timed(&state, "GET /logs/distinct", &filters, query.build_query_as().fetch_all(&state.pool))
+            .await
+            .map_err(|e| read_failed(&state, "list distinct values", &filters, e))?;
+
+    let mut counts: HashMap<String, i64> = HashMap::new();
+    for (mut value, count) in rows {
+        if *column == "service" {
+            state.service_aliases.canonicalize(&mut value);
+        }
+        *counts.entry(value).or_insert(0) += count;
+    }
+    let mut counts: Vec<(String, i64)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(limit as usize);
+
+    let values: Vec<DistinctValue> = counts
+        .into_iter()
+        .map(|(value, count)| DistinctValue {
+            value,
+            count: with_counts.then_some(count),
+        })
+        .collect();
+    Ok(format.respond(&values))
+}
+
+/// Streams logs as server-sent `log` events as they are stored, optionally limited to one
+/// service and/or level and thinned out to a random `sample`. A consumer too slow to keep
+/// up receives a `lagged` event with the number of logs it missed.
+#[utoipa::path(
+    get,
+    path = "/logs/tail",
+    params(TailParams),
+    responses(
+        (status = 200, description = "Server-sent events, one `log` event per stored log", content_type = "text/event-stream", body = LogEntry),
+        (status = 400, description = "`sample` is not between 0 and 1", body = ErrorBody),
+        (status = 429, description = "This client holds too many tail streams", body = ErrorBody),
+        (status = 503, description = "The server holds too many tail streams", body = ErrorBody),
+    )
+)]
+async fn tail_logs(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Query(params): Query<TailParams>,
+) -> Result<Response, ApiError> {
+    let sample = match params.sample {
+        Some(sample) if sample > 0.0 && sample <= 1.0 => sample,
+        Some(_) => return Err(ApiError::bad_request("sample must be greater than 0 and at most 1")),
+        None => 1.0,
+    };
+    let subscription = state.tail.subscribe(client.ip()).map_err(|limit| match limit {
+        TailLimit::Client(max) => ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            format!("at most {} tail streams per client", max),
+        ),
+        TailLimit::Server(max) => ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("the server is at its limit of {} tail streams", max),
+        ),
+    })?;
+    let level = params.level.map(|l| normalize_level(&l, &state.level_aliases));
+    let services = params.service.map(|service| state.service_aliases.expand(&service, false));
+    let aliases = state.service_aliases.clone();
+
+    let events = futures_util::stream::unfold(subscription, move |mut subscription| {
+        let (services, level, aliases) = (services.clone(), level.clone(), aliases.clone());
+        async move {
+            loop {
+                let event = match subscription.receiver.recv().await {
+                    Ok(mut entry) => {
+                        let wanted = services.as_ref().is_none_or(|names| names.contains(&entry.service))
+                            && level.as_ref().is_none_or(|l| *l == entry.level)
+                            && (sample >= 1.0 || rand::random::<f64>() < sample);
+                        if !wanted {
+                            continue;
+                        }
+                        if aliases.canonical(&entry.service) != entry.service {
+                            aliases.canonicalize(&mut Arc::make_mut(&mut entry).service);
+                        }
+                        match Event::default().event("log").json_data(&*entry) {
+                            Ok(event) => event,
+                            Err(_) => continue,
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => Event::default().event("lagged").data(missed.to_string()),
+                    Err(RecvError::Closed) => return None,
+                };
+                return Some((Ok::<_, Infallible>(event), subscription));
+            }
+        }
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()).into_response())
+}
+
+/// Pages through logs in ingestion order, ascending by `(created_at, id)` and strictly
+/// after the given cursor, for mirroring them into another store. `created_at` is the
+/// insert's transaction start, so a row can become visible after rows with a later
+/// `created_at`; only logs older than `SYNC_SETTLE_MS` are returned, which keeps a slow
+/// commit from being skipped by a cursor that already moved past it.
+#[utoipa::path(
+    get,
+    path = "/logs/sync",
+    params(SyncParams, PrettyParam),
+    responses(
+        (status = 200, description = "The next logs after the cursor", body = SyncResponse),
+        (status = 400, description = "`since_id` without `since_created`, a limit below 1, or an invalid or plain cursor (with `CURSOR_SECRET`)", body = ErrorBody),
+    )
+)]
+async fn sync_logs(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Query(params): Query<SyncParams>,
+) -> Result<Response, ApiError> {
+    if params.since_id.is_some() && params.since_created.is_none() {
+        return Err(ApiError::bad_request("since_id requires since_created"));
+    }
+    let mut cursor = (params.since_created, params.since_id);
+    match (&state.cursor_tokens, &params.cursor) {
+        (Some(_), None) if params.since_created.is_some() => {
+            return Err(ApiError::bad_request("pass the next_cursor token of the previous response as cursor instead of since_created"));
+        }
+        (Some(tokens), Some(token)) => {
+            let SyncCursor(created, id) = tokens
+                .open("sync", token)
+                .ok_or_else(|| ApiError::bad_request("invalid cursor"))?;
+            cursor = (Some(created), id);
+        }
+        (None, Some(_)) => return Err(ApiError::bad_request("cursor tokens are not enabled; use since_created/since_id")),
+        _ => {}
+    }
+    let limit = params.limit.unwrap_or(100).min(1000);
+    if limit < 1 {
+        return Err(ApiError::bad_request("limit must be at least 1"));
+    }
+
+    let mut query = QueryBuilder::new(format!(
+        "SELECT {} FROM logs WHERE created_at <= NOW() - ",
+        query::LOG_COLUMNS
+    ));
+    query.push_bind(state.sync_settle.as_secs_f64()).push(" * INTERVAL '1 second'");
+    match cursor {
+        (Some(created), Some(id)) => {
+            query.push(" AND (created_at, id) > (").push_bind(created).push(", ").push_bind(id).push(")");
+        }
+        (Some(created), None) => {
+            query.push(" AND created_at > ").push_bind(created);
+        }
+        _ => {}
+    }
+    query.push(" ORDER BY created_at ASC, id ASC LIMIT ").push_bind(limit);
+    let rows = timed(&state, "GET /logs/sync", &params, query.build().fetch_all(&state.pool))
+        .await
+        .map_err(|e| read_failed(&state, "sync logs", &params, e))?;
+
+    let logs: Vec<LogEntry> = rows
+        .iter()
+        .map(|row| {
+            let mut entry = log_from_row(row);
+            state.service_aliases.canonicalize(&mut entry.service);
+            entry
+        })
+        .collect();
+    let (mut next_since_created, mut next_since_id) = match logs.last() {
+        Some(log) => (log.created_at.map(|t| t.with_timezone(&Utc)), log.id),
+        None => cursor,
+    };
+    let next_cursor = match (&state.cursor_tokens, next_since_created) {
+        (Some(tokens), Some(created)) => {
+            next_since_created = None;
+            Some(tokens.seal("sync", &SyncCursor(created, next_since_id.take())))
+        }
+        _ => None,
+    };
+    Ok(format.respond(&SyncResponse {
+        has_more: logs.len() as i64 == limit,
+        logs,
+        next_since_created,
+        next_since_id,
+        next_cursor,
+    }))
+}
+
+/// Streams logs as NDJSON in strict ascending `(timestamp, id)` order. Rows are read
+/// through a cursor and pushed into a bounded channel, so a slow client also slows the
+/// database read instead of buffering the whole window in memory.
+#[utoipa::path(
+    get,
+    path = "/logs/replay",
+    params(ReplayParams),
+    responses(
+        (status = 200, description = "NDJSON stream of logs, oldest first", content_type = "application/x-ndjson", body = LogEntry),
+        (status = 400, description = "Invalid rate", body = ErrorBody),
+        (status = 503, description = "Too many expensive queries in progress", body = ErrorBody),
+    )
+)]
+async fn replay_logs(
+    State(state): State<AppState>,
+    Query(params): Query<ReplayParams>,
+) -> Result<Response, ApiError> {
+    let interval = match params.rate {
+        Some(rate) if rate > 0.0 && rate.is_finite() => Some(Duration::from_secs_f64(1.0 / rate)),
+        Some(_) => return Err(ApiError::bad_request("rate must be a positive number")),
+        None => None,
+    };
+    let permit = expensive_read_permit(&state).await?;
+    let services = params.service.map(|service| state.service_aliases.expand(&service, false));
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<String>(64);
+    tokio::spawn(async move {
+        // Held for the whole stream, which keeps its cursor open until it finishes
+        let _permit = permit;
+        let mut ticker = interval.map(tokio::time::interval);
+        let mut rows = sqlx::query(
+            r#"
+            SELECT id, timestamp, service, level, message, message_gzip, metadata, created_at, seq FROM logs
+            WHERE ($1::timestamptz IS NULL OR timestamp >= $1)
+              AND ($2::timestamptz IS NULL OR timestamp < $2)
+              AND ($3::text[] IS NULL OR service = ANY($3))
+            ORDER BY timestamp ASC, id ASC
+            "#
+        )
+            .bind(params.from)
+            .bind(params.to)
+            .bind(services)
+            .fetch(&state.pool);
+
+        loop {
+            let row = match rows.try_next().await {
+                Ok(Some(row)) => row,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Log replay aborted: {}", e);
+                    break;
+                }
+            };
+            let mut entry = log_from_row(&row);
+            state.service_aliases.canonicalize(&mut entry.service);
+            let mut line = match serde_json::to_string(&entry) {
+                Ok(line) => line,
+                Err(e) => {
+                    warn!("Failed to serialize replayed log: {}", e);
+                    break;
+                }
+            };
+            line.push('\n');
+            if let Some(ticker) = ticker.as_mut() {
+                ticker.tick().await;
+            }
+            if tx.send(line).await.is_err() {
+                // Client went away
+                break;
+            }
+        }
+    });
+
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|line| (Ok::<_, Infallible>(line), rx))
+    });
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(stream),
+    )
+        .into_response())
+}
+
+/// Streams every log matching the filters as CSV, oldest first, with a header row.
+/// `metadata_cols` flattens the named metadata keys into columns of their own for
+/// spreadsheets; other keys are left out. Pagination params are ignored.
+#[utoipa::path(
+    get,
+    path = "/logs/export",
+    params(LogFilters, ExportParams),
+    responses(
+        (status = 200, description = "CSV of the matching logs, oldest first", content_type = "text/csv", body = String),
+        (status = 400, description = "Invalid timezone or too many metadata columns", body = ErrorBody),
+        (status = 503, description = "Too many exports in progress", body = ErrorBody),
+    )
+)]
+async fn export_logs(
+    State(state): State<AppState>,
+    Filters(filters): Filters,
+    Query(params): Query<ExportParams>,
+) -> Result<Response, ApiError> {
+    check_time_window(&filters)?;
+    let tz = match &filters.tz {
+        Some(name) => Some(
+            name.parse::<Tz>()
+                .map_err(|_| ApiError::bad_request(format!("unknown timezone '{}'", name)))?,
+        ),
+        None => None,
+    };
+    let metadata_cols = params.metadata_cols.as_deref().and_then(comma_list);
+    if metadata_cols.as_ref().is_some_and(|cols| cols.len() > 100) {
+        return Err(ApiError::bad_request("metadata_cols takes at most 100 keys"));
+    }
+    let permit = export_permit(&state).await?;
+
+    let mut header_row = vec!["id", "timestamp", "service", "level", "message"];
+    match &metadata_cols {
+        Some(cols) => header_row.extend(cols.iter().map(String::as_str)),
+        None => header_row.push("metadata"),
+    }
+    let header_row = csv_row(header_row);
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<String>(64);
+    tokio::spawn(async move {
+        // Held for the whole stream, which keeps its cursor open until it finishes
+        let _permit = permit;
+        let mut query = QueryBuilder::new("SELECT id, timestamp, service, level, message, message_gzip, metadata, created_at, seq");
+        for (i, key) in metadata_cols.iter().flatten().enumerate() {
+            query.push(", metadata ->> ").push_bind(key.clone()).push(format!(" AS meta_{}", i));
+        }
+        query.push(" FROM logs");
+        push_filters(&mut query, &filters, &state.service_aliases);
+        query.push(" ORDER BY timestamp ASC, id ASC");
+        let mut rows = query.build().fetch(&state.pool);
+
+        if tx.send(header_row).await.is_err() {
+            return;
+        }
+        loop {
+            let row = match rows.try_next().await {
+                Ok(Some(row)) => row,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Log export aborted: {}", e);
+                    break;
+                }
+            };
+            let mut entry = match tz {
+                Some(tz) => log_from_row(&row).in_timezone(tz),
+                None => log_from_row(&row),
+            };
+            state.service_aliases.canonicalize(&mut entry.service);
+
+            let mut fields = vec![
+                entry.id.map(|id| id.to_string()).unwrap_or_default(),
+                entry.timestamp.map(|t| t.to_rfc3339()).unwrap_or_default(),
+                entry.service,
+                entry.level,
+                entry.message,
+            ];
+            match &metadata_cols {
+                Some(cols) => fields.extend(
+                    (0..cols.len()).map(|i| row.get::<Option<String>, _>(format!("meta_{}", i).as_str()).unwrap_or_default()),
+                ),
+                None => fields.push(entry.metadata.map(|m| m.to_string()).unwrap_or_default()),
+            }
+            if tx.send(csv_row(fields)).await.is_err() {
+                // Client went away
+                break;
+            }
+        }
+    });
+
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|line| (Ok::<_, Infallible>(line), rx))
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"logs.csv\""),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response())
+}
+
+/// One RFC 4180 line: fields containing a comma, quote or line break are quoted, with
+/// inner quotes doubled. A field a spreadsheet would run as a formula, one starting with
+/// `=`, `+`, `-`, `@`, a tab or a carriage return, gets a leading `'` so it stays text.
+fn csv_row<S: AsRef<str>>(fields: impl IntoIterator<Item = S>) -> String {
+    let mut line = String::new();
+    for (i, field) in fields.into_iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        let field = field.as_ref();
+        let escaped;
+        let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+            escaped = format!("'{}", field);
+            escaped.as_str()
+        } else {
+            field
+        };
+        if field.contains([',', '"', '\n', '\r']) {
+            line.push('"');
+            line.push_str(&field.replace('"', "\"\""));
+            line.push('"');
+        } else {
+            line.push_str(field);
+        }
+    }
+    line.push_str("\r\n");
+    line
+}
+
+/// The logs immediately around `id` in `(timestamp, id)` order, for seeing what led up
+/// to an event and what followed it.
+#[utoipa::path(
+    get,
+    path = "/logs/context/{id}",
+    params(("id" = Uuid, Path, description = "The target log"), ContextParams, PrettyParam),
+    responses(
+        (status = 200, description = "The target log with its neighbours", body = LogContext),
+        (status = 404, description = "No log with this id", body = ErrorBody),
+    )
+)]
+async fn get_log_context(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Path(id): Path<Uuid>,
+    Query(params): Query<ContextParams>,
+) -> Result<Response, ApiError> {
+    let fetch_failed = |e| read_failed(&state, "fetch log context", &params, e);
+
+    let query = sqlx::query(
+        "SELECT id, timestamp, service, level, message, message_gzip, metadata, created_at, seq FROM logs WHERE id = $1",
+    )
+        .bind(id)
+        .fetch_optional(&state.pool);
+    let target = timed(&state, "GET /logs/context/{id}", &params, query)
+        .await
+        .map_err(fetch_failed)?
+        .map(|row| log_from_row(&row))
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("no log with id {}", id)))?;
+
+    let service = params.same_service.unwrap_or(false).then_some(&target.service);
+    let before_limit = params.before.unwrap_or(20).clamp(0, 500);
+    let after_limit = params.after.unwrap_or(20).clamp(0, 500);
+
+    let query = sqlx::query(
+        r#"
+        SELECT id, timestamp, service, level, message, message_gzip, metadata, created_at, seq FROM logs
+        WHERE (timestamp, id) < ($1, $2) AND ($3::text IS NULL OR service = $3)
+        ORDER BY timestamp DESC, id DESC
+        LIMIT $4
+        "#
+    )
+        .bind(target.timestamp)
+        .bind(id)
+        .bind(service)
+        .bind(before_limit)
+        .fetch_all(&state.pool);
+    let before = timed(&state, "GET /logs/context/{id}", &params, query)
+        .await
+        .map_err(fetch_failed)?;
+
+    let query = sqlx::query(
+        r#"
+        SELECT id, timestamp, service, level, message, message_gzip, metadata, created_at, seq FROM logs
+        WHERE (timestamp, id) > ($1, $2) AND ($3::text IS NULL OR service = $3)
+        ORDER BY timestamp ASC, id ASC
+        LIMIT $4
+        "#
+    )
+        .bind(target.timestamp)
+        .bind(id)
+        .bind(service)
+        .bind(after_limit)
+        .fetch_all(&state.pool);
+    let after = timed(&state, "GET /logs/context/{id}", &params, query)
+        .await
+        .map_err(fetch_failed)?;
+
+    Ok(format.respond(&LogContext {
+        before: before.iter().rev().map(log_from_row).collect(),
+        after: after.iter().map(log_from_row).collect(),
+        target,
+    }))
+}
+
+/// Publishes a stored log again, for exercising tail and Kafka consumers with a known
+/// event. By default the stored log itself is re-broadcast as it is and nothing is written.
+/// With `insert=true` it goes through ingestion again as a new log, with a new id and
+/// timestamp and `replay_of` set to the original's id in its metadata; it is then stored,
+/// counted against its service's quota and broadcast like any other.
+#[utoipa::path(
+    post,
+    path = "/logs/{id}/replay",
+    params(("id" = Uuid, Path, description = "The log to replay"), ReplayLogParams),
+    responses(
+        (status = 200, description = "The broadcast log: the original, or with `insert=true` the newly stored one", body = LogEntry),
+        (status = 202, description = "With `insert=true`, not stored: `queued` for retry or `dropped` by load shedding", body = QueuedResponse),
+        (status = 404, description = "No log with this id", body = ErrorBody),
+        (status = 429, description = "With `insert=true`, the service exceeded its daily quota", body = ErrorBody),
+        (status = 503, description = "With `insert=true`, maintenance mode or a full retry queue", body = ErrorBody),
+    )
+)]
+async fn replay_log(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Query(params): Query<ReplayLogParams>,
+) -> Result<Response, ApiError> {
+    let statement = format!("SELECT {} FROM logs WHERE id = $1", query::LOG_COLUMNS);
+    let query = sqlx::query(&statement).bind(id).fetch_optional(&state.pool);
+    let mut log = timed(&state, "POST /logs/{id}/replay", &(), query)
+        .await
+        .map_err(|e| read_failed(&state, "fetch log to replay", &(), e))?
+        .map(|row| log_from_row(&row))
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("no log with id {}", id)))?;
+
+    if !params.insert.unwrap_or(false) {
+        state.service_aliases.canonicalize(&mut log.service);
+        announce(&state, &log);
+        info!("Re-broadcast log {}", id);
+        return Ok(Json(log).into_response());
+    }
+
+    let mut metadata = match log.metadata {
+        Some(Value::Object(map)) => map,
+        _ => serde_json::Map::new(),
+    };
+    metadata.insert("replay_of".to_string(), Value::String(id.to_string()));
+    let replay = LogEntry {
+        id: None,
+        timestamp: None,
+        metadata: Some(Value::Object(metadata)),
+        created_at: None,
+        seq: None,
+        ..log
+    };
+    let ingested = store_log(&state, replay, Durability::Sync).await?;
+    if let Ingested::Stored(stored) = &ingested {
+        info!("Replayed log {} as new log {:?}", id, stored.id);
+    }
+    Ok(ingested.into_response())
+}
+
+/// Recomputes a log's signature to check that its service, level, message and timestamp
+/// are unchanged since it was ingested. See `SIGNING_KEY` for what this does not prove.
+#[utoipa::path(
+    get,
+    path = "/logs/{id}/verify",
+    params(("id" = Uuid, Path, description = "The log to verify"), PrettyParam),
+    responses(
+        (status = 200, description = "Whether the stored signature still matches", body = LogVerification),
+        (status = 404, description = "No log with this id, or signing is not enabled", body = ErrorBody),
+    )
+)]
+async fn verify_log(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Path(id): Path<Uuid>,
+) -> Result<Response, ApiError> {
+    let Some(signer) = &state.signer else {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "log signing is not enabled; set SIGNING_KEY"));
     };
+    let query = sqlx::query(
+        "SELECT timestamp, service, level, message, message_gzip, signature FROM logs WHERE id = $1",
+    )
+        .bind(id)
+        .fetch_optional(&state.pool);
+    let row = timed(&state, "GET /logs/{id}/verify", &(), query)
+        .await
+        .map_err(|e| read_failed(&state, "verify log", &(), e))?
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("no log with id {}", id)))?;
 
-    info!("Created log entry: {} - {} - {}", response.service, response.level, response.message);
-    Ok(Json(response))
+    let status = match row.get::<Option<String>, _>("signature") {
+        None => "unsigned",
+        Some(signature) => {
+            let service: String = row.get("service");
+            let level: String = row.get("level");
+            let message = message_from_row(&row);
+            if signer.verify(&service, &level, &message, row.get("timestamp"), &signature) {
+                "valid"
+            } else {
+                warn!("Log {} failed signature verification", id);
+                "invalid"
+            }
+        }
+    };
+    Ok(format.respond(&LogVerification { id, status }))
 }
 
-async fn get_logs(
+/// Resolves many log ids in one query, e.g. for hydrating a list of bookmarks.
+#[utoipa::path(
+    get,
+    path = "/logs/batch-get",
+    params(BatchGetParams, PrettyParam),
+    responses(
+        (status = 200, description = "The requested logs and the ids that were not found", body = BatchGetResponse),
+        (status = 400, description = "Malformed ids, or more than MAX_BATCH_GET_IDS of them", body = ErrorBody),
+    )
+)]
+async fn batch_get_logs(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Query(params): Query<BatchGetParams>,
+) -> Result<Response, ApiError> {
+    let mut ids: Vec<Uuid> = Vec::new();
+    let mut errors = Vec::new();
+    for raw in comma_list(&params.ids).unwrap_or_default() {
+        match Uuid::parse_str(&raw) {
+            // Repeated ids are answered once, at their first position
+            Ok(id) if !ids.contains(&id) => ids.push(id),
+            Ok(_) => {}
+            Err(_) => errors.push(FieldError::new("ids", format!("'{}' is not a valid log id", raw))),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::validation(errors));
+    }
+    if ids.is_empty() {
+        return Err(ApiError::bad_request("ids must list at least one log id"));
+    }
+    if ids.len() > state.max_batch_get_ids {
+        return Err(ApiError::bad_request(format!(
+            "at most {} ids may be requested at once, got {}",
+            state.max_batch_get_ids,
+            ids.len()
+        )));
+    }
+
+    let query = sqlx::query(
+        "SELECT id, timestamp, service, level, message, message_gzip, metadata, created_at, seq FROM logs WHERE id = ANY($1)",
+    )
+        .bind(&ids)
+        .fetch_all(&state.pool);
+    let rows = timed(&state, "GET /logs/batch-get", &params, query)
+        .await
+        .map_err(|e| read_failed(&state, "fetch logs by id", &params, e))?;
+
+    let mut found: HashMap<Uuid, LogEntry> = rows
+        .iter()
+        .map(log_from_row)
+        .filter_map(|entry| Some((entry.id?, entry)))
+        .collect();
+    let mut logs = Vec::with_capacity(found.len());
+    let mut missing = Vec::new();
+    for id in ids {
+        match found.remove(&id) {
+            Some(entry) => logs.push(entry),
+            None => missing.push(id),
+        }
+    }
+
+    Ok(format.respond(&BatchGetResponse { logs, missing }))
+}
+
+/// Per service: how many logs it has in the window and the most recent of them.
+#[utoipa::path(
+    get,
+    path = "/logs/summary",
+    params(TimeWindow, PrettyParam),
+    responses(
+        (status = 200, description = "One entry per service, by service name", body = [ServiceSummary]),
+        (status = 503, description = "Too many expensive queries in progress", body = ErrorBody),
+    )
+)]
+async fn get_log_summary(
     State(state): State<AppState>,
-    Query(filters): Query<LogFilters>,
-) -> Result<Json<LogResponse>, StatusCode> {
-    let limit = filters.limit.unwrap_or(100).min(1000);
-    let offset = filters.offset.unwrap_or(0);
+    format: ResponseFormat,
+    Query(window): Query<TimeWindow>,
+) -> Result<Response, ApiError> {
+    let _permit = expensive_read_permit(&state).await?;
+    let query = sqlx::query(
+        r#"
+        SELECT c.service, c.count, l.id, l.timestamp, l.level, l.message, l.message_gzip, l.metadata, l.created_at, l.seq
+        FROM (
+            SELECT service, COUNT(*) AS count FROM logs
+            WHERE ($1::timestamptz IS NULL OR timestamp >= $1)
+              AND ($2::timestamptz IS NULL OR timestamp < $2)
+            GROUP BY service
+        ) c
+        CROSS JOIN LATERAL (
+            SELECT id, timestamp, level, message, message_gzip, metadata, created_at, seq FROM logs
+            WHERE service = c.service
+              AND ($1::timestamptz IS NULL OR timestamp >= $1)
+              AND ($2::timestamptz IS NULL OR timestamp < $2)
+            ORDER BY timestamp DESC, id DESC
+            LIMIT 1
+        ) l
+        ORDER BY c.service
+        "#
+    )
+        .bind(window.from)
+        .bind(window.to)
+        .fetch_all(&state.pool);
+    let rows = timed(&state, "GET /logs/summary", &window, query)
+        .await
+        .map_err(|e| read_failed(&state, "summarize logs", &window, e))?;
+
+    // Aliases of one service are merged into a single entry under its canonical name
+    let mut summaries: Vec<ServiceSummary> = Vec::with_capacity(rows.len());
+    for row in &rows {
+        let mut latest_log = log_from_row(row);
+        state.service_aliases.canonicalize(&mut latest_log.service);
+        let count: i64 = row.get("count");
+        match summaries.iter_mut().find(|s| s.service == latest_log.service) {
+            Some(summary) => {
+                summary.count += count;
+                if latest_log.timestamp > summary.latest_log.timestamp {
+                    summary.latest_log = latest_log;
+                }
+            }
+            None => summaries.push(ServiceSummary {
+                service: latest_log.service.clone(),
+                count,
+                latest_log,
+            }),
+        }
+    }
+    summaries.sort_by(|a, b| a.service.cmp(&b.service));
+
+    Ok(format.respond(&summaries))
+}
 
-    let mut query = "SELECT id, timestamp, service, level, message, metadata, created_at FROM logs".to_string();
-    let mut conditions = Vec::new();
-    let mut param_count = 0;
+/// Serves the cached metrics snapshot; `fresh=true` (or an empty cache) forces a live
+/// recompute, which also refreshes the cache. Responds `304` when `If-None-Match` names
+/// the current snapshot's `ETag`.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    params(MetricsParams, PrettyParam),
+    responses(
+        (status = 200, description = "Log totals by service and level", body = MetricsResponse),
+        (status = 304, description = "Metrics unchanged since the `If-None-Match` ETag"),
+        (status = 503, description = "Too many expensive queries in progress", body = ErrorBody),
+        (status = 504, description = "Every metrics query timed out", body = ErrorBody)
+    )
+)]
+async fn get_metrics(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Query(params): Query<MetricsParams>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let snapshot = state.metrics_cache.read().await.clone();
+    let cached = match snapshot {
+        Some(cached) if !params.fresh.unwrap_or(false) => cached,
+        _ => {
+            let _permit = expensive_read_permit(&state).await?;
+            let metrics = compute_metrics(&state)
+                .await
+                .map_err(|e| read_failed(&state, "compute metrics", &params, e))?;
+            if metrics.unavailable.iter().any(|part| part == "total_logs") {
+                return Err(ApiError::new(StatusCode::GATEWAY_TIMEOUT, "metrics queries timed out"));
+            }
+            let cached = CachedMetrics::new(metrics);
+            // A partial snapshot is served but never replaces the cached one
+            if !cached.metrics.partial {
+                *state.metrics_cache.write().await = Some(cached.clone());
+            }
+            cached
+        }
+    };
 
-    if let Some(service) = &filters.service {
-        param_count += 1;
-        conditions.push(format!("service = ${}", param_count));
+    let age = (Utc::now() - cached.metrics.computed_at).num_seconds().max(0);
+    let cache_headers = [(header::ETAG, cached.etag.clone()), (header::AGE, age.to_string())];
+    let unchanged = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| cached.matches(v));
+    if unchanged {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
     }
 
-    if let Some(level) = &filters.level {
-        param_count += 1;
-        conditions.push(format!("level = ${}", param_count));
+    Ok((cache_headers, format.respond(&cached.metrics)).into_response())
+}
+
+/// Runs the three aggregate queries concurrently, each under `METRICS_QUERY_TIMEOUT_MS`.
+/// A query that runs out of time is left out and listed in `unavailable` instead of
+/// failing the whole computation; only database errors do that.
+async fn compute_metrics(state: &AppState) -> Result<MetricsResponse, sqlx::Error> {
+    let pool = &state.pool;
+    let timeout = state.metrics_query_timeout;
+    let (total_logs, service_rows, level_rows) = tokio::join!(
+        within(pool, timeout, "total logs", "SELECT COUNT(*) AS count FROM logs"),
+        within(pool, timeout, "service metrics", "SELECT service, COUNT(*) as count FROM logs GROUP BY service"),
+        within(pool, timeout, "level metrics", "SELECT level, COUNT(*) as count FROM logs GROUP BY level"),
+    );
+    let total_logs = total_logs
+        .inspect_err(|e| warn!("Failed to count total logs: {}", e))?
+        .map(|rows| rows.first().map_or(0, |row| row.get::<i64, _>("count")));
+    let service_rows = service_rows.inspect_err(|e| warn!("Failed to fetch service metrics: {}", e))?;
+    let level_rows = level_rows.inspect_err(|e| warn!("Failed to fetch level metrics: {}", e))?;
+
+    let mut unavailable = Vec::new();
+    let mut services = HashMap::new();
+    match &service_rows {
+        Some(rows) => {
+            for row in rows {
+                let service: String = row.get("service");
+                let count: i64 = row.get("count");
+                *services.entry(state.service_aliases.canonical(&service).to_string()).or_insert(0) += count;
+            }
+        }
+        None => unavailable.push("services".to_string()),
     }
 
-    if !conditions.is_empty() {
-        query.push_str(" WHERE ");
-        query.push_str(&conditions.join(" AND "));
+    let mut levels = HashMap::new();
+    match &level_rows {
+        Some(rows) => {
+            for row in rows {
+                let level: String = row.get("level");
+                let count: i64 = row.get("count");
+                levels.insert(level, count);
+            }
+        }
+        None => unavailable.push("levels".to_string()),
     }
 
-    query.push_str(" ORDER BY timestamp DESC");
-    param_count += 1;
-    query.push_str(&format!(" LIMIT ${}", param_count));
-    param_count += 1;
-    query.push_str(&format!(" OFFSET ${}", param_count));
+    // Either breakdown adds up to the total, so a slow COUNT(*) can be made up for
+    let total_logs = match total_logs {
+        Some(total) => total,
+        None if service_rows.is_some() => services.values().sum(),
+        None if level_rows.is_some() => levels.values().sum(),
+        None => {
+            unavailable.push("total_logs".to_string());
+            0
+        }
+    };
 
-    // Build the actual query based on filters
-    let rows = if let (Some(service), Some(level)) = (&filters.service, &filters.level) {
-        sqlx::query(&query)
-            .bind(service)
-            .bind(level)
-            .bind(limit)
-            .bind(offset)
-            .fetch_all(&state.pool)
-            .await
-    } else if let Some(service) = &filters.service {
-        sqlx::query(&query)
-            .bind(service)
-            .bind(limit)
-            .bind(offset)
-            .fetch_all(&state.pool)
-            .await
-    } else if let Some(level) = &filters.level {
-        sqlx::query(&query)
-            .bind(level)
-            .bind(limit)
-            .bind(offset)
-            .fetch_all(&state.pool)
-            .await
+    let error_rate = if level_rows.is_none() {
+        unavailable.push("error_rate".to_string());
+        0.0
+    } else if total_logs > 0 {
+        let errors: i64 = state.error_levels.iter().filter_map(|level| levels.get(level)).sum();
+        errors as f64 / total_logs as f64
     } else {
-        sqlx::query(&query)
-            .bind(limit)
-            .bind(offset)
-            .fetch_all(&state.pool)
-            .await
+        0.0
     };
 
-    let rows = rows.map_err(|e| {
-        warn!("Failed to fetch logs: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    Ok(MetricsResponse {
+        total_logs,
+        has_data: total_logs > 0,
+        error_rate,
+        services,
+        levels,
+        computed_at: Utc::now(),
+        partial: !unavailable.is_empty(),
+        unavailable,
+    })
+}
 
-    let logs: Vec<LogEntry> = rows
-        .into_iter()
-        .map(|row| LogEntry {
-            id: Some(row.get("id")),
-            timestamp: Some(row.get("timestamp")),
-            service: row.get("service"),
-            level: row.get("level"),
-            message: row.get("message"),
-            metadata: Some(row.get("metadata")),
-            created_at: Some(row.get("created_at")),
-        })
-        .collect();
+/// SQLSTATE of a statement cancelled by `statement_timeout`.
+const QUERY_CANCELED: &str = "57014";
 
-    // Get total count for pagination
-    let total_query = if !conditions.is_empty() {
-        let mut count_query = "SELECT COUNT(*) FROM logs WHERE ".to_string();
-        count_query.push_str(&conditions.join(" AND "));
-        count_query
-    } else {
-        "SELECT COUNT(*) FROM logs".to_string()
+/// Runs one metrics query, giving up after `timeout`: `Ok(None)` means it ran out of time.
+/// The limit is a `statement_timeout` on the query's transaction, so Postgres stops working
+/// on a query that runs out of time instead of finishing it in the background.
+async fn within(
+    pool: &PgPool,
+    timeout: Option<Duration>,
+    what: &str,
+    sql: &str,
+) -> Result<Option<Vec<PgRow>>, sqlx::Error> {
+    let Some(timeout) = timeout else {
+        return sqlx::query(sql).fetch_all(pool).await.map(Some);
     };
+    // The request's own deadline may be sooner; it cancels the query as well
+    let limit = deadline::remaining().map_or(timeout, |remaining| remaining.min(timeout));
+    let mut tx = pool.begin().await?;
+    sqlx::query("SELECT set_config('statement_timeout', $1, true)")
+        .bind(limit.as_millis().max(1).to_string())
+        .execute(&mut *tx)
+        .await?;
+    match sqlx::query(sql).fetch_all(&mut *tx).await {
+        Ok(rows) => {
+            tx.commit().await?;
+            Ok(Some(rows))
+        }
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some(QUERY_CANCELED) => {
+            warn!("Query for {} timed out after {:?}; leaving it out of the metrics", what, limit);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
 
-    let total: i64 = if let (Some(service), Some(level)) = (&filters.service, &filters.level) {
-        sqlx::query_scalar(&total_query)
-            .bind(service)
-            .bind(level)
-            .fetch_one(&state.pool)
-            .await
-    } else if let Some(service) = &filters.service {
-        sqlx::query_scalar(&total_query)
-            .bind(service)
-            .fetch_one(&state.pool)
-            .await
-    } else if let Some(level) = &filters.level {
-        sqlx::query_scalar(&total_query)
-            .bind(level)
-            .fetch_one(&state.pool)
-            .await
-    } else {
-        sqlx::query_scalar(&total_query)
+/// Measures the logs table every `interval` for the `MAX_TABLE_BYTES` guard, so
+/// ingestion doesn't query the catalog on every insert.
+async fn refresh_table_size(state: AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        // The partitioned parent has no storage of its own; its partitions hold everything
+        let size = sqlx::query_scalar::<_, i64>(
+            "SELECT COALESCE(SUM(pg_total_relation_size(relid)), 0)::bigint FROM pg_partition_tree('logs')",
+        )
             .fetch_one(&state.pool)
-            .await
-    }.map_err(|e| {
-        warn!("Failed to count logs: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+            .await;
+        match size {
+            Ok(bytes) => state.table_bytes.store(bytes.max(0) as u64, Ordering::Relaxed),
+            // Keep the last measurement rather than guessing
+            Err(e) => warn!("Failed to measure logs table size: {}", e),
+        }
+    }
+}
 
-    Ok(Json(LogResponse {
-        logs,
-        total,
-    }))
+/// Recomputes the metrics cache every `interval` so `/metrics` rarely hits the table.
+async fn refresh_metrics_cache(state: AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match compute_metrics(&state).await {
+            Ok(metrics) if !metrics.partial => *state.metrics_cache.write().await = Some(CachedMetrics::new(metrics)),
+            // Keep serving the last complete snapshot
+            _ => {}
+        }
+    }
 }
 
-async fn get_metrics(
+/// Log counts by hour of the day, always all 24 hours with zeroes filled in.
+#[utoipa::path(
+    get,
+    path = "/metrics/hourly-distribution",
+    params(HourlyParams, PrettyParam),
+    responses(
+        (status = 200, description = "24 buckets, hour 0 first", body = [HourBucket]),
+        (status = 400, description = "Unknown timezone", body = ErrorBody),
+        (status = 503, description = "Too many expensive queries in progress", body = ErrorBody),
+    )
+)]
+async fn get_hourly_distribution(
     State(state): State<AppState>,
-) -> Result<Json<MetricsResponse>, StatusCode> {
-    // Get total logs count
-    let total_logs: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM logs")
-        .fetch_one(&state.pool)
+    format: ResponseFormat,
+    Query(params): Query<HourlyParams>,
+) -> Result<Response, ApiError> {
+    let tz = match &params.tz {
+        Some(name) => name
+            .parse::<Tz>()
+            .map_err(|_| ApiError::bad_request(format!("unknown timezone '{}'", name)))?,
+        None => Tz::UTC,
+    };
+
+    let _permit = expensive_read_permit(&state).await?;
+    let query = sqlx::query_as(
+        r#"
+        SELECT EXTRACT(HOUR FROM timestamp AT TIME ZONE $1)::int AS hour, COUNT(*)
+        FROM logs
+        WHERE ($2::timestamptz IS NULL OR timestamp >= $2)
+          AND ($3::timestamptz IS NULL OR timestamp < $3)
+          AND ($4::text IS NULL OR service = $4)
+        GROUP BY 1
+        "#
+    )
+        .bind(tz.name())
+        .bind(params.from)
+        .bind(params.to)
+        .bind(&params.service)
+        .fetch_all(&state.pool);
+    let rows: Vec<(i32, i64)> = timed(&state, "GET /metrics/hourly-distribution", &params, query)
         .await
-        .map_err(|e| {
-            warn!("Failed to count total logs: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        .map_err(|e| read_failed(&state, "compute hourly distribution", &params, e))?;
 
-    // Get logs by service
-    let service_rows = sqlx::query("SELECT service, COUNT(*) as count FROM logs GROUP BY service")
-        .fetch_all(&state.pool)
+    let mut buckets: Vec<HourBucket> = (0..24).map(|hour| HourBucket { hour, count: 0 }).collect();
+    for (hour, count) in rows {
+        if let Some(bucket) = buckets.get_mut(hour as usize) {
+            bucket.count = count;
+        }
+    }
+
+    Ok(format.respond(&buckets))
+}
+
+/// How many distinct values a metadata key takes, to spot keys with unexpectedly high
+/// cardinality.
+#[utoipa::path(
+    get,
+    path = "/metrics/metadata/{key}/cardinality",
+    params(("key" = String, Path, description = "Top-level metadata key"), TimeWindow, PrettyParam),
+    responses(
+        (status = 200, description = "`{\"key\", \"distinct_values\", \"logs_with_key\"}`", body = Object),
+        (status = 503, description = "Too many expensive queries in progress", body = ErrorBody),
+    )
+)]
+async fn get_metadata_cardinality(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Path(key): Path<String>,
+    Query(window): Query<TimeWindow>,
+) -> Result<Response, ApiError> {
+    let _permit = expensive_read_permit(&state).await?;
+    let query = sqlx::query_as(
+        r#"
+        SELECT COUNT(DISTINCT metadata ->> $1), COUNT(*) FROM logs
+        WHERE metadata ? $1
+          AND ($2::timestamptz IS NULL OR timestamp >= $2)
+          AND ($3::timestamptz IS NULL OR timestamp < $3)
+        "#
+    )
+        .bind(&key)
+        .bind(window.from)
+        .bind(window.to)
+        .fetch_one(&state.pool);
+    let (distinct_values, logs_with_key): (i64, i64) = timed(&state, "GET /metrics/metadata/{key}/cardinality", &window, query)
         .await
-        .map_err(|e| {
-            warn!("Failed to fetch service metrics: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        .map_err(|e| read_failed(&state, "count metadata cardinality", &window, e))?;
 
-    let mut services = HashMap::new();
-    for row in service_rows {
-        let service: String = row.get("service");
-        let count: i64 = row.get("count");
-        services.insert(service, count);
+    Ok(format.respond(&serde_json::json!({
+        "key": key,
+        "distinct_values": distinct_values,
+        "logs_with_key": logs_with_key,
+    })))
+}
+
+/// Log counts per value of one metadata key. Only keys listed in `GROUP_BY_KEYS` may be
+/// grouped, since every distinct value becomes an entry in the response.
+#[utoipa::path(
+    get,
+    path = "/metrics/group-by",
+    params(GroupByParams, PrettyParam),
+    responses(
+        (status = 200, description = "Counts per value of the key", body = GroupByResponse),
+        (status = 400, description = "The key is not in GROUP_BY_KEYS", body = ErrorBody),
+        (status = 503, description = "Too many expensive queries in progress", body = ErrorBody),
+    )
+)]
+async fn get_group_by(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Query(params): Query<GroupByParams>,
+) -> Result<Response, ApiError> {
+    if !state.group_by_keys.contains(&params.key) {
+        return Err(ApiError::bad_request(if state.group_by_keys.is_empty() {
+            "no metadata keys are enabled for grouping (see GROUP_BY_KEYS)".to_string()
+        } else {
+            format!(
+                "metadata key '{}' cannot be grouped by; allowed keys: {}",
+                params.key,
+                state.group_by_keys.join(", ")
+            )
+        }));
     }
 
-    // Get logs by level
-    let level_rows = sqlx::query("SELECT level, COUNT(*) as count FROM logs GROUP BY level")
-        .fetch_all(&state.pool)
+    let _permit = expensive_read_permit(&state).await?;
+    let query = sqlx::query_as(
+        r#"
+        SELECT metadata ->> $1 AS value, COUNT(*) FROM logs
+        WHERE ($2::timestamptz IS NULL OR timestamp >= $2)
+          AND ($3::timestamptz IS NULL OR timestamp < $3)
+        GROUP BY value
+        "#
+    )
+        .bind(&params.key)
+        .bind(params.from)
+        .bind(params.to)
+        .fetch_all(&state.pool);
+    let rows: Vec<(Option<String>, i64)> = timed(&state, "GET /metrics/group-by", &params, query)
         .await
-        .map_err(|e| {
-            warn!("Failed to fetch level metrics: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        .map_err(|e| read_failed(&state, "group logs by metadata key", &params, e))?;
+
+    let mut counts = HashMap::new();
+    let mut null = 0;
+    for (value, count) in rows {
+        match value {
+            Some(value) => {
+                counts.insert(value, count);
+            }
+            None => null = count,
+        }
+    }
+
+    Ok(format.respond(&GroupByResponse {
+        key: params.key,
+        counts,
+        null,
+    }))
+}
+
+/// Counts of the logs stored since `since`, by `created_at`, for monitors that poll for
+/// rates. Only the logs in the window are read, via the `created_at` index, instead of
+/// all-time totals.
+#[utoipa::path(
+    get,
+    path = "/metrics/delta",
+    params(DeltaParams, PrettyParam),
+    responses(
+        (status = 200, description = "Counts of logs stored after `since`", body = MetricsDelta),
+        (status = 400, description = "Missing or invalid `since`", body = ErrorBody),
+    )
+)]
+async fn get_metrics_delta(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Query(params): Query<DeltaParams>,
+) -> Result<Response, ApiError> {
+    // Bounded above by the database clock, the same one that sets created_at, so
+    // consecutive polls neither overlap nor leave gaps
+    let delta = async {
+        let next_since: DateTime<Utc> = sqlx::query_scalar("SELECT NOW()").fetch_one(&state.pool).await?;
+        let rows: Vec<(String, String, i64)> = sqlx::query_as(
+            r#"
+            SELECT service, level, COUNT(*) FROM logs
+            WHERE created_at > $1 AND created_at <= $2
+            GROUP BY service, level
+            "#
+        )
+            .bind(params.since)
+            .bind(next_since)
+            .fetch_all(&state.pool)
+            .await?;
+        Ok::<_, sqlx::Error>((next_since, rows))
+    };
+    let (next_since, rows) = timed(&state, "GET /metrics/delta", &params, delta)
+        .await
+        .map_err(|e| read_failed(&state, "count logs since", &params, e))?;
 
+    let mut total_logs = 0;
+    let mut services = HashMap::new();
     let mut levels = HashMap::new();
-    for row in level_rows {
-        let level: String = row.get("level");
-        let count: i64 = row.get("count");
-        levels.insert(level, count);
+    for (service, level, count) in rows {
+        total_logs += count;
+        *services.entry(state.service_aliases.canonical(&service).to_string()).or_insert(0) += count;
+        *levels.entry(level).or_insert(0) += count;
     }
 
-    Ok(Json(MetricsResponse {
+    Ok(format.respond(&MetricsDelta {
         total_logs,
         services,
         levels,
+        next_since,
     }))
-}
\ No newline at end of file
+}
+
+/// How long ago each service last logged, stalest first, to spot services that have
+/// gone quiet.
+#[utoipa::path(
+    get,
+    path = "/metrics/lag",
+    params(PrettyParam),
+    responses(
+        (status = 200, description = "Per-service age of the newest log, stalest first", body = [ServiceLag]),
+        (status = 503, description = "Too many expensive queries in progress", body = ErrorBody),
+    )
+)]
+async fn get_metrics_lag(State(state): State<AppState>, format: ResponseFormat) -> Result<Response, ApiError> {
+    let _permit = expensive_read_permit(&state).await?;
+    let query = sqlx::query_as(
+        r#"
+        SELECT service, MAX(timestamp) AS last_seen,
+               EXTRACT(EPOCH FROM NOW() - MAX(timestamp))::float8 AS lag_seconds
+        FROM logs
+        GROUP BY service
+        "#
+    )
+        .fetch_all(&state.pool);
+    let rows: Vec<(String, DateTime<Utc>, f64)> = timed(&state, "GET /metrics/lag", &(), query)
+        .await
+        .map_err(|e| read_failed(&state, "compute service lag", &(), e))?;
+
+    // Aliases report under their canonical name, which is as fresh as its freshest alias
+    let mut lags: HashMap<String, ServiceLag> = HashMap::new();
+    for (service, last_seen, lag_seconds) in rows {
+        let service = state.service_aliases.canonical(&service).to_string();
+        match lags.get_mut(&service) {
+            Some(lag) if lag.last_seen >= last_seen => {}
+            Some(lag) => {
+                lag.last_seen = last_seen;
+                lag.lag_seconds = lag_seconds;
+            }
+            None => {
+                lags.insert(service.clone(), ServiceLag { service, last_seen, lag_seconds });
+            }
+        }
+    }
+    let mut lags: Vec<ServiceLag> = lags.into_values().collect();
+    lags.sort_by(|a, b| a.last_seen.cmp(&b.last_seen).then_with(|| a.service.cmp(&b.service)));
+
+    Ok(format.respond(&lags))
+}
+
+/// Stores a metrics snapshot every `interval`, so volume trends survive retention
+/// deletes and purges of the logs themselves.
+async fn record_metrics_history(state: AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        // A partial snapshot would show up in the history as a drop in volume
+        let metrics = match compute_metrics(&state).await {
+            Ok(metrics) if !metrics.partial => metrics,
+            _ => continue,
+        };
+        let stored = sqlx::query(
+            r#"
+            INSERT INTO metrics_history (taken_at, total_logs, error_rate, services, levels)
+            VALUES ($1, $2, $3, $4, $5)
+            "#
+        )
+            .bind(metrics.computed_at)
+            .bind(metrics.total_logs)
+            .bind(metrics.error_rate)
+            .bind(serde_json::to_value(&metrics.services).unwrap_or_default())
+            .bind(serde_json::to_value(&metrics.levels).unwrap_or_default())
+            .execute(&state.pool)
+            .await;
+        if let Err(e) = stored {
+            warn!("Failed to record metrics snapshot: {}", e);
+        }
+    }
+}
+
+/// Stored metrics snapshots in the window, oldest first.
+#[utoipa::path(
+    get,
+    path = "/metrics/history",
+    params(HistoryParams, PrettyParam),
+    responses((status = 200, description = "A page of metrics snapshots", body = HistoryResponse))
+)]
+async fn get_metrics_history(
+    State(state): State<AppState>,
+    format: ResponseFormat,
+    Query(params): Query<HistoryParams>,
+) -> Result<Response, ApiError> {
+    let query = sqlx::query(
+        r#"
+        SELECT taken_at, total_logs, error_rate, services, levels, COUNT(*) OVER () AS total
+        FROM metrics_history
+        WHERE ($1::timestamptz IS NULL OR taken_at >= $1)
+          AND ($2::timestamptz IS NULL OR taken_at < $2)
+        ORDER BY taken_at ASC, id ASC
+        LIMIT $3 OFFSET $4
+        "#
+    )
+        .bind(params.from)
+        .bind(params.to)
+        .bind(params.limit.unwrap_or(100).clamp(0, 1000))
+        .bind(params.offset.unwrap_or(0).max(0))
+        .fetch_all(&state.pool);
+    let rows = timed(&state, "GET /metrics/history", &params, query)
+        .await
+        .map_err(|e| read_failed(&state, "fetch metrics history", &params, e))?;
+
+    // The window total rides along on every row; an empty page needs its own count
+    let total = match rows.first() {
+        Some(row) => row.get("total"),
+        None => sqlx::query_scalar(
+            r#"
+            SELECT COUNT(*) FROM metrics_history
+            WHERE ($1::timestamptz IS NULL OR taken_at >= $1)
+              AND ($2::timestamptz IS NULL OR taken_at < $2)
+            "#
+        )
+            .bind(params.from)
+            .bind(params.to)
+            .fetch_one(&state.pool)
+            .await
+            .map_err(|e| read_failed(&state, "count metrics history", &params, e))?,
+    };
+
+    let snapshots = rows
+        .iter()
+        .map(|row| MetricsSnapshot {
+            taken_at: row.get("taken_at"),
+            total_logs: row.get("total_logs"),
+            error_rate: row.get("error_rate"),
+            services: row.get("services"),
+            levels: row.get("levels"),
+        })
+        .collect::<Vec<_>>();
+
+    Ok(format.respond(&HistoryResponse { snapshots, total }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics/prometheus",
+    responses((status = 200, description = "Prometheus text exposition", content_type = "text/plain", body = String))
+)]
+async fn get_prometheus_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let mut body = state.telemetry.render();
+    telemetry::render_gauge(
+        "tidelogs_tail_connections",
+        "Open /logs/tail streams.",
+        state.tail.active_connections() as u64,
+        &mut body,
+    );
+    if state.max_table_bytes.is_some() {
+        telemetry::render_gauge(
+            "tidelogs_table_bytes",
+            "Last measured size of the logs table in bytes.",
+            state.table_bytes.load(Ordering::Relaxed),
+            &mut body,
+        );
+    }
+    if let Some(queue) = &state.retry_queue {
+        telemetry::render_gauge(
+            "tidelogs_insert_retry_pending",
+            "Logs waiting in the insert retry queue.",
+            queue.pending() as u64,
+            &mut body,
+        );
+    }
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+async fn openapi_spec() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_level_aliases() -> HashMap<String, String> {
+        DEFAULT_LEVEL_ALIASES.iter().map(|(alias, level)| (alias.to_string(), level.to_string())).collect()
+    }
+
+    #[test]
+    fn normalize_level_uppercases_and_maps_aliases() {
+        let aliases = default_level_aliases();
+        assert_eq!(normalize_level("info", &aliases), "INFO");
+        assert_eq!(normalize_level("  Error ", &aliases), "ERROR");
+        assert_eq!(normalize_level("warning", &aliases), "WARN");
+        assert_eq!(normalize_level("Critical", &aliases), "ERROR");
+        assert_eq!(normalize_level("verbose", &aliases), "VERBOSE");
+    }
+
+    #[test]
+    fn normalize_level_strips_trailing_punctuation() {
+        let aliases = default_level_aliases();
+        assert_eq!(normalize_level("error!", &aliases), "ERROR");
+        assert_eq!(normalize_level("warn:", &aliases), "WARN");
+        assert_eq!(normalize_level("Warning: ", &aliases), "WARN");
+        assert_eq!(normalize_level("err!! ", &aliases), "ERROR");
+        assert_eq!(normalize_level("debug ...", &aliases), "DEBUG");
+    }
+
+    fn page(total: i64, per_page: i64) -> LogResponse {
+        LogResponse {
+            logs: Vec::new(),
+            total,
+            page: 1,
+            per_page,
+            total_pages: 0,
+            next_since: None,
+            next_since_id: None,
+            next_after_seq: None,
+            next_cursor: None,
+            cached: false,
+        }
+    }
+
+    /// The `Link` header for `GET /logs?<query>` answered with `response`.
+    fn links(query: &str, response: &LogResponse, window: Option<i64>) -> Option<String> {
+        let uri: Uri = format!("/logs?{}", query).parse().unwrap();
+        let filters: LogFilters = serde_urlencoded::from_str(query).unwrap();
+        let (_, offset) = filters.limit_offset().unwrap();
+        page_links(&uri, &filters, response, offset, window)
+    }
+
+    #[test]
+    fn page_links_with_limit_and_offset() {
+        let response = page(250, 100);
+        assert_eq!(
+            links("service=api&limit=100&offset=100", &response, None).unwrap(),
+            concat!(
+                "</logs?service=api&limit=100&offset=0>; rel=\"first\", ",
+                "</logs?service=api&limit=100&offset=0>; rel=\"prev\", ",
+                "</logs?service=api&limit=100&offset=200>; rel=\"next\"",
+            )
+        );
+        assert_eq!(
+            links("limit=100&offset=200", &response, None).unwrap(),
+            "</logs?limit=100&offset=0>; rel=\"first\", </logs?limit=100&offset=100>; rel=\"prev\""
+        );
+        assert_eq!(
+            links("limit=100&offset=30", &response, None).unwrap(),
+            concat!(
+                "</logs?limit=100&offset=0>; rel=\"first\", ",
+                "</logs?limit=100&offset=0>; rel=\"prev\", ",
+                "</logs?limit=100&offset=130>; rel=\"next\"",
+            )
+        );
+    }
+
+    #[test]
+    fn page_links_keep_the_page_form() {
+        assert_eq!(
+            links("level=ERROR&page=2&per_page=50", &page(120, 50), None).unwrap(),
+            concat!(
+                "</logs?level=ERROR&page=1&per_page=50>; rel=\"first\", ",
+                "</logs?level=ERROR&page=1&per_page=50>; rel=\"prev\", ",
+                "</logs?level=ERROR&page=3&per_page=50>; rel=\"next\"",
+            )
+        );
+    }
+
+    #[test]
+    fn page_links_stop_at_the_result_window() {
+        let header = links("limit=100&offset=800", &page(5000, 100), Some(1000)).unwrap();
+        assert!(header.contains("offset=900>; rel=\"next\""), "{}", header);
+        let header = links("limit=100&offset=900", &page(5000, 100), Some(1000)).unwrap();
+        assert!(!header.contains("rel=\"next\""), "{}", header);
+    }
+
+    #[test]
+    fn page_links_follow_cursors() {
+        let mut response = page(0, 100);
+        response.next_since = DateTime::parse_from_rfc3339("2024-01-15T10:30:00.5Z").ok();
+        response.next_since_id = "5f0c2a4e-8a4b-4d7e-9a55-1f3e5b7c9d01".parse().ok();
+        assert_eq!(
+            links("service=api&since=2024-01-01T00:00:00Z&limit=100", &response, None).unwrap(),
+            concat!(
+                "</logs?service=api&limit=100&since=2024-01-15T10%3A30%3A00.500Z",
+                "&since_id=5f0c2a4e-8a4b-4d7e-9a55-1f3e5b7c9d01>; rel=\"next\"",
+            )
+        );
+
+        let mut response = page(0, 10);
+        response.next_after_seq = Some(42);
+        assert_eq!(
+            links("after_seq=7&limit=10", &response, None).unwrap(),
+            "</logs?after_seq=42&limit=10>; rel=\"next\""
+        );
+
+        let mut response = page(0, 10);
+        response.next_cursor = Some("opaque".to_string());
+        assert_eq!(
+            links("after_seq=7&limit=10", &response, None).unwrap(),
+            "</logs?cursor=opaque&limit=10>; rel=\"next\""
+        );
+    }
+
+    #[test]
+    fn page_links_are_left_out_of_counts() {
+        assert_eq!(links("count_only=true", &page(10, 100), None), None);
+    }
+
+    #[test]
+    fn service_naming_none_keeps_names() {
+        assert_eq!(ServiceNaming::None.apply(" Billing_API "), " Billing_API ");
+    }
+
+    #[test]
+    fn service_naming_lower_collapses_whitespace() {
+        assert_eq!(ServiceNaming::Lower.apply("Billing  API"), "billing api");
+        assert_eq!(ServiceNaming::Lower.apply("\tBilling\n API "), "billing api");
+        assert_eq!(ServiceNaming::Lower.apply("billing_api"), "billing_api");
+    }
+
+    #[test]
+    fn service_naming_kebab_joins_words_with_dashes() {
+        assert_eq!(ServiceNaming::Kebab.apply("Billing_API "), "billing-api");
+        assert_eq!(ServiceNaming::Kebab.apply("billing  __ api--v2"), "billing-api-v2");
+        assert_eq!(ServiceNaming::Kebab.apply("-billing-"), "billing");
+        assert_eq!(ServiceNaming::Kebab.apply("Zürich Gateway"), "zürich-gateway");
+    }
+
+    #[test]
+    fn service_naming_can_empty_a_name() {
+        assert_eq!(ServiceNaming::Lower.apply("   "), "");
+        assert_eq!(ServiceNaming::Kebab.apply(" _-_ "), "");
+    }
+
+    #[test]
+    fn match_snippet_finds_the_term_ignoring_case() {
+        assert_eq!(match_snippet("Payment FAILED for order 7", "failed").as_deref(), Some("Payment FAILED for order 7"));
+        assert_eq!(match_snippet("Payment failed", "refund"), None);
+        assert_eq!(match_snippet("Payment failed", ""), None);
+    }
+
+    #[test]
+    fn match_snippet_cuts_long_messages_around_the_match() {
+        let message = format!("{}timeout{}", "a".repeat(100), "b".repeat(100));
+        let snippet = match_snippet(&message, "TIMEOUT").unwrap();
+        let context = "a".repeat(SNIPPET_CONTEXT);
+        assert_eq!(snippet, format!("…{}timeout{}…", context, "b".repeat(SNIPPET_CONTEXT)));
+
+        let start = format!("timeout{}", "b".repeat(100));
+        assert_eq!(match_snippet(&start, "timeout").unwrap(), format!("timeout{}…", "b".repeat(SNIPPET_CONTEXT)));
+    }
+
+    #[test]
+    fn match_snippet_counts_characters_not_bytes() {
+        let message = format!("{}İstanbul{}", "é".repeat(50), "ü".repeat(50));
+        let snippet = match_snippet(&message, "i\u{307}stanbul").unwrap();
+        assert_eq!(snippet, format!("…{}İstanbul{}…", "é".repeat(SNIPPET_CONTEXT), "ü".repeat(SNIPPET_CONTEXT)));
+        assert_eq!(match_snippet("İstanbul", "stan").as_deref(), Some("İstanbul"));
+    }
+}
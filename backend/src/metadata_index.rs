@@ -0,0 +1,111 @@
+//! The metadata keys that may be filtered on.
+//!
+//! `QUERYABLE_METADATA_KEYS` lists the top-level keys that `metadata.<key>=value` filters
+//! may name, e.g. `request_id,region`; filters on any other key are rejected, so no query
+//! falls back to scanning every row's JSONB. Each listed key gets an expression index on
+//! `metadata ->> '<key>'`. Keys are configuration rather than schema, so the indexes are
+//! created at startup instead of in a migration, in the background because building one
+//! over a large table takes a while. A plain `CREATE INDEX` on the partitioned table would
+//! block ingestion for the whole build, so the index is first created on the parent alone
+//! (`ON ONLY`), which is instant and leaves it invalid. Each partition's index is then
+//! built `CONCURRENTLY` and attached; once every partition has one, the parent index is
+//! valid and partitions created later get theirs automatically. An interrupted build is
+//! picked up on the next start. Indexes of keys removed from the list are left in place.
+
+use sqlx::PgPool;
+use tidelogs_backend::query::metadata_expression;
+use std::collections::HashSet;
+use tracing::{error, info, warn};
+
+/// Postgres truncates longer identifiers, which would make index names collide.
+const MAX_INDEX_NAME_LEN: usize = 63;
+
+/// Room left in an index name for the `_<oid>` suffix of its partitions' indexes.
+const PARTITION_SUFFIX_LEN: usize = 11;
+
+pub struct QueryableKeys {
+    keys: HashSet<String>,
+}
+
+impl QueryableKeys {
+    pub fn from_env() -> Self {
+        let mut keys = HashSet::new();
+        let spec = std::env::var("QUERYABLE_METADATA_KEYS").unwrap_or_default();
+        for key in spec.split(',').map(str::trim).filter(|k| !k.is_empty()) {
+            // Keys end up inside the index DDL, which takes no bind parameters
+            if key.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+                keys.insert(key.to_string());
+            } else {
+                warn!("Ignoring QUERYABLE_METADATA_KEYS entry '{}': only letters, digits, '_', '-' and '.' are allowed", key);
+            }
+        }
+        Self { keys }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// The allowed keys in a stable order, for error messages.
+    pub fn sorted(&self) -> Vec<&str> {
+        let mut keys: Vec<&str> = self.keys.iter().map(String::as_str).collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Creates the missing expression index of every allowed key.
+    pub async fn ensure_indexes(&self, pool: PgPool) {
+        for key in self.sorted() {
+            let sanitized: String = key.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+            let name = format!("logs_meta_{}_idx", sanitized.to_lowercase());
+            if name.len() + PARTITION_SUFFIX_LEN > MAX_INDEX_NAME_LEN {
+                warn!("Not indexing metadata key '{}': its index name would be too long", key);
+                continue;
+            }
+            match ensure_index(&pool, &name, &metadata_expression(key)).await {
+                Ok(()) => info!("Metadata key '{}' is indexed ({})", key, name),
+                Err(e) => error!("Failed to create index {} for metadata key '{}': {}", name, key, e),
+            }
+        }
+    }
+}
+
+/// Creates the partitioned index `name` on `expression` one partition at a time.
+async fn ensure_index(pool: &PgPool, name: &str, expression: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!("CREATE INDEX IF NOT EXISTS {} ON ONLY logs ({})", name, expression))
+        .execute(pool)
+        .await?;
+
+    // Partitions without an index attached to this one, by OID and name
+    let missing: Vec<(i64, String)> = sqlx::query_as(
+        r#"
+        SELECT t.relid::oid::bigint, t.relid::regclass::text
+        FROM pg_partition_tree('logs') t
+        WHERE t.isleaf
+          AND NOT EXISTS (
+              SELECT 1 FROM pg_inherits i
+              JOIN pg_index x ON x.indexrelid = i.inhrelid
+              WHERE i.inhparent = $1::regclass AND x.indrelid = t.relid
+          )
+        "#,
+    )
+        .bind(name)
+        .fetch_all(pool)
+        .await?;
+
+    for (oid, partition) in missing {
+        let partition_index = format!("{}_{}", name, oid);
+        // A build that was interrupted leaves an invalid index behind
+        sqlx::query(&format!("DROP INDEX CONCURRENTLY IF EXISTS {}", partition_index))
+            .execute(pool)
+            .await?;
+        sqlx::query(&format!("CREATE INDEX CONCURRENTLY {} ON {} ({})", partition_index, partition, expression))
+            .execute(pool)
+            .await?;
+        sqlx::query(&format!("ALTER INDEX {} ATTACH PARTITION {}", name, partition_index))
+            .execute(pool)
+            .await?;
+        info!("Built index {} on partition {}", partition_index, partition);
+    }
+    Ok(())
+}
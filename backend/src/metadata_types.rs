@@ -0,0 +1,104 @@
+//! Optional type schema for top-level metadata keys.
+//!
+//! `METADATA_SCHEMA_PATH` points at a JSON object mapping keys to one of `string`,
+//! `number`, `integer` or `boolean`, e.g. `{"count": "integer", "cached": "boolean"}`.
+//! By default values of the wrong type are coerced where that is lossless (`"5"` becomes
+//! `5`) and left untouched otherwise; with `METADATA_SCHEMA_STRICT` a mismatch rejects
+//! the log instead. Keys missing from the schema and `null` values are never checked.
+
+use serde::Deserialize;
+use serde_json::{Number, Value};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+}
+
+impl FieldType {
+    fn matches(self, value: &Value) -> bool {
+        match self {
+            Self::String => value.is_string(),
+            Self::Number => value.is_number(),
+            Self::Integer => value.is_i64() || value.is_u64(),
+            Self::Boolean => value.is_boolean(),
+        }
+    }
+
+    /// The value converted to this type, if it has an unambiguous representation in it.
+    fn coerce(self, value: &Value) -> Option<Value> {
+        match (self, value) {
+            (Self::String, Value::Number(n)) => Some(Value::String(n.to_string())),
+            (Self::String, Value::Bool(b)) => Some(Value::String(b.to_string())),
+            (Self::Integer, Value::String(s)) => s.trim().parse::<i64>().ok().map(Value::from),
+            (Self::Number, Value::String(s)) => {
+                let s = s.trim();
+                match s.parse::<i64>() {
+                    Ok(i) => Some(Value::from(i)),
+                    Err(_) => s.parse::<f64>().ok().and_then(Number::from_f64).map(Value::Number),
+                }
+            }
+            (Self::Boolean, Value::String(s)) => match s.trim().to_ascii_lowercase().as_str() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::String => "string",
+            Self::Number => "number",
+            Self::Integer => "integer",
+            Self::Boolean => "boolean",
+        }
+    }
+}
+
+pub struct MetadataSchema {
+    fields: HashMap<String, FieldType>,
+    strict: bool,
+}
+
+impl MetadataSchema {
+    pub fn load(path: &str, strict: bool) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read METADATA_SCHEMA_PATH '{}': {}", path, e))?;
+        let fields = serde_json::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("invalid metadata schema in '{}': {}", path, e))?;
+        Ok(Self { fields, strict })
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    /// Coerces (or, in strict mode, checks) the typed keys of `metadata` in place.
+    /// Returns the keys strict mode rejects, with the type each should have had.
+    pub fn apply(&self, metadata: &mut Value) -> Vec<(String, &'static str)> {
+        let mut mismatches = Vec::new();
+        let Value::Object(map) = metadata else {
+            return mismatches;
+        };
+        for (key, value) in map.iter_mut() {
+            let Some(&expected) = self.fields.get(key) else {
+                continue;
+            };
+            if value.is_null() || expected.matches(value) {
+                continue;
+            }
+            if self.strict {
+                mismatches.push((key.clone(), expected.name()));
+            } else if let Some(coerced) = expected.coerce(value) {
+                *value = coerced;
+            }
+        }
+        mismatches
+    }
+}
@@ -0,0 +1,336 @@
+//! Time-range partitions of the logs table.
+//!
+//! `logs` is range-partitioned on `timestamp`. Rows no partition covers, including every
+//! row written before partitioning was introduced, land in `logs_default`. Unless
+//! `PARTITION_MAINTENANCE` is turned off, the maintenance task keeps the partition for the
+//! current period and the next `PARTITION_PREMAKE` ones in place, one per
+//! `PARTITION_GRANULARITY` (`day` or `month`). With `PARTITION_DROP_EXPIRED` it also drops
+//! partitions that ended more than `RETENTION_DAYS` ago (or the longest
+//! `SERVICE_RETENTION` override, if that is longer), which is far cheaper than deleting
+//! their rows.
+//!
+//! Postgres refuses to create a partition while `logs_default` holds rows belonging in it,
+//! and checks by scanning `logs_default`. So each partition is created as a plain table,
+//! the default partition's rows for its period are moved into it and only then is it
+//! attached, all in one transaction. The task also empties `logs_default` this way, oldest
+//! period first, so legacy rows end up in partitions that expire like any other and the
+//! scans stay cheap.
+
+use crate::env_or;
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, Utc};
+use sqlx::PgPool;
+use std::time::Duration;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Granularity {
+    Day,
+    Month,
+}
+
+impl Granularity {
+    fn start_of(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Self::Day => date,
+            Self::Month => date.with_day(1).unwrap_or(date),
+        }
+    }
+
+    fn next(self, start: NaiveDate) -> NaiveDate {
+        match self {
+            Self::Day => start.checked_add_days(Days::new(1)),
+            Self::Month => start.checked_add_months(Months::new(1)),
+        }
+        .unwrap_or(start)
+    }
+
+    /// `logs_p20260314` for a day, `logs_p202603` for a month.
+    fn name(self, start: NaiveDate) -> String {
+        match self {
+            Self::Day => format!("logs_p{}", start.format("%Y%m%d")),
+            Self::Month => format!("logs_p{}", start.format("%Y%m")),
+        }
+    }
+
+    /// The start of the period a partition created by this task covers, from its name.
+    fn parse_name(self, name: &str) -> Option<NaiveDate> {
+        let date = name.strip_prefix("logs_p")?;
+        match self {
+            Self::Day if date.len() == 8 => NaiveDate::parse_from_str(date, "%Y%m%d").ok(),
+            Self::Month if date.len() == 6 => NaiveDate::parse_from_str(&format!("{}01", date), "%Y%m%d").ok(),
+            _ => None,
+        }
+    }
+
+    /// Whether `name` is a partition of this task that ended on or before `cutoff`. Any
+    /// other table, `logs_default` included, never is.
+    fn expired(self, name: &str, cutoff: NaiveDate) -> bool {
+        self.parse_name(name).is_some_and(|start| self.next(start) <= cutoff)
+    }
+}
+
+/// Most periods moved out of `logs_default` per maintenance run, so a large backlog is
+/// worked off over several runs.
+const LEGACY_PERIODS_PER_RUN: usize = 31;
+
+pub struct PartitionManager {
+    granularity: Granularity,
+    premake: u32,
+    /// Partitions ending before this many days ago are dropped
+    drop_after_days: Option<u64>,
+    interval: Duration,
+}
+
+impl PartitionManager {
+    /// `None` when `PARTITION_MAINTENANCE` is turned off, e.g. because partitions are
+    /// managed outside TideLogs.
+    pub fn from_env(retention_days: Option<i32>) -> Option<Self> {
+        if !crate::env_flag("PARTITION_MAINTENANCE", true) {
+            info!("PARTITION_MAINTENANCE is off; not creating or dropping log partitions");
+            return None;
+        }
+        let granularity = match std::env::var("PARTITION_GRANULARITY").ok().as_deref().map(str::trim) {
+            None | Some("") | Some("day") => Granularity::Day,
+            Some("month") => Granularity::Month,
+            Some(other) => {
+                warn!("Ignoring invalid PARTITION_GRANULARITY '{}': expected day or month", other);
+                Granularity::Day
+            }
+        };
+        let drop_expired = crate::env_flag("PARTITION_DROP_EXPIRED", false);
+        let drop_after_days = match (drop_expired, retention_days) {
+            (true, Some(days)) if days > 0 => Some(days as u64),
+            (true, _) => {
+                warn!("PARTITION_DROP_EXPIRED needs a positive RETENTION_DAYS; not dropping partitions");
+                None
+            }
+            (false, _) => None,
+        };
+        Some(Self {
+            granularity,
+            premake: env_or("PARTITION_PREMAKE", 3u32),
+            drop_after_days,
+            interval: Duration::from_secs(env_or("PARTITION_CHECK_SECS", 3600).max(1)),
+        })
+    }
+}
+
+pub async fn run(pool: PgPool, manager: PartitionManager) {
+    let label = match manager.granularity {
+        Granularity::Day => "daily",
+        Granularity::Month => "monthly",
+    };
+    match manager.drop_after_days {
+        Some(days) => info!(
+            "Maintaining {} log partitions, {} ahead, dropping them {} days after they end",
+            label, manager.premake, days
+        ),
+        None => info!("Maintaining {} log partitions, {} ahead", label, manager.premake),
+    }
+    let mut ticker = tokio::time::interval(manager.interval);
+    loop {
+        ticker.tick().await;
+        create_upcoming(&pool, &manager).await;
+        move_legacy(&pool, manager.granularity).await;
+        if let Some(days) = manager.drop_after_days {
+            drop_expired(&pool, manager.granularity, days).await;
+        }
+    }
+}
+
+async fn create_upcoming(pool: &PgPool, manager: &PartitionManager) {
+    let granularity = manager.granularity;
+    let mut start = granularity.start_of(Utc::now().date_naive());
+    for _ in 0..=manager.premake {
+        let name = granularity.name(start);
+        match create_partition(pool, granularity, start).await {
+            Ok(Some(moved)) if moved > 0 => info!("Created log partition {} with {} logs from logs_default", name, moved),
+            Ok(_) => {}
+            Err(e) => warn!("Failed to create log partition {}: {}", name, e),
+        }
+        start = granularity.next(start);
+    }
+}
+
+/// Moves the rows of `logs_default` into partitions of their periods, oldest first.
+async fn move_legacy(pool: &PgPool, granularity: Granularity) {
+    for _ in 0..LEGACY_PERIODS_PER_RUN {
+        let oldest: Option<DateTime<Utc>> = match sqlx::query_scalar("SELECT MIN(timestamp) FROM logs_default")
+            .fetch_one(pool)
+            .await
+        {
+            Ok(oldest) => oldest,
+            Err(e) => {
+                warn!("Failed to find the oldest row of logs_default: {}", e);
+                return;
+            }
+        };
+        let Some(oldest) = oldest else {
+            return;
+        };
+        let start = granularity.start_of(oldest.date_naive());
+        let name = granularity.name(start);
+        match create_partition(pool, granularity, start).await {
+            Ok(Some(moved)) => info!("Moved {} logs from logs_default into partition {}", moved, name),
+            // The period has a partition, so its rows can't be in logs_default
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Failed to move logs from logs_default into partition {}: {}", name, e);
+                return;
+            }
+        }
+    }
+}
+
+/// Creates the partition of the period starting at `start`, moving the rows `logs_default`
+/// holds for it in first. Returns how many rows were moved, or `None` when the partition
+/// already exists.
+async fn create_partition(
+    pool: &PgPool,
+    granularity: Granularity,
+    start: NaiveDate,
+) -> Result<Option<u64>, sqlx::Error> {
+    let end = granularity.next(start);
+    let name = granularity.name(start);
+    let exists: bool = sqlx::query_scalar("SELECT to_regclass($1) IS NOT NULL")
+        .bind(&name)
+        .fetch_one(pool)
+        .await?;
+    if exists {
+        return Ok(None);
+    }
+
+    // DDL takes no bind parameters; every interpolated value is generated here
+    let (from, to) = (format!("'{} 00:00:00+00'", start), format!("'{} 00:00:00+00'", end));
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!("CREATE TABLE {} (LIKE logs INCLUDING DEFAULTS INCLUDING COMPRESSION)", name))
+        .execute(&mut *tx)
+        .await?;
+    // Proves the bounds to ATTACH PARTITION, which then doesn't scan the new table
+    sqlx::query(&format!(
+        "ALTER TABLE {0} ADD CONSTRAINT {0}_bounds CHECK (timestamp >= {1} AND timestamp < {2})",
+        name, from, to
+    ))
+        .execute(&mut *tx)
+        .await?;
+    let columns: String = sqlx::query_scalar(
+        r#"
+        SELECT string_agg(quote_ident(attname), ', ' ORDER BY attnum) FROM pg_attribute
+        WHERE attrelid = 'logs'::regclass AND attnum > 0 AND NOT attisdropped
+        "#,
+    )
+        .fetch_one(&mut *tx)
+        .await?;
+    let moved = sqlx::query(&format!(
+        "WITH moved AS (DELETE FROM logs_default WHERE timestamp >= {2} AND timestamp < {3} RETURNING {1}) \
+         INSERT INTO {0} ({1}) SELECT {1} FROM moved",
+        name, columns, from, to
+    ))
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    sqlx::query(&format!("ALTER TABLE logs ATTACH PARTITION {} FOR VALUES FROM ({}) TO ({})", name, from, to))
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(&format!("ALTER TABLE {0} DROP CONSTRAINT {0}_bounds", name))
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(Some(moved))
+}
+
+async fn drop_expired(pool: &PgPool, granularity: Granularity, days: u64) {
+    let today = Utc::now().date_naive();
+    let Some(cutoff) = today.checked_sub_days(Days::new(days)) else {
+        return;
+    };
+    let partitions: Vec<String> = match sqlx::query_scalar(
+        r#"
+        SELECT c.relname::text FROM pg_inherits i
+        JOIN pg_class c ON c.oid = i.inhrelid
+        WHERE i.inhparent = 'logs'::regclass
+        "#
+    )
+        .fetch_all(pool)
+        .await
+    {
+        Ok(names) => names,
+        Err(e) => {
+            warn!("Failed to list log partitions: {}", e);
+            return;
+        }
+    };
+
+    for name in partitions.into_iter().filter(|name| granularity.expired(name, cutoff)) {
+        match sqlx::query(&format!("DROP TABLE IF EXISTS {}", name)).execute(pool).await {
+            Ok(_) => info!("Dropped expired log partition {}", name),
+            Err(e) => warn!("Failed to drop log partition {}: {}", name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn periods_start_on_their_first_day() {
+        assert_eq!(Granularity::Day.start_of(date(2026, 3, 14)), date(2026, 3, 14));
+        assert_eq!(Granularity::Month.start_of(date(2026, 3, 14)), date(2026, 3, 1));
+        assert_eq!(Granularity::Month.start_of(date(2026, 3, 1)), date(2026, 3, 1));
+    }
+
+    #[test]
+    fn next_crosses_month_and_year_boundaries() {
+        assert_eq!(Granularity::Day.next(date(2026, 3, 14)), date(2026, 3, 15));
+        assert_eq!(Granularity::Day.next(date(2026, 2, 28)), date(2026, 3, 1));
+        assert_eq!(Granularity::Day.next(date(2028, 2, 28)), date(2028, 2, 29));
+        assert_eq!(Granularity::Day.next(date(2026, 12, 31)), date(2027, 1, 1));
+        assert_eq!(Granularity::Month.next(date(2026, 1, 1)), date(2026, 2, 1));
+        assert_eq!(Granularity::Month.next(date(2026, 12, 1)), date(2027, 1, 1));
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for start in [date(2026, 3, 14), date(2026, 12, 31), date(2028, 2, 29)] {
+            let name = Granularity::Day.name(start);
+            assert_eq!(Granularity::Day.parse_name(&name), Some(start));
+        }
+        assert_eq!(Granularity::Day.name(date(2026, 3, 4)), "logs_p20260304");
+        assert_eq!(Granularity::Month.name(date(2026, 3, 1)), "logs_p202603");
+        assert_eq!(Granularity::Month.parse_name("logs_p202603"), Some(date(2026, 3, 1)));
+    }
+
+    #[test]
+    fn parse_name_only_accepts_names_of_its_granularity() {
+        assert_eq!(Granularity::Day.parse_name("logs_p202603"), None);
+        assert_eq!(Granularity::Month.parse_name("logs_p20260314"), None);
+        assert_eq!(Granularity::Day.parse_name("logs_default"), None);
+        assert_eq!(Granularity::Day.parse_name("logs_p20260230"), None);
+        assert_eq!(Granularity::Month.parse_name("logs_p202613"), None);
+        assert_eq!(Granularity::Day.parse_name("other_p20260314"), None);
+    }
+
+    #[test]
+    fn day_partitions_expire_once_they_have_ended() {
+        let cutoff = date(2026, 3, 1);
+        assert!(Granularity::Day.expired("logs_p20260227", cutoff));
+        assert!(Granularity::Day.expired("logs_p20260228", cutoff));
+        assert!(!Granularity::Day.expired("logs_p20260301", cutoff));
+        assert!(!Granularity::Day.expired("logs_default", cutoff));
+        assert!(!Granularity::Day.expired("logs_p202601", cutoff));
+    }
+
+    #[test]
+    fn month_partitions_expire_only_after_their_last_day() {
+        assert!(!Granularity::Month.expired("logs_p202602", date(2026, 2, 28)));
+        assert!(Granularity::Month.expired("logs_p202602", date(2026, 3, 1)));
+        assert!(Granularity::Month.expired("logs_p202512", date(2026, 1, 1)));
+        assert!(!Granularity::Month.expired("logs_p202601", date(2026, 1, 31)));
+        assert!(!Granularity::Month.expired("logs_p20251201", date(2026, 3, 1)));
+    }
+}
@@ -0,0 +1,715 @@
+//! Log filters and the SQL they select, shared by the server's routes and by other Rust
+//! code that queries the logs table directly.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use tidelogs_backend::query::{self, LogFilters};
+//! use tidelogs_backend::service_aliases::ServiceAliases;
+//!
+//! # async fn example(pool: sqlx::PgPool) -> Result<(), Box<dyn std::error::Error>> {
+//! let filters = LogFilters::builder().service("api").level("ERROR").last(Duration::from_secs(900)).build();
+//! let logs = query::select(&filters, &ServiceAliases::default())?.build().fetch_all(&pool).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::service_aliases::ServiceAliases;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, QueryBuilder};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+/// The columns every log query selects, in the order `LogEntry` is read from.
+pub const LOG_COLUMNS: &str = "id, timestamp, service, level, message, message_gzip, metadata, created_at, seq";
+
+/// The filters `GET /logs` and the routes sharing it take from the query string. Build
+/// one with [`LogFilters::builder`] to query logs from Rust.
+#[derive(Debug, Default, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LogFilters {
+    pub service: Option<String>,
+    pub level: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+    /// Match `service` and `exclude_service` ignoring case
+    pub service_ci: Option<bool>,
+    /// Case-insensitive substring the service name must contain; empty is ignored
+    pub service_like: Option<String>,
+    /// Comma-separated services to leave out
+    pub exclude_service: Option<String>,
+    /// Comma-separated levels to leave out
+    pub exclude_level: Option<String>,
+    /// IANA timezone name the response timestamps are converted to (default UTC)
+    pub tz: Option<String>,
+    /// Case-insensitive substring the message must contain; empty is ignored
+    pub search: Option<String>,
+    /// Case-insensitive substring the message must not contain; empty is ignored
+    pub not_search: Option<String>,
+    /// Attach a `match_snippet` around the search match to each result
+    pub highlight: Option<bool>,
+    /// Only logs at or after this time (RFC 3339)
+    pub from: Option<DateTime<Utc>>,
+    /// Only logs before this time (RFC 3339)
+    pub to: Option<DateTime<Utc>>,
+    /// Only logs from this long ago until now, e.g. `15m` or `24h` (units `s`, `m`, `h`,
+    /// `d`); not combinable with `from`/`to`
+    #[param(value_type = Option<String>)]
+    pub last: Option<RelativeWindow>,
+    /// Only logs stored more than this long after their timestamp, e.g. `5m`. The server
+    /// assigns timestamps, so this is the lag between accepting a log and inserting it, not
+    /// shipper delays; needs `from` or `last`, and counts as an expensive read
+    #[param(value_type = Option<String>)]
+    pub min_ingest_delay: Option<RelativeWindow>,
+    /// Only logs newer than this timestamp; results are then returned oldest first
+    pub since: Option<DateTime<Utc>>,
+    /// Only logs after this one in `(timestamp, id)` order; results are then returned
+    /// oldest first
+    pub since_id: Option<Uuid>,
+    /// Only logs with a higher `seq`; results are then returned in `seq` order. Unlike
+    /// `since`, this cursor does not depend on clock timestamps.
+    pub after_seq: Option<i64>,
+    /// With `after_seq`, only logs stored at least this long ago. A `seq` is taken when its
+    /// insert starts, so a slow insert can commit after logs with a higher one; the server
+    /// sets this to `SYNC_SETTLE_MS` so a poller does not move past such a log.
+    #[serde(skip)]
+    #[param(ignore)]
+    pub settle: Option<Duration>,
+    /// Skip fetching rows and return only `total` (with an empty `logs`)
+    pub count_only: Option<bool>,
+    /// `metadata.<key>=value` filters, which the server reads from the query string itself
+    /// after checking each key against `QUERYABLE_METADATA_KEYS`
+    #[serde(skip_deserializing, skip_serializing_if = "BTreeMap::is_empty")]
+    #[param(ignore)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl LogFilters {
+    pub fn builder() -> LogFiltersBuilder {
+        LogFiltersBuilder::default()
+    }
+
+    /// The rows to return as `(limit, offset)`. `page`/`per_page` take precedence over
+    /// raw `limit`/`offset` when either is given; both limits are capped at 1000. Fails on
+    /// values out of range, including a page whose offset would overflow.
+    pub fn limit_offset(&self) -> Result<(i64, i64), &'static str> {
+        if self.page.is_some() || self.per_page.is_some() {
+            let page = self.page.unwrap_or(1);
+            let per_page = self.per_page.unwrap_or(100).min(1000);
+            if page < 1 || per_page < 1 {
+                return Err("page and per_page must be at least 1");
+            }
+            let offset = (page - 1).checked_mul(per_page).ok_or("page is too large")?;
+            Ok((per_page, offset))
+        } else {
+            let (limit, offset) = (self.limit.unwrap_or(100).min(1000), self.offset.unwrap_or(0));
+            if limit < 0 || offset < 0 {
+                return Err("limit and offset must not be negative");
+            }
+            offset.checked_add(limit).ok_or("offset is too large")?;
+            Ok((limit, offset))
+        }
+    }
+
+    /// Whether this is an incremental poll from a `since`/`since_id` cursor, which reads
+    /// forward (oldest first) so a burst larger than one page is picked up over several
+    /// polls instead of skipped.
+    pub fn is_incremental(&self) -> bool {
+        self.since.is_some() || self.since_id.is_some() || self.after_seq.is_some()
+    }
+
+    /// Fails on an `exclude_service` or `exclude_level` that names nothing, such as an
+    /// empty value or only commas, which would otherwise silently exclude nothing, and on a
+    /// `min_ingest_delay` without `from` or `last`, which no index can serve.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.exclude_service.as_deref().is_some_and(|v| comma_list(v).is_none()) {
+            return Err("exclude_service must name at least one service");
+        }
+        if self.exclude_level.as_deref().is_some_and(|v| comma_list(v).is_none()) {
+            return Err("exclude_level must name at least one level");
+        }
+        if self.min_ingest_delay.is_some() && self.from.is_none() && self.last.is_none() {
+            return Err("min_ingest_delay needs from or last to bound the logs compared");
+        }
+        Ok(())
+    }
+}
+
+/// Fluent construction of [`LogFilters`]; every filter left unset matches everything.
+#[derive(Debug, Default)]
+pub struct LogFiltersBuilder {
+    filters: LogFilters,
+}
+
+impl LogFiltersBuilder {
+    pub fn service(mut self, service: impl Into<String>) -> Self {
+        self.filters.service = Some(service.into());
+        self
+    }
+
+    /// Match `service` and `exclude_service` ignoring case.
+    pub fn service_case_insensitive(mut self) -> Self {
+        self.filters.service_ci = Some(true);
+        self
+    }
+
+    pub fn service_like(mut self, substring: impl Into<String>) -> Self {
+        self.filters.service_like = Some(substring.into());
+        self
+    }
+
+    pub fn level(mut self, level: impl Into<String>) -> Self {
+        self.filters.level = Some(level.into().to_uppercase());
+        self
+    }
+
+    pub fn exclude_services<S: AsRef<str>>(mut self, services: impl IntoIterator<Item = S>) -> Self {
+        self.filters.exclude_service = join(services);
+        self
+    }
+
+    pub fn exclude_levels<S: AsRef<str>>(mut self, levels: impl IntoIterator<Item = S>) -> Self {
+        self.filters.exclude_level = join(levels);
+        self
+    }
+
+    pub fn search(mut self, substring: impl Into<String>) -> Self {
+        self.filters.search = Some(substring.into());
+        self
+    }
+
+    pub fn not_search(mut self, substring: impl Into<String>) -> Self {
+        self.filters.not_search = Some(substring.into());
+        self
+    }
+
+    pub fn from(mut self, from: DateTime<Utc>) -> Self {
+        self.filters.from = Some(from);
+        self
+    }
+
+    pub fn to(mut self, to: DateTime<Utc>) -> Self {
+        self.filters.to = Some(to);
+        self
+    }
+
+    /// Only logs from `window` ago until now, in whole seconds (at least one, at most 100
+    /// years).
+    pub fn last(mut self, window: Duration) -> Self {
+        self.filters.last = Some(RelativeWindow {
+            amount: window.as_secs().clamp(1, MAX_WINDOW_SECONDS as u64) as i64,
+            unit: 's',
+        });
+        self
+    }
+
+    /// Only logs stored more than `delay` (in whole seconds, at least one, at most 100
+    /// years) after their timestamp. Compares every row, so bound it with `from` or `last`.
+    pub fn min_ingest_delay(mut self, delay: Duration) -> Self {
+        self.filters.min_ingest_delay = Some(RelativeWindow {
+            amount: delay.as_secs().clamp(1, MAX_WINDOW_SECONDS as u64) as i64,
+            unit: 's',
+        });
+        self
+    }
+
+    /// Only logs newer than `since`, returned oldest first.
+    pub fn since(mut self, since: DateTime<Utc>) -> Self {
+        self.filters.since = Some(since);
+        self
+    }
+
+    /// Only logs after this one in `(timestamp, id)` order, returned oldest first.
+    pub fn since_id(mut self, id: Uuid) -> Self {
+        self.filters.since_id = Some(id);
+        self
+    }
+
+    /// Only logs with a `seq` above `seq`, returned in `seq` order.
+    pub fn after_seq(mut self, seq: i64) -> Self {
+        self.filters.after_seq = Some(seq);
+        self
+    }
+
+    /// With `after_seq`, only logs stored at least `settle` ago, so a log from a slow
+    /// insert with a lower `seq` is not skipped.
+    pub fn settle(mut self, settle: Duration) -> Self {
+        self.filters.settle = Some(settle);
+        self
+    }
+
+    /// Only logs whose top-level metadata `key` is exactly `value`. Unlike the server,
+    /// the builder accepts any key, so an unindexed one scans every row.
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.filters.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.filters.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: i64) -> Self {
+        self.filters.offset = Some(offset);
+        self
+    }
+
+    pub fn page(mut self, page: i64, per_page: i64) -> Self {
+        self.filters.page = Some(page);
+        self.filters.per_page = Some(per_page);
+        self
+    }
+
+    pub fn build(self) -> LogFilters {
+        self.filters
+    }
+}
+
+fn join<S: AsRef<str>>(items: impl IntoIterator<Item = S>) -> Option<String> {
+    let joined = items.into_iter().map(|s| s.as_ref().to_string()).collect::<Vec<_>>().join(",");
+    (!joined.is_empty()).then_some(joined)
+}
+
+/// A duration such as `15m`, given as a positive whole number and one of the units `s`,
+/// `m`, `h` or `d`, of at most 100 years. Parsed while deserializing, so an invalid value
+/// fails the request with `400` before any query runs.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RelativeWindow {
+    amount: i64,
+    unit: char,
+}
+
+/// The longest window accepted, in seconds: 100 years of 365 days.
+const MAX_WINDOW_SECONDS: i64 = 100 * 365 * 86_400;
+
+impl RelativeWindow {
+    pub fn seconds(self) -> i64 {
+        self.amount * unit_seconds(self.unit)
+    }
+}
+
+fn unit_seconds(unit: char) -> i64 {
+    match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        _ => 86_400,
+    }
+}
+
+impl TryFrom<String> for RelativeWindow {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid duration '{}': expected e.g. 30s, 15m, 24h or 7d", value);
+        let unit = value.chars().last().filter(|c| matches!(c, 's' | 'm' | 'h' | 'd')).ok_or_else(invalid)?;
+        let amount: i64 = value[..value.len() - 1].parse().map_err(|_| invalid())?;
+        if amount <= 0 {
+            return Err(invalid());
+        }
+        if amount > MAX_WINDOW_SECONDS / unit_seconds(unit) {
+            return Err(format!("duration '{}' is too long: at most 100 years", value));
+        }
+        Ok(Self { amount, unit })
+    }
+}
+
+impl From<RelativeWindow> for String {
+    fn from(window: RelativeWindow) -> Self {
+        format!("{}{}", window.amount, window.unit)
+    }
+}
+
+/// The page of logs `filters` selects, as `GET /logs` returns it: newest first, or oldest
+/// first after a `since`/`since_id` cursor, with `id` breaking timestamp ties in the same
+/// direction so the order is total and pages neither repeat nor skip rows. After an
+/// `after_seq` cursor logs come in `seq` order instead. Fails when the paging is invalid.
+pub fn select(filters: &LogFilters, aliases: &ServiceAliases) -> Result<QueryBuilder<'static, Postgres>, &'static str> {
+    let (limit, offset) = filters.limit_offset()?;
+    let mut query = QueryBuilder::new(format!("SELECT {} FROM logs", LOG_COLUMNS));
+    push_filters(&mut query, filters, aliases);
+    query
+        .push(if filters.after_seq.is_some() {
+            " ORDER BY seq ASC LIMIT "
+        } else if filters.is_incremental() {
+            " ORDER BY timestamp ASC, id ASC LIMIT "
+        } else {
+            " ORDER BY timestamp DESC, id DESC LIMIT "
+        })
+        .push_bind(limit)
+        .push(" OFFSET ")
+        .push_bind(offset);
+    Ok(query)
+}
+
+/// The number of logs `filters` matches, ignoring paging.
+pub fn count(filters: &LogFilters, aliases: &ServiceAliases) -> QueryBuilder<'static, Postgres> {
+    let mut query = QueryBuilder::new("SELECT COUNT(*) FROM logs");
+    push_filters(&mut query, filters, aliases);
+    query
+}
+
+/// Appends the WHERE clause for `filters` to `query`, binding every value. Both the
+/// data and the count queries are built through this so they can't drift apart.
+/// Service filters match every stored name the service has in `aliases`. Returns whether
+/// any condition was pushed, i.e. whether the query selects fewer than all rows.
+pub fn push_filters(query: &mut QueryBuilder<'_, Postgres>, filters: &LogFilters, aliases: &ServiceAliases) -> bool {
+    let mut first = true;
+    let mut and = |query: &mut QueryBuilder<'_, Postgres>| {
+        query.push(if first { " WHERE " } else { " AND " });
+        first = false;
+    };
+
+    if let Some(service) = &filters.service {
+        and(query);
+        let case_insensitive = filters.service_ci.unwrap_or(false);
+        let names = aliases.expand(service, case_insensitive);
+        if case_insensitive {
+            query.push("LOWER(service) = ANY(").push_bind(names).push(")");
+        } else {
+            query.push("service = ANY(").push_bind(names).push(")");
+        }
+    }
+
+    if let Some(service_like) = filters.service_like.as_deref().filter(|s| !s.is_empty()) {
+        and(query);
+        query.push("service ILIKE '%' || ").push_bind(escape_like(service_like)).push(" || '%'");
+    }
+
+    if let Some(level) = &filters.level {
+        and(query);
+        query.push("level = ").push_bind(level.to_uppercase());
+    }
+
+    if let Some(search) = filters.search.as_deref().filter(|s| !s.is_empty()) {
+        and(query);
+        query.push("message ILIKE '%' || ").push_bind(escape_like(search)).push(" || '%'");
+    }
+
+    if let Some(not_search) = filters.not_search.as_deref().filter(|s| !s.is_empty()) {
+        and(query);
+        query.push("message NOT ILIKE '%' || ").push_bind(escape_like(not_search)).push(" || '%'");
+    }
+
+    // Plain range conditions on the bare column, so the timestamp indexes apply
+    if let Some(from) = filters.from {
+        and(query);
+        query.push("timestamp >= ").push_bind(from);
+    }
+    if let Some(to) = filters.to {
+        and(query);
+        query.push("timestamp < ").push_bind(to);
+    }
+    if let Some(last) = filters.last {
+        and(query);
+        query.push("timestamp >= NOW() - make_interval(secs => ").push_bind(last.seconds() as f64).push(")");
+    }
+    if let Some(delay) = filters.min_ingest_delay {
+        and(query);
+        query.push("created_at - timestamp > make_interval(secs => ").push_bind(delay.seconds() as f64).push(")");
+    }
+
+    match (filters.since, filters.since_id) {
+        (Some(since), Some(since_id)) => {
+            and(query);
+            query
+                .push("(timestamp, id) > (")
+                .push_bind(since)
+                .push(", ")
+                .push_bind(since_id)
+                .push(")");
+        }
+        (None, Some(since_id)) => {
+            and(query);
+            query
+                .push("(timestamp, id) > (SELECT timestamp, id FROM logs WHERE id = ")
+                .push_bind(since_id)
+                .push(")");
+        }
+        (Some(since), None) => {
+            and(query);
+            query.push("timestamp > ").push_bind(since);
+        }
+        (None, None) => {}
+    }
+
+    if let Some(after_seq) = filters.after_seq {
+        and(query);
+        query.push("seq > ").push_bind(after_seq);
+        if let Some(settle) = filters.settle {
+            query
+                .push(" AND created_at <= NOW() - ")
+                .push_bind(settle.as_secs_f64())
+                .push(" * INTERVAL '1 second'");
+        }
+    }
+
+    if let Some(excluded) = filters.exclude_service.as_deref().and_then(comma_list) {
+        let case_insensitive = filters.service_ci.unwrap_or(false);
+        let excluded: Vec<String> = excluded.iter().flat_map(|s| aliases.expand(s, case_insensitive)).collect();
+        and(query);
+        if case_insensitive {
+            query.push("LOWER(service) <> ALL(").push_bind(excluded).push(")");
+        } else {
+            query.push("service <> ALL(").push_bind(excluded).push(")");
+        }
+    }
+
+    if let Some(excluded) = filters.exclude_level.as_deref().and_then(comma_list) {
+        let excluded: Vec<String> = excluded.iter().map(|l| l.to_uppercase()).collect();
+        and(query);
+        query.push("level <> ALL(").push_bind(excluded).push(")");
+    }
+
+    // The server checks keys against QUERYABLE_METADATA_KEYS, so they can be spelled out
+    // to match the expression indexes
+    for (key, value) in &filters.metadata {
+        and(query);
+        query.push(metadata_expression(key)).push(" = ").push_bind(value.clone());
+    }
+
+    !first
+}
+
+/// Whether any row-selecting filter is set (pagination and formatting options don't count).
+/// Derived from what [`push_filters`] renders, so a filter that renders no condition
+/// can't pass for one.
+pub fn has_filters(filters: &LogFilters) -> bool {
+    push_filters(&mut QueryBuilder::new(""), filters, &ServiceAliases::default())
+}
+
+/// The expression `metadata ->> '<key>'` with `key` inlined as a literal, spelled exactly
+/// as in the expression indexes of `QUERYABLE_METADATA_KEYS` so the planner can use them.
+pub fn metadata_expression(key: &str) -> String {
+    format!("(metadata ->> '{}')", key.replace('\'', "''"))
+}
+
+/// Escapes `%`, `_` and `\` so `value` matches literally inside a LIKE pattern.
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Splits a comma-separated list, trimming entries and dropping empty ones.
+pub fn comma_list(value: &str) -> Option<Vec<String>> {
+    let items: Vec<String> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+        .collect();
+    if items.is_empty() {
+        None
+    } else {
+        Some(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{Arguments, Execute};
+
+    /// The SQL `query` renders and how many values it binds.
+    fn render(mut query: QueryBuilder<'_, Postgres>) -> (String, usize) {
+        let sql = query.sql().to_string();
+        let binds = query.build().take_arguments().unwrap().map_or(0, |args| args.len());
+        (sql, binds)
+    }
+
+    /// The WHERE clause `filters` renders, with no aliases.
+    fn filter_sql(filters: &LogFilters) -> (String, usize) {
+        let mut query = QueryBuilder::new("");
+        push_filters(&mut query, filters, &ServiceAliases::default());
+        render(query)
+    }
+
+    fn filters(query: &str) -> LogFilters {
+        serde_urlencoded::from_str(query).unwrap()
+    }
+
+    #[test]
+    fn select_orders_newest_first_by_default() {
+        let (sql, binds) = render(select(&LogFilters::default(), &ServiceAliases::default()).unwrap());
+        let expected = format!("SELECT {} FROM logs ORDER BY timestamp DESC, id DESC LIMIT $1 OFFSET $2", LOG_COLUMNS);
+        assert_eq!(sql, expected);
+        assert_eq!(binds, 2);
+    }
+
+    #[test]
+    fn select_reads_forward_after_a_cursor() {
+        let (sql, binds) = render(select(&filters("since=2024-01-01T00:00:00Z"), &ServiceAliases::default()).unwrap());
+        assert!(sql.ends_with(" WHERE timestamp > $1 ORDER BY timestamp ASC, id ASC LIMIT $2 OFFSET $3"), "{}", sql);
+        assert_eq!(binds, 3);
+
+        let mut seq = filters("after_seq=10");
+        seq.settle = Some(Duration::from_secs(5));
+        let (sql, binds) = render(select(&seq, &ServiceAliases::default()).unwrap());
+        let expected = " WHERE seq > $1 AND created_at <= NOW() - $2 * INTERVAL '1 second' ORDER BY seq ASC LIMIT $3 OFFSET $4";
+        assert!(sql.ends_with(expected), "{}", sql);
+        assert_eq!(binds, 4);
+    }
+
+    #[test]
+    fn select_fails_on_invalid_paging() {
+        assert!(select(&filters("page=0"), &ServiceAliases::default()).is_err());
+    }
+
+    #[test]
+    fn count_ignores_paging() {
+        let (sql, binds) = render(count(&filters("level=ERROR&limit=5&offset=10"), &ServiceAliases::default()));
+        assert_eq!(sql, "SELECT COUNT(*) FROM logs WHERE level = $1");
+        assert_eq!(binds, 1);
+    }
+
+    #[test]
+    fn each_filter_renders_its_condition() {
+        let cases = [
+            ("service=api", " WHERE service = ANY($1)", 1),
+            ("service=API&service_ci=true", " WHERE LOWER(service) = ANY($1)", 1),
+            ("service_like=pay", " WHERE service ILIKE '%' || $1 || '%'", 1),
+            ("level=error", " WHERE level = $1", 1),
+            ("search=timeout", " WHERE message ILIKE '%' || $1 || '%'", 1),
+            ("not_search=health", " WHERE message NOT ILIKE '%' || $1 || '%'", 1),
+            ("from=2024-01-01T00:00:00Z", " WHERE timestamp >= $1", 1),
+            ("to=2024-01-02T00:00:00Z", " WHERE timestamp < $1", 1),
+            ("last=15m", " WHERE timestamp >= NOW() - make_interval(secs => $1)", 1),
+            ("min_ingest_delay=5m", " WHERE created_at - timestamp > make_interval(secs => $1)", 1),
+            (
+                "since=2024-01-01T00:00:00Z&since_id=5f0c2a4e-8a4b-4d7e-9a55-1f3e5b7c9d01",
+                " WHERE (timestamp, id) > ($1, $2)",
+                2,
+            ),
+            (
+                "since_id=5f0c2a4e-8a4b-4d7e-9a55-1f3e5b7c9d01",
+                " WHERE (timestamp, id) > (SELECT timestamp, id FROM logs WHERE id = $1)",
+                1,
+            ),
+            ("after_seq=7", " WHERE seq > $1", 1),
+            ("exclude_service=a,b", " WHERE service <> ALL($1)", 1),
+            ("exclude_service=A&service_ci=true", " WHERE LOWER(service) <> ALL($1)", 1),
+            ("exclude_level=debug,info", " WHERE level <> ALL($1)", 1),
+        ];
+        for (query, expected, binds) in cases {
+            assert_eq!(filter_sql(&filters(query)), (expected.to_string(), binds), "{}", query);
+        }
+    }
+
+    #[test]
+    fn filters_are_joined_with_and() {
+        let (sql, binds) = filter_sql(&filters("service=api&level=ERROR&from=2024-01-01T00:00:00Z"));
+        assert_eq!(sql, " WHERE service = ANY($1) AND level = $2 AND timestamp >= $3");
+        assert_eq!(binds, 3);
+    }
+
+    #[test]
+    fn metadata_filters_inline_the_key() {
+        let filters = LogFilters::builder().metadata("user's id", "42").build();
+        assert_eq!(filter_sql(&filters), (" WHERE (metadata ->> 'user''s id') = $1".to_string(), 1));
+    }
+
+    #[test]
+    fn empty_values_render_nothing() {
+        assert_eq!(filter_sql(&filters("search=&not_search=&service_like=")), (String::new(), 0));
+        assert_eq!(filter_sql(&filters("exclude_service=,,")), (String::new(), 0));
+    }
+
+    #[test]
+    fn has_filters_ignores_paging_and_formatting() {
+        assert!(!has_filters(&LogFilters::default()));
+        assert!(!has_filters(&filters("limit=10&offset=5&page=2&tz=Europe/Berlin&highlight=true&count_only=true")));
+        assert!(!has_filters(&filters("search=&exclude_level=,")));
+        assert!(has_filters(&filters("level=WARN")));
+        assert!(has_filters(&LogFilters::builder().metadata("region", "eu").build()));
+    }
+
+    #[test]
+    fn comma_list_trims_and_drops_empty_entries() {
+        assert_eq!(comma_list(" a, ,b ,"), Some(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(comma_list(""), None);
+        assert_eq!(comma_list(" , ,"), None);
+    }
+
+    #[test]
+    fn escape_like_escapes_wildcards_and_backslashes() {
+        assert_eq!(escape_like("50%_off\\now"), "50\\%\\_off\\\\now");
+        assert_eq!(escape_like("plain"), "plain");
+    }
+
+    #[test]
+    fn relative_window_parses_units() {
+        let seconds = |v: &str| RelativeWindow::try_from(v.to_string()).map(RelativeWindow::seconds);
+        assert_eq!(seconds("30s"), Ok(30));
+        assert_eq!(seconds("15m"), Ok(900));
+        assert_eq!(seconds("24h"), Ok(86_400));
+        assert_eq!(seconds("7d"), Ok(604_800));
+        for invalid in ["", "m", "0s", "-5m", "5x", "1.5h", "5 m", "5é"] {
+            assert!(seconds(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(String::from(RelativeWindow::try_from("90m".to_string()).unwrap()), "90m");
+    }
+
+    #[test]
+    fn relative_window_is_capped_at_100_years() {
+        let parse = |v: &str| RelativeWindow::try_from(v.to_string());
+        assert_eq!(parse("36500d").map(RelativeWindow::seconds), Ok(MAX_WINDOW_SECONDS));
+        assert_eq!(parse("3153600000s").map(RelativeWindow::seconds), Ok(MAX_WINDOW_SECONDS));
+        assert!(parse("36501d").unwrap_err().contains("too long"));
+        assert!(parse("876001h").unwrap_err().contains("too long"));
+        assert!(parse("99999999999999999999d").unwrap_err().contains("invalid duration"));
+        assert!(serde_urlencoded::from_str::<LogFilters>("last=36501d").is_err());
+
+        let clamped = LogFilters::builder()
+            .last(Duration::from_secs(u64::MAX))
+            .min_ingest_delay(Duration::ZERO)
+            .build();
+        assert_eq!(clamped.last.map(RelativeWindow::seconds), Some(MAX_WINDOW_SECONDS));
+        assert_eq!(clamped.min_ingest_delay.map(RelativeWindow::seconds), Some(1));
+    }
+
+    #[test]
+    fn validate_rejects_empty_exclusions_and_unbounded_delays() {
+        assert!(LogFilters::default().validate().is_ok());
+        assert!(filters("exclude_service=,").validate().is_err());
+        assert!(filters("exclude_level=").validate().is_err());
+        assert!(filters("exclude_level=debug").validate().is_ok());
+        assert!(filters("min_ingest_delay=5m").validate().is_err());
+        assert!(filters("min_ingest_delay=5m&to=2024-01-01T00:00:00Z").validate().is_err());
+        assert!(filters("min_ingest_delay=5m&last=1d").validate().is_ok());
+        assert!(filters("min_ingest_delay=5m&from=2024-01-01T00:00:00Z").validate().is_ok());
+    }
+
+    #[test]
+    fn limit_offset_pages_and_caps() {
+        assert_eq!(LogFilters::default().limit_offset(), Ok((100, 0)));
+        assert_eq!(filters("limit=5000&offset=20").limit_offset(), Ok((1000, 20)));
+        assert_eq!(filters("page=3&per_page=50").limit_offset(), Ok((50, 100)));
+        assert_eq!(filters("page=2&limit=10&offset=5").limit_offset(), Ok((100, 100)));
+        assert_eq!(filters("per_page=5000").limit_offset(), Ok((1000, 0)));
+        assert!(filters("page=0").limit_offset().is_err());
+        assert!(filters("per_page=0").limit_offset().is_err());
+        assert!(filters("limit=-1").limit_offset().is_err());
+        assert!(filters("offset=-1").limit_offset().is_err());
+    }
+
+    #[test]
+    fn limit_offset_rejects_overflow() {
+        let page = LogFilters::builder().page(i64::MAX, 1000).build();
+        assert_eq!(page.limit_offset(), Err("page is too large"));
+        let offset = LogFilters::builder().offset(i64::MAX).limit(1).build();
+        assert_eq!(offset.limit_offset(), Err("offset is too large"));
+        assert_eq!(LogFilters::builder().offset(i64::MAX).limit(0).build().limit_offset(), Ok((0, i64::MAX)));
+    }
+}
@@ -0,0 +1,140 @@
+//! Short-lived cache of `GET /logs` results.
+//!
+//! Dashboards often have many users polling the same view within seconds. A response is
+//! kept for `QUERY_CACHE_TTL_MS` (2 seconds by default, 0 turns caching off), keyed by the
+//! request's full filter set, and identical requests in the meantime are answered from memory with
+//! `cached: true` and an `Age` header. Writes do not invalidate entries, so a cached page
+//! can miss logs stored in the last TTL. At most `QUERY_CACHE_MAX_ENTRIES` results are
+//! kept; while the cache is full of live entries, new results are not cached.
+
+use crate::env_or;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+pub struct QueryCache<T> {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, (Instant, Arc<T>)>>,
+}
+
+impl<T> QueryCache<T> {
+    /// `None` when `QUERY_CACHE_TTL_MS` is 0.
+    pub fn from_env() -> Option<Self> {
+        let ttl = Duration::from_millis(env_or("QUERY_CACHE_TTL_MS", 2000));
+        if ttl.is_zero() {
+            return None;
+        }
+        let max_entries = env_or("QUERY_CACHE_MAX_ENTRIES", 1000usize).max(1);
+        info!("Caching log queries for {:?} (up to {} results)", ttl, max_entries);
+        Some(Self {
+            ttl,
+            max_entries,
+            entries: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The key of a request: its parameters serialized, so requests that parse to the
+    /// same filters share an entry whatever their parameter order.
+    pub fn key(params: &impl Serialize) -> String {
+        serde_json::to_string(params).unwrap_or_default()
+    }
+
+    /// The live entry for `key` and how long ago it was stored.
+    pub fn get(&self, key: &str) -> Option<(Arc<T>, Duration)> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let (stored, value) = entries.get(key)?;
+        let age = stored.elapsed();
+        (age < self.ttl).then(|| (value.clone(), age))
+    }
+
+    pub fn insert(&self, key: String, value: T) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= self.max_entries {
+            entries.retain(|_, (stored, _)| stored.elapsed() < self.ttl);
+            if entries.len() >= self.max_entries {
+                return;
+            }
+        }
+        entries.insert(key, (Instant::now(), Arc::new(value)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tidelogs_backend::query::LogFilters;
+
+    fn cache(max_entries: usize) -> QueryCache<u32> {
+        QueryCache {
+            ttl: Duration::from_secs(2),
+            max_entries,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Backdates the entry for `key` by `by`.
+    fn age(cache: &QueryCache<u32>, key: &str, by: Duration) {
+        let mut entries = cache.entries.lock().unwrap();
+        let (stored, _) = entries.get_mut(key).unwrap();
+        *stored -= by;
+    }
+
+    #[test]
+    fn entries_live_for_the_ttl() {
+        let cache = cache(10);
+        cache.insert("a".to_string(), 1);
+        let (value, age_now) = cache.get("a").unwrap();
+        assert_eq!(*value, 1);
+        assert!(age_now < Duration::from_secs(1));
+        assert!(cache.get("b").is_none());
+
+        age(&cache, "a", Duration::from_millis(1500));
+        assert!(cache.get("a").unwrap().1 >= Duration::from_millis(1500));
+        age(&cache, "a", Duration::from_millis(500));
+        assert!(cache.get("a").is_none());
+    }
+
+    #[test]
+    fn inserting_replaces_an_entry() {
+        let cache = cache(10);
+        cache.insert("a".to_string(), 1);
+        age(&cache, "a", Duration::from_secs(1));
+        cache.insert("a".to_string(), 2);
+        let (value, age_now) = cache.get("a").unwrap();
+        assert_eq!(*value, 2);
+        assert!(age_now < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn a_full_cache_stores_nothing_new() {
+        let cache = cache(2);
+        cache.insert("a".to_string(), 1);
+        cache.insert("b".to_string(), 2);
+        cache.insert("c".to_string(), 3);
+        assert!(cache.get("c").is_none());
+        assert!(cache.get("a").is_some() && cache.get("b").is_some());
+    }
+
+    #[test]
+    fn expired_entries_make_room() {
+        let cache = cache(2);
+        cache.insert("a".to_string(), 1);
+        cache.insert("b".to_string(), 2);
+        age(&cache, "a", Duration::from_secs(2));
+        cache.insert("c".to_string(), 3);
+        assert_eq!(cache.get("c").map(|(value, _)| *value), Some(3));
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn keys_ignore_parameter_order() {
+        let filters = |query: &str| serde_urlencoded::from_str::<LogFilters>(query).unwrap();
+        let key = |query: &str| QueryCache::<u32>::key(&filters(query));
+        assert_eq!(key("service=api&level=ERROR"), key("level=ERROR&service=api"));
+        assert_ne!(key("service=api&level=ERROR"), key("service=api&level=WARN"));
+        assert_ne!(key("service=api"), key("service=api&page=2"));
+    }
+}
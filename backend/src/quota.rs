@@ -0,0 +1,308 @@
+//! Per-service daily ingestion quotas.
+//!
+//! Usage is counted per `(service, UTC date)` and resets at midnight UTC, in memory or,
+//! with `REDIS_URL`, in Redis so that every replica shares the count. Limits come from
+//! `SERVICE_QUOTAS` (`service=limit,...`), with `DEFAULT_DAILY_QUOTA` applying to every
+//! service not listed there.
+//!
+//! Logs are reserved against the quota before they are inserted, all of a batch at once,
+//! and refunded when the insert fails, so only stored logs (and those parked in the retry
+//! queue) use it up.
+
+use crate::shared_store::{SharedStore, KEY_PREFIX};
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+/// How long a day's Redis counters outlive it, so replicas with skewed clocks still find
+/// them.
+const SHARED_COUNTER_TTL_SECS: i64 = 2 * 24 * 3600;
+
+pub struct QuotaExceeded {
+    pub limit: u64,
+    pub resets_at: DateTime<Utc>,
+}
+
+pub struct Quotas {
+    limits: HashMap<String, u64>,
+    default_limit: Option<u64>,
+    usage: Mutex<Usage>,
+    shared: Option<Arc<SharedStore>>,
+    /// Whether the last Redis command failed, so outages are logged once
+    shared_failing: AtomicBool,
+}
+
+struct Usage {
+    day: NaiveDate,
+    counts: HashMap<String, u64>,
+}
+
+impl Quotas {
+    pub fn from_env() -> Self {
+        let mut limits = HashMap::new();
+        let spec = std::env::var("SERVICE_QUOTAS").unwrap_or_default();
+        for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match pair.split_once('=').map(|(s, l)| (s.trim(), l.trim().parse::<u64>())) {
+                Some((service, Ok(limit))) if !service.is_empty() => {
+                    limits.insert(service.to_string(), limit);
+                }
+                _ => warn!("Ignoring invalid SERVICE_QUOTAS entry '{}'", pair),
+            }
+        }
+
+        Self {
+            limits,
+            default_limit: std::env::var("DEFAULT_DAILY_QUOTA").ok().and_then(|v| v.parse().ok()),
+            usage: Mutex::new(Usage {
+                day: Utc::now().date_naive(),
+                counts: HashMap::new(),
+            }),
+            shared: None,
+            shared_failing: AtomicBool::new(false),
+        }
+    }
+
+    /// Counts usage in Redis instead of in memory.
+    pub fn shared(mut self, store: Arc<SharedStore>) -> Self {
+        self.shared = Some(store);
+        self
+    }
+
+    /// Counts up to `wanted` logs against `service`'s quota for today, as many as still
+    /// fit. Hand the reservation back to [`Quotas::refund`] when the logs end up not being
+    /// stored, so a failed insert doesn't use up the quota.
+    pub async fn reserve(&self, service: &str, wanted: u64) -> Reservation {
+        let Some(limit) = self.limits.get(service).copied().or(self.default_limit) else {
+            return Reservation::unlimited(service, wanted);
+        };
+
+        let today = Utc::now().date_naive();
+        if let Some(store) = &self.shared {
+            match consume_shared(store, service, today, wanted, limit).await {
+                Ok(granted) => {
+                    if self.shared_failing.swap(false, Ordering::Relaxed) {
+                        info!("Redis is reachable again; quotas are shared between replicas");
+                    }
+                    return Reservation::counted(service, wanted, granted, limit, today, Counter::Shared);
+                }
+                Err(e) => self.shared_failed(e),
+            }
+        }
+
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        if usage.day != today {
+            usage.day = today;
+            usage.counts.clear();
+        }
+
+        let used = usage.counts.entry(service.to_string()).or_insert(0);
+        let granted = wanted.min(limit.saturating_sub(*used));
+        *used += granted;
+        Reservation::counted(service, wanted, granted, limit, today, Counter::Local)
+    }
+
+    /// Takes the logs of `reservation` off the count again. Counts of a day that has
+    /// since ended are left alone.
+    pub async fn refund(&self, reservation: Reservation) {
+        if reservation.granted == 0 {
+            return;
+        }
+        match reservation.counter {
+            None => {}
+            Some(Counter::Shared) => {
+                let Some(store) = &self.shared else {
+                    return;
+                };
+                let key = shared_key(&reservation.service, reservation.day);
+                let mut pipeline = redis::pipe();
+                pipeline.decr(&key, reservation.granted).ignore();
+                if let Err(e) = store.query::<()>(&pipeline).await {
+                    self.shared_failed(e);
+                }
+            }
+            Some(Counter::Local) => {
+                let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+                if usage.day == reservation.day {
+                    if let Some(used) = usage.counts.get_mut(&reservation.service) {
+                        *used = used.saturating_sub(reservation.granted);
+                    }
+                }
+            }
+        }
+    }
+
+    fn shared_failed(&self, e: redis::RedisError) {
+        if !self.shared_failing.swap(true, Ordering::Relaxed) {
+            warn!("Redis is unavailable, counting quotas on this instance only: {}", e);
+        }
+    }
+}
+
+/// Where a reservation was counted.
+#[derive(Clone, Copy)]
+enum Counter {
+    Local,
+    Shared,
+}
+
+/// Logs counted against a service's quota by [`Quotas::reserve`].
+pub struct Reservation {
+    service: String,
+    day: NaiveDate,
+    /// `None` for a service without a quota
+    counter: Option<Counter>,
+    /// How many of the logs asked for were counted
+    pub granted: u64,
+    /// Set when fewer logs fit than were asked for
+    pub exceeded: Option<QuotaExceeded>,
+}
+
+impl Reservation {
+    fn unlimited(service: &str, wanted: u64) -> Self {
+        Self {
+            service: service.to_string(),
+            day: NaiveDate::MIN,
+            counter: None,
+            granted: wanted,
+            exceeded: None,
+        }
+    }
+
+    fn counted(service: &str, wanted: u64, granted: u64, limit: u64, day: NaiveDate, counter: Counter) -> Self {
+        Self {
+            service: service.to_string(),
+            day,
+            counter: Some(counter),
+            granted,
+            exceeded: (granted < wanted).then(|| exceeded(limit, day)),
+        }
+    }
+}
+
+fn exceeded(limit: u64, today: NaiveDate) -> QuotaExceeded {
+    let tomorrow = today.checked_add_days(Days::new(1)).unwrap_or(today);
+    QuotaExceeded {
+        limit,
+        resets_at: tomorrow.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc(),
+    }
+}
+
+fn shared_key(service: &str, day: NaiveDate) -> String {
+    format!("{}quota:{}:{}", KEY_PREFIX, day, service)
+}
+
+/// Adds `wanted` to the shared counter of `service` for `today` and takes back whatever
+/// went over `limit`, returning how many were counted.
+async fn consume_shared(
+    store: &SharedStore,
+    service: &str,
+    today: NaiveDate,
+    wanted: u64,
+    limit: u64,
+) -> redis::RedisResult<u64> {
+    let key = shared_key(service, today);
+    let mut pipeline = redis::pipe();
+    pipeline.atomic().incr(&key, wanted).expire(&key, SHARED_COUNTER_TTL_SECS).ignore();
+    let (used,): (u64,) = store.query(&pipeline).await?;
+    let over = used.saturating_sub(limit).min(wanted);
+    if over > 0 {
+        let mut pipeline = redis::pipe();
+        pipeline.decr(&key, over).ignore();
+        // The logs were not counted even if Redis keeps the excess until the day ends
+        if let Err(e) = store.query::<()>(&pipeline).await {
+            warn!("Failed to take {} logs over quota off the shared count of {}: {}", over, service, e);
+        }
+    }
+    Ok(wanted - over)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quotas(limits: &[(&str, u64)], default_limit: Option<u64>) -> Quotas {
+        Quotas {
+            limits: limits.iter().map(|&(service, limit)| (service.to_string(), limit)).collect(),
+            default_limit,
+            usage: Mutex::new(Usage {
+                day: Utc::now().date_naive(),
+                counts: HashMap::new(),
+            }),
+            shared: None,
+            shared_failing: AtomicBool::new(false),
+        }
+    }
+
+    #[tokio::test]
+    async fn grants_what_still_fits() {
+        let quotas = quotas(&[("api", 10)], None);
+        let first = quotas.reserve("api", 6).await;
+        assert_eq!(first.granted, 6);
+        assert!(first.exceeded.is_none());
+
+        let second = quotas.reserve("api", 6).await;
+        assert_eq!(second.granted, 4);
+        let exceeded = second.exceeded.unwrap();
+        assert_eq!(exceeded.limit, 10);
+        let tomorrow = Utc::now().date_naive().checked_add_days(Days::new(1)).unwrap();
+        assert_eq!(exceeded.resets_at, tomorrow.and_hms_opt(0, 0, 0).unwrap().and_utc());
+
+        assert_eq!(quotas.reserve("api", 1).await.granted, 0);
+    }
+
+    #[tokio::test]
+    async fn services_are_counted_separately() {
+        let quotas = quotas(&[("api", 5)], Some(2));
+        assert_eq!(quotas.reserve("api", 5).await.granted, 5);
+        assert_eq!(quotas.reserve("worker", 5).await.granted, 2);
+        assert_eq!(quotas.reserve("cron", 5).await.granted, 2);
+    }
+
+    #[tokio::test]
+    async fn services_without_a_quota_are_unlimited() {
+        let quotas = quotas(&[("api", 5)], None);
+        let reservation = quotas.reserve("worker", 1000).await;
+        assert_eq!(reservation.granted, 1000);
+        assert!(reservation.exceeded.is_none());
+        quotas.refund(reservation).await;
+        assert!(quotas.usage.lock().unwrap().counts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refunds_free_the_quota_again() {
+        let quotas = quotas(&[("api", 10)], None);
+        let reservation = quotas.reserve("api", 8).await;
+        quotas.refund(reservation).await;
+        assert_eq!(quotas.reserve("api", 10).await.granted, 10);
+    }
+
+    #[tokio::test]
+    async fn a_new_day_resets_usage() {
+        let quotas = quotas(&[("api", 10)], None);
+        assert_eq!(quotas.reserve("api", 10).await.granted, 10);
+        quotas.usage.lock().unwrap().day = Utc::now().date_naive().pred_opt().unwrap();
+        assert_eq!(quotas.reserve("api", 7).await.granted, 7);
+    }
+
+    #[tokio::test]
+    async fn refunds_of_an_ended_day_are_ignored() {
+        let quotas = quotas(&[("api", 10)], None);
+        let reservation = quotas.reserve("api", 10).await;
+        quotas.usage.lock().unwrap().day = Utc::now().date_naive().succ_opt().unwrap();
+        quotas.refund(reservation).await;
+        assert_eq!(quotas.usage.lock().unwrap().counts["api"], 10);
+    }
+
+    #[tokio::test]
+    async fn counts_locally_while_redis_is_unreachable() {
+        let quotas = quotas(&[("api", 10)], None).shared(Arc::new(SharedStore::unreachable()));
+        assert_eq!(quotas.reserve("api", 6).await.granted, 6);
+        assert!(quotas.shared_failing.load(Ordering::Relaxed));
+        let reservation = quotas.reserve("api", 6).await;
+        assert_eq!(reservation.granted, 4);
+        quotas.refund(reservation).await;
+        assert_eq!(quotas.usage.lock().unwrap().counts["api"], 6);
+    }
+}
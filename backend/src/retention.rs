@@ -0,0 +1,133 @@
+//! How long logs are kept, per service.
+//!
+//! `RETENTION_DAYS` is the default and `SERVICE_RETENTION` (`service=days,...`) overrides
+//! it for individual services, e.g. `debug-worker=1,audit=365`. Overrides are keyed by
+//! canonical service name and apply to its aliases too. With `RETENTION_SWEEP` enabled, a
+//! background task deletes logs older than their service's retention every
+//! `RETENTION_SWEEP_SECS`. Services without an override keep their logs forever when no
+//! default is set.
+
+use crate::AppState;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+pub struct RetentionPolicy {
+    default_days: Option<i32>,
+    overrides: HashMap<String, i32>,
+}
+
+impl RetentionPolicy {
+    pub fn from_env() -> Self {
+        let default_days = std::env::var("RETENTION_DAYS").ok().and_then(|v| v.parse().ok());
+        let mut overrides = HashMap::new();
+        let spec = std::env::var("SERVICE_RETENTION").unwrap_or_default();
+        for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match pair.split_once('=').map(|(s, d)| (s.trim(), d.trim().parse::<i32>())) {
+                Some((service, Ok(days))) if !service.is_empty() && days > 0 => {
+                    overrides.insert(service.to_string(), days);
+                }
+                _ => warn!("Ignoring invalid SERVICE_RETENTION entry '{}'", pair),
+            }
+        }
+        Self { default_days, overrides }
+    }
+
+    pub fn default_days(&self) -> Option<i32> {
+        self.default_days
+    }
+
+    /// Retention in days of each overridden stored service name, aliases expanded, as
+    /// parallel lists for binding to a query.
+    pub fn overrides(&self, state: &AppState) -> (Vec<String>, Vec<i32>) {
+        let mut names = Vec::new();
+        let mut days = Vec::new();
+        for (service, &d) in &self.overrides {
+            for name in state.service_aliases.expand(service, false) {
+                names.push(name);
+                days.push(d);
+            }
+        }
+        (names, days)
+    }
+
+    /// The age after which no service keeps its logs any more, which is when a whole
+    /// time partition may be dropped. `None` when some service keeps them forever.
+    pub fn longest(&self) -> Option<i32> {
+        let default = self.default_days?;
+        Some(self.overrides.values().copied().fold(default, i32::max))
+    }
+}
+
+pub async fn run(state: AppState, interval: Duration) {
+    let policy = state.retention.clone();
+    match policy.default_days {
+        Some(days) => info!(
+            "Deleting logs after {} days ({} service overrides) every {:?}",
+            days,
+            policy.overrides.len(),
+            interval
+        ),
+        None => info!(
+            "Deleting logs of {} services with a retention override every {:?}; others are kept",
+            policy.overrides.len(),
+            interval
+        ),
+    }
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if state.maintenance.load(Ordering::Relaxed) {
+            continue;
+        }
+        for (service, &days) in &policy.overrides {
+            let stored = state.service_aliases.expand(service, false);
+            sweep(&state, service, &stored, false, days).await;
+        }
+        if let Some(days) = policy.default_days {
+            let (overridden, _) = policy.overrides(&state);
+            sweep(&state, "services without an override", &overridden, true, days).await;
+        }
+    }
+}
+
+/// Deletes, in batches of `DELETE_BATCH_SIZE`, the logs older than `days` whose service
+/// is among `services`, or with `exclude` among every other service.
+async fn sweep(state: &AppState, label: &str, services: &[String], exclude: bool, days: i32) {
+    let condition = if exclude { "service <> ALL($1)" } else { "service = ANY($1)" };
+    let statement = format!(
+        r#"
+        DELETE FROM logs WHERE (id, timestamp) IN (
+            SELECT id, timestamp FROM logs
+            WHERE {} AND timestamp < NOW() - make_interval(days => $2)
+            LIMIT $3
+        )
+        "#,
+        condition
+    );
+
+    let mut deleted: u64 = 0;
+    loop {
+        let batch = match sqlx::query(&statement)
+            .bind(services)
+            .bind(days)
+            .bind(state.delete_batch_size)
+            .execute(&state.pool)
+            .await
+        {
+            Ok(result) => result.rows_affected(),
+            Err(e) => {
+                error!("Retention sweep for {} failed after {} rows: {}", label, deleted, e);
+                return;
+            }
+        };
+        deleted += batch;
+        if batch < state.delete_batch_size as u64 {
+            break;
+        }
+    }
+    if deleted > 0 {
+        info!("Deleted {} logs of {} older than {} days", deleted, label, days);
+    }
+}
@@ -0,0 +1,184 @@
+//! Optional parking of inserts that failed because the database was unreachable.
+//!
+//! With `INSERT_RETRY_QUEUE` enabled, a log whose insert fails for a transient reason
+//! (connection errors, pool timeouts, the server shutting down) is kept in a bounded
+//! in-memory queue and the request is answered with `202 Accepted`. A background task
+//! retries the queue in arrival order every `INSERT_RETRY_INTERVAL_MS` until the database
+//! is back. A parked log is timestamped when it is parked, so it keeps its place in time
+//! however long the outage lasts. Parked logs only live in memory and are lost if the process exits before they
+//! are written, which is why this is opt-in.
+
+use crate::batcher::NewLog;
+use crate::{env_or, AppState};
+use chrono::Utc;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Why an insert did not produce a stored row.
+#[derive(Clone, Copy)]
+pub struct InsertFailure {
+    /// Whether trying the same insert again later could succeed.
+    pub transient: bool,
+}
+
+impl From<&sqlx::Error> for InsertFailure {
+    fn from(e: &sqlx::Error) -> Self {
+        Self { transient: is_transient(e) }
+    }
+}
+
+/// Errors that say nothing about the row itself, only that the database could not be
+/// reached or is overloaded. The circuit breaker and `SELF_MONITOR` count only these.
+pub fn is_transient(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Io(_)
+        | sqlx::Error::Tls(_)
+        | sqlx::Error::Protocol(_)
+        | sqlx::Error::PoolTimedOut
+        | sqlx::Error::PoolClosed => true,
+        // Class 08 is connection exceptions, 57P covers admin/crash shutdown and
+        // "cannot connect now" during startup, 53 is insufficient resources
+        sqlx::Error::Database(db) => db
+            .code()
+            .is_some_and(|code| code.starts_with("08") || code.starts_with("57P") || code.starts_with("53")),
+        _ => false,
+    }
+}
+
+pub struct RetryQueue {
+    pending: Mutex<VecDeque<NewLog>>,
+    capacity: usize,
+    interval: Duration,
+}
+
+impl RetryQueue {
+    pub fn from_env() -> Option<Self> {
+        if !crate::env_flag("INSERT_RETRY_QUEUE", false) {
+            return None;
+        }
+        let capacity = env_or("INSERT_RETRY_QUEUE_SIZE", 10_000usize).max(1);
+        let interval = Duration::from_millis(env_or("INSERT_RETRY_INTERVAL_MS", 1000).max(1));
+        info!("Parking failed inserts for retry (up to {}, every {:?})", capacity, interval);
+        Some(Self {
+            pending: Mutex::new(VecDeque::new()),
+            capacity,
+            interval,
+        })
+    }
+
+    /// Queues `log` for a later insert, stamping it with the current time unless it
+    /// already has a timestamp. Returns `false` when the queue is full.
+    pub fn park(&self, mut log: NewLog) -> bool {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        if pending.len() >= self.capacity {
+            return false;
+        }
+        log.timestamp.get_or_insert_with(Utc::now);
+        pending.push_back(log);
+        true
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    fn front(&self) -> Option<NewLog> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).front().cloned()
+    }
+
+    fn pop_front(&self) {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).pop_front();
+    }
+}
+
+/// Drains the queue whenever the database accepts writes again, pausing while maintenance
+/// mode is on. Only this task removes entries, so the front of the queue stays the log
+/// being retried.
+pub async fn run(state: AppState) {
+    let Some(queue) = state.retry_queue.clone() else {
+        return;
+    };
+    let mut ticker = tokio::time::interval(queue.interval);
+    loop {
+        ticker.tick().await;
+        while let Some(log) = queue.front() {
+            if crate::ensure_writable(&state).is_err() {
+                break;
+            }
+            match crate::insert_log(&state.pool, &log).await {
+                Ok(entry) => {
+                    queue.pop_front();
+                    crate::announce(&state, &entry);
+                    info!("Stored queued log entry: {} - {} - {}", entry.service, entry.level, entry.message);
+                }
+                Err(e) if is_transient(&e) => {
+                    warn!("Retrying {} queued logs later: {}", queue.pending(), e);
+                    break;
+                }
+                Err(e) => {
+                    queue.pop_front();
+                    error!("Dropping queued log for {} after a permanent failure: {}", log.service, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    fn queue(capacity: usize) -> RetryQueue {
+        RetryQueue {
+            pending: Mutex::new(VecDeque::new()),
+            capacity,
+            interval: Duration::from_secs(1),
+        }
+    }
+
+    fn log(timestamp: Option<DateTime<Utc>>) -> NewLog {
+        NewLog {
+            service: "api".to_string(),
+            level: "ERROR".to_string(),
+            message: "connection refused".to_string(),
+            metadata: serde_json::json!({}),
+            timestamp,
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn parked_logs_keep_the_time_they_arrived() {
+        let queue = queue(10);
+        let before = Utc::now();
+        assert!(queue.park(log(None)));
+        let parked = queue.front().unwrap().timestamp.unwrap();
+        assert!(parked >= before && parked <= Utc::now());
+    }
+
+    #[test]
+    fn signed_logs_keep_their_timestamp() {
+        let queue = queue(10);
+        let signed = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        assert!(queue.park(log(Some(signed))));
+        assert_eq!(queue.front().unwrap().timestamp, Some(signed));
+    }
+
+    #[test]
+    fn refuses_logs_once_full_and_retries_in_order() {
+        let queue = queue(2);
+        let first = DateTime::from_timestamp(1, 0);
+        assert!(queue.park(log(first)));
+        assert!(queue.park(log(None)));
+        assert!(!queue.park(log(None)));
+        assert_eq!(queue.pending(), 2);
+
+        assert_eq!(queue.front().unwrap().timestamp, first);
+        queue.pop_front();
+        assert_eq!(queue.pending(), 1);
+        assert!(queue.park(log(None)));
+    }
+}
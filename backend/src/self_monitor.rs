@@ -0,0 +1,203 @@
+//! Readiness from TideLogs' own database error rate.
+//!
+//! A wedged connection pool leaves the process running while every insert and query
+//! fails, which a liveness probe cannot see. With `SELF_MONITOR` enabled, every insert
+//! attempt and every read through `timed` counts as a database operation, and those that
+//! could not reach the database (connection and pool errors, SQLSTATE classes 08 and 53,
+//! and 57P shutdowns) as errors. Errors the statement itself caused, such as a
+//! constraint violation, invalid input or a statement cancelled at its request's
+//! deadline, are not counted at all. `GET /health/ready` answers `503` while more than
+//! `SELF_MONITOR_ERROR_RATE` of the operations in the last `SELF_MONITOR_WINDOW_SECS`
+//! failed, once at least `SELF_MONITOR_MIN_OPS` were counted, so a supervisor probing it
+//! restarts the instance. It turns ready again by itself when the errors stop.
+
+use crate::{env_flag, env_or};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::{info, warn};
+
+pub struct ErrorMonitor {
+    window_secs: u64,
+    max_error_rate: f64,
+    min_ops: u64,
+    started: Instant,
+    /// One bucket per second that saw operations, oldest first
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+struct Bucket {
+    second: u64,
+    ops: u64,
+    errors: u64,
+}
+
+pub struct ErrorRate {
+    pub operations: u64,
+    pub errors: u64,
+    pub error_rate: f64,
+    pub healthy: bool,
+}
+
+impl ErrorMonitor {
+    pub fn from_env() -> Option<Self> {
+        if !env_flag("SELF_MONITOR", false) {
+            return None;
+        }
+        let monitor = Self {
+            window_secs: env_or("SELF_MONITOR_WINDOW_SECS", 60u64).max(1),
+            max_error_rate: env_or("SELF_MONITOR_ERROR_RATE", 0.5f64).clamp(0.0, 1.0),
+            min_ops: env_or("SELF_MONITOR_MIN_OPS", 20u64).max(1),
+            started: Instant::now(),
+            buckets: Mutex::new(VecDeque::new()),
+        };
+        info!(
+            "Reporting not ready when over {:.0}% of database operations fail within {}s (at least {} operations)",
+            monitor.max_error_rate * 100.0,
+            monitor.window_secs,
+            monitor.min_ops
+        );
+        Some(monitor)
+    }
+
+    /// Counts one database operation, failed unless `ok`.
+    pub fn record(&self, ok: bool) {
+        let second = self.started.elapsed().as_secs();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        match buckets.back_mut() {
+            Some(bucket) if bucket.second == second => {
+                bucket.ops += 1;
+                bucket.errors += u64::from(!ok);
+            }
+            _ => buckets.push_back(Bucket {
+                second,
+                ops: 1,
+                errors: u64::from(!ok),
+            }),
+        }
+        self.expire(&mut buckets, second);
+    }
+
+    /// The error rate over the window and whether it is within bounds.
+    pub fn current(&self) -> ErrorRate {
+        let second = self.started.elapsed().as_secs();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        self.expire(&mut buckets, second);
+        let operations: u64 = buckets.iter().map(|b| b.ops).sum();
+        let errors: u64 = buckets.iter().map(|b| b.errors).sum();
+        let error_rate = if operations > 0 { errors as f64 / operations as f64 } else { 0.0 };
+        let healthy = operations < self.min_ops || error_rate <= self.max_error_rate;
+        if !healthy {
+            warn!(
+                "Not ready: {} of {} database operations failed in the last {}s",
+                errors, operations, self.window_secs
+            );
+        }
+        ErrorRate {
+            operations,
+            errors,
+            error_rate,
+            healthy,
+        }
+    }
+
+    fn expire(&self, buckets: &mut VecDeque<Bucket>, second: u64) {
+        while buckets.front().is_some_and(|b| b.second + self.window_secs <= second) {
+            buckets.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn monitor(window_secs: u64, max_error_rate: f64, min_ops: u64) -> ErrorMonitor {
+        ErrorMonitor {
+            window_secs,
+            max_error_rate,
+            min_ops,
+            started: Instant::now(),
+            buckets: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Makes `secs` seconds pass for the monitor.
+    fn advance(monitor: &mut ErrorMonitor, secs: u64) {
+        monitor.started -= Duration::from_secs(secs);
+    }
+
+    #[test]
+    fn healthy_without_operations() {
+        let rate = monitor(60, 0.5, 1).current();
+        assert_eq!((rate.operations, rate.errors), (0, 0));
+        assert_eq!(rate.error_rate, 0.0);
+        assert!(rate.healthy);
+    }
+
+    #[test]
+    fn unhealthy_above_the_error_rate() {
+        let monitor = monitor(60, 0.5, 4);
+        monitor.record(true);
+        monitor.record(false);
+        monitor.record(false);
+        // Too few operations to judge yet
+        assert!(monitor.current().healthy);
+
+        monitor.record(false);
+        let rate = monitor.current();
+        assert_eq!((rate.operations, rate.errors), (4, 3));
+        assert_eq!(rate.error_rate, 0.75);
+        assert!(!rate.healthy);
+    }
+
+    #[test]
+    fn exactly_the_error_rate_is_still_healthy() {
+        let monitor = monitor(60, 0.5, 2);
+        monitor.record(true);
+        monitor.record(false);
+        assert!(monitor.current().healthy);
+    }
+
+    #[test]
+    fn operations_leave_the_window() {
+        let mut monitor = monitor(2, 0.5, 1);
+        monitor.record(false);
+        advance(&mut monitor, 1);
+        monitor.record(true);
+        let rate = monitor.current();
+        assert_eq!((rate.operations, rate.errors), (2, 1));
+
+        // The failure's second is now out of the window, the success's is not
+        advance(&mut monitor, 1);
+        let rate = monitor.current();
+        assert_eq!((rate.operations, rate.errors), (1, 0));
+        assert!(rate.healthy);
+
+        advance(&mut monitor, 1);
+        assert_eq!(monitor.current().operations, 0);
+    }
+
+    #[test]
+    fn recovers_once_errors_stop() {
+        let mut monitor = monitor(2, 0.1, 1);
+        for _ in 0..10 {
+            monitor.record(false);
+        }
+        assert!(!monitor.current().healthy);
+        advance(&mut monitor, 2);
+        monitor.record(true);
+        assert!(monitor.current().healthy);
+    }
+
+    #[test]
+    fn one_bucket_per_second() {
+        let mut monitor = monitor(60, 0.5, 1);
+        monitor.record(true);
+        monitor.record(true);
+        advance(&mut monitor, 1);
+        monitor.record(false);
+        assert_eq!(monitor.buckets.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,163 @@
+//! Collapsing several stored service names onto one canonical name.
+//!
+//! After a rename the same service is stored under both its old and new names. The JSON
+//! file at `SERVICE_ALIASES_PATH` maps each canonical name to the names it absorbs, e.g.
+//! `{"payments": ["payment"]}`. Filtering on any of them matches all of them, and log
+//! listings, summaries and metrics report the canonical name; stored rows are left as
+//! they are. With `SERVICE_ALIASES_AT_INGEST`, new logs are also stored under the
+//! canonical name.
+
+use std::collections::HashMap;
+
+#[derive(Default)]
+pub struct ServiceAliases {
+    /// Alias to canonical name
+    canonical: HashMap<String, String>,
+    /// Canonical name to every stored name it stands for, itself included
+    names: HashMap<String, Vec<String>>,
+    at_ingest: bool,
+}
+
+impl ServiceAliases {
+    pub fn load(path: &str, at_ingest: bool) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read SERVICE_ALIASES_PATH '{}': {}", path, e))?;
+        let groups: HashMap<String, Vec<String>> = serde_json::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("invalid service aliases in '{}': {}", path, e))?;
+
+        let mut canonical = HashMap::new();
+        for (name, aliases) in &groups {
+            for alias in aliases.iter().filter(|alias| *alias != name) {
+                if groups.contains_key(alias) {
+                    anyhow::bail!("service alias '{}' is itself a canonical name in '{}'", alias, path);
+                }
+                if let Some(other) = canonical.insert(alias.clone(), name.clone()) {
+                    anyhow::bail!("service alias '{}' maps to both '{}' and '{}' in '{}'", alias, other, name, path);
+                }
+            }
+        }
+        let names = groups
+            .into_iter()
+            .map(|(name, aliases)| {
+                let mut all = vec![name.clone()];
+                all.extend(aliases.into_iter().filter(|alias| *alias != name));
+                (name, all)
+            })
+            .collect();
+
+        Ok(Self {
+            canonical,
+            names,
+            at_ingest,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.canonical.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.canonical.is_empty()
+    }
+
+    pub fn at_ingest(&self) -> bool {
+        self.at_ingest
+    }
+
+    pub fn canonical<'a>(&'a self, service: &'a str) -> &'a str {
+        self.canonical.get(service).map_or(service, String::as_str)
+    }
+
+    /// Replaces an alias in place with its canonical name.
+    pub fn canonicalize(&self, service: &mut String) {
+        if let Some(name) = self.canonical.get(service.as_str()) {
+            service.clone_from(name);
+        }
+    }
+
+    /// Every stored name a filter on `service` should match: its canonical name and all
+    /// of that name's aliases. With `case_insensitive`, aliases are looked up ignoring
+    /// case and the names come back lowercased.
+    pub fn expand(&self, service: &str, case_insensitive: bool) -> Vec<String> {
+        if !case_insensitive {
+            let name = self.canonical(service);
+            return self.names.get(name).cloned().unwrap_or_else(|| vec![service.to_string()]);
+        }
+        let name = self
+            .canonical
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(service))
+            .map(|(_, name)| name.as_str())
+            .unwrap_or(service);
+        let mut names: Vec<String> = self
+            .names
+            .iter()
+            .filter(|(canonical, _)| canonical.eq_ignore_ascii_case(name))
+            .flat_map(|(_, names)| names.iter().map(|n| n.to_lowercase()))
+            .collect();
+        if names.is_empty() {
+            names.push(service.to_lowercase());
+        }
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads `json` from a file of its own, like `SERVICE_ALIASES_PATH` would.
+    fn load(name: &str, json: &str) -> anyhow::Result<ServiceAliases> {
+        let path = std::env::temp_dir().join(format!("tidelogs-aliases-{}-{}.json", std::process::id(), name));
+        std::fs::write(&path, json).unwrap();
+        let aliases = ServiceAliases::load(path.to_str().unwrap(), false);
+        std::fs::remove_file(&path).unwrap();
+        aliases
+    }
+
+    fn sorted(mut names: Vec<String>) -> Vec<String> {
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn maps_aliases_to_their_canonical_name() {
+        let aliases = load("canonical", r#"{"payments": ["payment", "payments", "billing"]}"#).unwrap();
+        assert_eq!(aliases.len(), 2);
+        assert_eq!(aliases.canonical("payment"), "payments");
+        assert_eq!(aliases.canonical("payments"), "payments");
+        assert_eq!(aliases.canonical("api"), "api");
+
+        let mut service = "billing".to_string();
+        aliases.canonicalize(&mut service);
+        assert_eq!(service, "payments");
+    }
+
+    #[test]
+    fn expands_any_name_of_a_group_to_all_of_them() {
+        let aliases = load("expand", r#"{"payments": ["payment", "billing"]}"#).unwrap();
+        let all = vec!["billing".to_string(), "payment".to_string(), "payments".to_string()];
+        assert_eq!(sorted(aliases.expand("payments", false)), all);
+        assert_eq!(sorted(aliases.expand("billing", false)), all);
+        assert_eq!(aliases.expand("api", false), vec!["api"]);
+        assert_eq!(aliases.expand("Billing", false), vec!["Billing"]);
+    }
+
+    #[test]
+    fn case_insensitive_expansion_ignores_case_and_lowercases() {
+        let aliases = load("case", r#"{"Payments": ["Payment"]}"#).unwrap();
+        assert_eq!(sorted(aliases.expand("PAYMENT", true)), vec!["payment", "payments"]);
+        assert_eq!(sorted(aliases.expand("payments", true)), vec!["payment", "payments"]);
+        assert_eq!(aliases.expand("API", true), vec!["api"]);
+    }
+
+    #[test]
+    fn rejects_ambiguous_groups() {
+        let chained = load("chained", r#"{"payments": ["payment"], "payment": ["pay"]}"#);
+        assert!(chained.err().unwrap().to_string().contains("is itself a canonical name"));
+        let shared = load("shared", r#"{"payments": ["pay"], "billing": ["pay"]}"#);
+        assert!(shared.err().unwrap().to_string().contains("maps to both"));
+        assert!(load("invalid", r#"{"payments": "payment"}"#).is_err());
+        assert!(ServiceAliases::load("/nonexistent/aliases.json", false).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+//! Optional Redis connection for state that must be shared between replicas.
+//!
+//! Without `REDIS_URL`, the daily quota counters and the ingest rate load shedding goes by
+//! live in memory, so every replica behind a load balancer counts on its own. With it,
+//! they are kept in Redis and all replicas see the same counts. Commands that fail or take
+//! longer than `REDIS_TIMEOUT_MS` fall back to the in-memory state; a broken connection is
+//! re-established at most once per `RECONNECT_INTERVAL`, so an outage costs each request
+//! one quick failure rather than a connection attempt.
+
+use crate::env_or;
+use anyhow::Context;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncConnectionConfig, Client, ErrorKind, FromRedisValue, Pipeline, RedisError, RedisResult};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Prefix of every key TideLogs writes, so the instance can share a Redis database.
+pub const KEY_PREFIX: &str = "tidelogs:";
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct SharedStore {
+    client: Client,
+    config: AsyncConnectionConfig,
+    timeout: Duration,
+    connection: Mutex<Option<MultiplexedConnection>>,
+    last_connect: Mutex<Instant>,
+}
+
+impl SharedStore {
+    /// Connects to `REDIS_URL`, or `None` when it is unset. An unreachable server fails
+    /// startup rather than silently counting per instance.
+    pub async fn connect_from_env() -> anyhow::Result<Option<Self>> {
+        let Some(url) = std::env::var("REDIS_URL").ok().filter(|u| !u.is_empty()) else {
+            return Ok(None);
+        };
+        let timeout = Duration::from_millis(env_or("REDIS_TIMEOUT_MS", 200).max(1));
+        let client = Client::open(url.as_str()).context("invalid REDIS_URL")?;
+        let config = AsyncConnectionConfig::new()
+            .set_connection_timeout(timeout)
+            .set_response_timeout(timeout);
+        let connection = client
+            .get_multiplexed_async_connection_with_config(&config)
+            .await
+            .context("failed to connect to REDIS_URL")?;
+        info!("Sharing quota counters and the ingest rate through Redis");
+        Ok(Some(Self {
+            client,
+            config,
+            timeout,
+            connection: Mutex::new(Some(connection)),
+            last_connect: Mutex::new(Instant::now()),
+        }))
+    }
+
+    /// Runs `pipeline`, failing once `REDIS_TIMEOUT_MS` passes. Connection failures and
+    /// timeouts drop the connection, so the next command reconnects.
+    pub async fn query<T: FromRedisValue>(&self, pipeline: &Pipeline) -> RedisResult<T> {
+        let mut connection = self.connection().await?;
+        let result = match tokio::time::timeout(self.timeout, pipeline.query_async(&mut connection)).await {
+            Ok(result) => result,
+            Err(_) => Err(RedisError::from((ErrorKind::IoError, "Redis did not answer in time"))),
+        };
+        if let Err(e) = &result {
+            if e.is_io_error() || e.is_timeout() || e.is_connection_dropped() {
+                *self.connection.lock().unwrap_or_else(|e| e.into_inner()) = None;
+            }
+        }
+        result
+    }
+
+    async fn connection(&self) -> RedisResult<MultiplexedConnection> {
+        if let Some(connection) = self.connection.lock().unwrap_or_else(|e| e.into_inner()).clone() {
+            return Ok(connection);
+        }
+        {
+            let mut last_connect = self.last_connect.lock().unwrap_or_else(|e| e.into_inner());
+            if last_connect.elapsed() < RECONNECT_INTERVAL {
+                return Err(RedisError::from((ErrorKind::IoError, "not connected to Redis")));
+            }
+            *last_connect = Instant::now();
+        }
+        let connection = self.client.get_multiplexed_async_connection_with_config(&self.config).await?;
+        *self.connection.lock().unwrap_or_else(|e| e.into_inner()) = Some(connection.clone());
+        Ok(connection)
+    }
+
+    /// A store whose server never answers, for testing the in-memory fallbacks.
+    #[cfg(test)]
+    pub fn unreachable() -> Self {
+        let timeout = Duration::from_millis(200);
+        Self {
+            client: Client::open("redis://127.0.0.1:1/").unwrap(),
+            config: AsyncConnectionConfig::new()
+                .set_connection_timeout(timeout)
+                .set_response_timeout(timeout),
+            timeout,
+            connection: Mutex::new(None),
+            last_connect: Mutex::new(Instant::now() - RECONNECT_INTERVAL),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn failed_commands_do_not_reconnect_right_away() {
+        let store = SharedStore::unreachable();
+        let pipeline = redis::pipe().get("tidelogs:test").clone();
+        let first = store.query::<Option<u64>>(&pipeline).await.unwrap_err();
+        assert!(first.is_io_error() || first.is_connection_refusal(), "{}", first);
+
+        let started = Instant::now();
+        let second = store.query::<Option<u64>>(&pipeline).await.unwrap_err();
+        assert!(second.to_string().contains("not connected to Redis"), "{}", second);
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn reconnects_once_the_interval_has_passed() {
+        let store = SharedStore::unreachable();
+        let pipeline = redis::pipe().get("tidelogs:test").clone();
+        let _ = store.query::<Option<u64>>(&pipeline).await;
+        *store.last_connect.lock().unwrap() -= RECONNECT_INTERVAL;
+        let attempt = store.query::<Option<u64>>(&pipeline).await.unwrap_err();
+        assert!(!attempt.to_string().contains("not connected to Redis"), "{}", attempt);
+    }
+}
@@ -0,0 +1,284 @@
+//! Adaptive load shedding of low-severity logs.
+//!
+//! `LOAD_SHED_THRESHOLDS` lists levels with the ingest rate, in logs per second, above
+//! which logs at that level are dropped instead of stored, e.g. `DEBUG=1000,INFO=5000`
+//! sheds DEBUG first and INFO as well once the storm grows. The rate counts every valid
+//! log arriving through any ingestion route, each log of a batch or stream included and
+//! shed ones too, so shedding lasts as long as the storm does. `ERROR` and `WARN` are
+//! never shed.
+//!
+//! With `REDIS_URL`, every replica adds its arrivals to a per-second counter in Redis a few
+//! times a second and sheds on the rate of all replicas together, so the thresholds hold
+//! for the whole deployment. While Redis is unreachable, or the shared rate is more than
+//! a couple of seconds old, each replica sheds on its own rate.
+
+use crate::shared_store::{SharedStore, KEY_PREFIX};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// How often arrivals are added to the shared counter.
+const SHARE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shared rates older than this are ignored in favour of the instance's own.
+const SHARED_RATE_MAX_AGE: Duration = Duration::from_secs(2);
+
+/// How long a second's shared counter is kept.
+const SHARED_COUNTER_TTL_SECS: i64 = 10;
+
+/// Levels that always reach the database, whatever the configuration says.
+const PROTECTED_LEVELS: &[&str] = &["ERROR", "WARN"];
+
+pub struct LoadShedder {
+    /// Level to the rate above which it is shed
+    thresholds: HashMap<String, u64>,
+    window: Mutex<Window>,
+    /// Whether the last Redis command failed, so outages are logged once
+    shared_failing: AtomicBool,
+}
+
+/// Arrivals counted in fixed one-second windows.
+struct Window {
+    started: Instant,
+    count: u64,
+    /// Arrivals in the window just before this one
+    previous: u64,
+    /// Arrivals not yet added to the shared counter
+    unshared: u64,
+    shared: Option<SharedRate>,
+}
+
+/// Arrivals at every replica, as of the last time this one added its own.
+#[derive(Clone, Copy)]
+struct SharedRate {
+    /// So far in the current second, this replica's included
+    current: u64,
+    /// In the second before it
+    previous: u64,
+    updated: Instant,
+}
+
+impl Window {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            count: 0,
+            previous: 0,
+            unshared: 0,
+            shared: None,
+        }
+    }
+}
+
+impl LoadShedder {
+    pub fn from_env() -> Option<Self> {
+        let spec = std::env::var("LOAD_SHED_THRESHOLDS").ok()?;
+        let mut thresholds = HashMap::new();
+        for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match pair.split_once('=').map(|(l, r)| (l.trim().to_uppercase(), r.trim().parse::<u64>())) {
+                Some((level, _)) if PROTECTED_LEVELS.contains(&level.as_str()) => {
+                    warn!("Ignoring LOAD_SHED_THRESHOLDS entry '{}': {} logs are never shed", pair, level)
+                }
+                Some((level, Ok(rate))) if crate::LEVELS.contains(&level.as_str()) => {
+                    thresholds.insert(level, rate);
+                }
+                _ => warn!("Ignoring invalid LOAD_SHED_THRESHOLDS entry '{}'", pair),
+            }
+        }
+        if thresholds.is_empty() {
+            return None;
+        }
+        info!("Shedding logs above these ingest rates (per second): {:?}", thresholds);
+        Some(Self {
+            thresholds,
+            window: Mutex::new(Window::new()),
+            shared_failing: AtomicBool::new(false),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Window> {
+        self.window.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Counts one arriving log at `level` and returns whether it should be dropped.
+    pub fn should_shed(&self, level: &str) -> bool {
+        let rate = {
+            let mut window = self.lock();
+            let elapsed = window.started.elapsed();
+            if elapsed >= Duration::from_secs(1) {
+                // After a quiet gap longer than a window, the previous one saw nothing
+                window.previous = if elapsed < Duration::from_secs(2) { window.count } else { 0 };
+                window.started = Instant::now();
+                window.count = 0;
+            }
+            window.count += 1;
+            window.unshared += 1;
+            // The current window alone already shows a surge before it has ended
+            let local = window.count.max(window.previous);
+            match window.shared.filter(|shared| shared.updated.elapsed() < SHARED_RATE_MAX_AGE) {
+                Some(shared) => local.max(shared.previous).max(shared.current + window.unshared),
+                None => local,
+            }
+        };
+        self.thresholds.get(level).is_some_and(|&threshold| rate > threshold)
+    }
+}
+
+/// Adds this replica's arrivals to the shared per-second counter every `SHARE_INTERVAL`
+/// and takes back the rate of all replicas.
+pub async fn share(shedder: Arc<LoadShedder>, store: Arc<SharedStore>) {
+    info!("Shedding on the ingest rate of all replicas, shared through Redis");
+    let mut ticker = tokio::time::interval(SHARE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        shedder.share_once(&store).await;
+    }
+}
+
+impl LoadShedder {
+    async fn share_once(&self, store: &SharedStore) {
+        let arrived = std::mem::take(&mut self.lock().unshared);
+        let second = Utc::now().timestamp();
+        let (key, previous_key) = (shared_key(second), shared_key(second - 1));
+        let mut pipeline = redis::pipe();
+        pipeline
+            .atomic()
+            .incr(&key, arrived)
+            .expire(&key, SHARED_COUNTER_TTL_SECS)
+            .ignore()
+            .get(&previous_key);
+        match store.query::<(u64, Option<u64>)>(&pipeline).await {
+            Ok((current, previous)) => {
+                if self.shared_failing.swap(false, Ordering::Relaxed) {
+                    info!("Redis is reachable again; shedding on the ingest rate of all replicas");
+                }
+                self.lock().shared = Some(SharedRate {
+                    current,
+                    previous: previous.unwrap_or(0),
+                    updated: Instant::now(),
+                });
+            }
+            // The arrivals are left out of the shared count, which only lasts seconds anyway
+            Err(e) => {
+                if !self.shared_failing.swap(true, Ordering::Relaxed) {
+                    warn!("Redis is unavailable, shedding on this instance's ingest rate only: {}", e);
+                }
+            }
+        }
+    }
+}
+
+fn shared_key(second: i64) -> String {
+    format!("{}ingest:{}", KEY_PREFIX, second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shedder(thresholds: &[(&str, u64)]) -> LoadShedder {
+        LoadShedder {
+            thresholds: thresholds.iter().map(|&(level, rate)| (level.to_string(), rate)).collect(),
+            window: Mutex::new(Window::new()),
+            shared_failing: AtomicBool::new(false),
+        }
+    }
+
+    /// Makes the current window end `by` ago.
+    fn end_window(shedder: &LoadShedder, by: Duration) {
+        let mut window = shedder.window.lock().unwrap();
+        window.started -= Duration::from_secs(1) + by;
+    }
+
+    #[test]
+    fn sheds_a_level_once_its_rate_is_exceeded() {
+        let shedder = shedder(&[("DEBUG", 3)]);
+        let shed: Vec<bool> = (0..5).map(|_| shedder.should_shed("DEBUG")).collect();
+        assert_eq!(shed, [false, false, false, true, true]);
+    }
+
+    #[test]
+    fn levels_without_a_threshold_are_kept_but_counted() {
+        let shedder = shedder(&[("DEBUG", 3)]);
+        for _ in 0..10 {
+            assert!(!shedder.should_shed("ERROR"));
+            assert!(!shedder.should_shed("INFO"));
+        }
+        assert!(shedder.should_shed("DEBUG"));
+    }
+
+    #[test]
+    fn lower_thresholds_shed_first() {
+        let shedder = shedder(&[("DEBUG", 2), ("INFO", 4)]);
+        for _ in 0..2 {
+            shedder.should_shed("WARN");
+        }
+        assert!(shedder.should_shed("DEBUG"));
+        assert!(!shedder.should_shed("INFO"));
+        shedder.should_shed("WARN");
+        assert!(shedder.should_shed("INFO"));
+    }
+
+    #[test]
+    fn the_previous_window_keeps_shedding_going() {
+        let shedder = shedder(&[("DEBUG", 3)]);
+        for _ in 0..5 {
+            shedder.should_shed("INFO");
+        }
+        end_window(&shedder, Duration::ZERO);
+        assert!(shedder.should_shed("DEBUG"));
+    }
+
+    #[test]
+    fn shedding_stops_after_a_quiet_gap() {
+        let shedder = shedder(&[("DEBUG", 3)]);
+        for _ in 0..5 {
+            shedder.should_shed("INFO");
+        }
+        end_window(&shedder, Duration::from_secs(1));
+        assert!(!shedder.should_shed("DEBUG"));
+    }
+
+    fn share(shedder: &LoadShedder, current: u64, previous: u64, age: Duration) {
+        shedder.lock().shared = Some(SharedRate {
+            current,
+            previous,
+            updated: Instant::now() - age,
+        });
+    }
+
+    #[test]
+    fn sheds_on_the_rate_of_all_replicas() {
+        let after_a_storm = shedder(&[("DEBUG", 3)]);
+        share(&after_a_storm, 0, 10, Duration::ZERO);
+        assert!(after_a_storm.should_shed("DEBUG"));
+
+        let during_one = shedder(&[("DEBUG", 3)]);
+        share(&during_one, 2, 0, Duration::ZERO);
+        assert!(!during_one.should_shed("DEBUG"));
+        assert!(during_one.should_shed("DEBUG"));
+    }
+
+    #[test]
+    fn stale_shared_rates_are_ignored() {
+        let shedder = shedder(&[("DEBUG", 3)]);
+        share(&shedder, 10, 10, SHARED_RATE_MAX_AGE);
+        assert!(!shedder.should_shed("DEBUG"));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_local_rate_without_redis() {
+        let shedder = shedder(&[("DEBUG", 3)]);
+        for _ in 0..3 {
+            shedder.should_shed("INFO");
+        }
+        shedder.share_once(&SharedStore::unreachable()).await;
+        assert!(shedder.shared_failing.load(Ordering::Relaxed));
+        assert!(shedder.lock().shared.is_none());
+        assert_eq!(shedder.lock().unshared, 0);
+        assert!(shedder.should_shed("DEBUG"));
+    }
+}
@@ -0,0 +1,149 @@
+//! Optional tamper-evidence signatures on stored logs.
+//!
+//! With `SIGNING_KEY` set, every log gets an HMAC-SHA256 over its service, level, message
+//! and timestamp when it is ingested, stored in the `signature` column, and
+//! `GET /logs/{id}/verify` recomputes it. The timestamp is taken by the server rather than
+//! the database so it can be signed before the insert; timestamps are kept strictly
+//! increasing, which preserves the order of a batch.
+//!
+//! The signature only proves that the signed fields were not changed by someone without
+//! the key. Whoever holds the key (or can read this server's environment) can forge
+//! signatures, deleted logs leave nothing behind to verify, and `metadata` is not covered.
+//! Logs stored before the key was set are unsigned, and reclassifying a log changes its
+//! level, so it fails verification until rolled back.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::atomic::{AtomicI64, Ordering};
+use tracing::info;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub struct LogSigner {
+    key: Vec<u8>,
+    /// Microseconds since the epoch of the last timestamp handed out
+    last_micros: AtomicI64,
+}
+
+impl LogSigner {
+    pub fn from_env() -> Option<Self> {
+        let key = std::env::var("SIGNING_KEY").ok().filter(|k| !k.is_empty())?;
+        info!("Signing ingested logs");
+        Some(Self {
+            key: key.into_bytes(),
+            last_micros: AtomicI64::new(0),
+        })
+    }
+
+    /// The timestamp for a log ingested now, truncated to the microseconds Postgres
+    /// stores and later than every timestamp returned before.
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        let now = Utc::now().timestamp_micros();
+        let previous = self
+            .last_micros
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some(now.max(last + 1)))
+            .unwrap_or(now);
+        DateTime::from_timestamp_micros(now.max(previous + 1)).unwrap_or_else(Utc::now)
+    }
+
+    /// Hex HMAC of the signed fields. `message` must be the full message, not the
+    /// beginning kept when it is stored compressed.
+    pub fn sign(&self, service: &str, level: &str, message: &str, timestamp: DateTime<Utc>) -> String {
+        hex::encode(self.mac(service, level, message, timestamp).finalize().into_bytes())
+    }
+
+    /// Whether `signature` matches the fields, compared in constant time.
+    pub fn verify(&self, service: &str, level: &str, message: &str, timestamp: DateTime<Utc>, signature: &str) -> bool {
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+        self.mac(service, level, message, timestamp).verify_slice(&signature).is_ok()
+    }
+
+    fn mac(&self, service: &str, level: &str, message: &str, timestamp: DateTime<Utc>) -> HmacSha256 {
+        // A JSON array keeps field boundaries unambiguous whatever the fields contain
+        let timestamp = timestamp.to_rfc3339_opts(SecondsFormat::Micros, true);
+        let signed = serde_json::to_vec(&(service, level, message, timestamp)).unwrap_or_default();
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(&signed);
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn signer_with(key: &str) -> LogSigner {
+        LogSigner {
+            key: key.as_bytes().to_vec(),
+            last_micros: AtomicI64::new(0),
+        }
+    }
+
+    #[test]
+    fn signatures_verify_until_a_field_changes() {
+        let signer = signer_with("secret");
+        let at = signer.timestamp();
+        let signature = signer.sign("api", "ERROR", "disk full", at);
+        assert!(signer.verify("api", "ERROR", "disk full", at, &signature));
+
+        assert!(!signer.verify("web", "ERROR", "disk full", at, &signature));
+        assert!(!signer.verify("api", "WARN", "disk full", at, &signature));
+        assert!(!signer.verify("api", "ERROR", "disk ful", at, &signature));
+        assert!(!signer.verify("api", "ERROR", "disk full", at + chrono::TimeDelta::microseconds(1), &signature));
+        assert!(!signer_with("other").verify("api", "ERROR", "disk full", at, &signature));
+        assert!(!signer.verify("api", "ERROR", "disk full", at, "not hex"));
+        assert!(!signer.verify("api", "ERROR", "disk full", at, &signature[..signature.len() - 2]));
+    }
+
+    #[test]
+    fn field_boundaries_are_signed() {
+        let signer = signer_with("secret");
+        let at = signer.timestamp();
+        assert_ne!(signer.sign("ab", "c", "m", at), signer.sign("a", "bc", "m", at));
+        assert_ne!(signer.sign("a", "b\",\"c", "m", at), signer.sign("a\",\"b", "c", "m", at));
+    }
+
+    #[test]
+    fn timestamps_are_whole_microseconds() {
+        let at = signer_with("secret").timestamp();
+        assert_eq!(at.timestamp_subsec_nanos() % 1000, 0);
+    }
+
+    #[test]
+    fn timestamps_strictly_increase() {
+        let signer = signer_with("secret");
+        let mut previous = signer.timestamp();
+        for _ in 0..10_000 {
+            let next = signer.timestamp();
+            assert!(next > previous);
+            previous = next;
+        }
+    }
+
+    #[test]
+    fn timestamps_are_unique_across_threads() {
+        let signer = Arc::new(signer_with("secret"));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let signer = signer.clone();
+                std::thread::spawn(move || (0..1000).map(|_| signer.timestamp()).collect::<Vec<_>>())
+            })
+            .collect();
+        let mut all: Vec<DateTime<Utc>> = threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 4000);
+    }
+
+    #[test]
+    fn timestamps_move_past_a_clock_ahead_of_now() {
+        let signer = signer_with("secret");
+        let ahead = Utc::now().timestamp_micros() + 60_000_000;
+        signer.last_micros.store(ahead, Ordering::Relaxed);
+        assert_eq!(signer.timestamp().timestamp_micros(), ahead + 1);
+    }
+}
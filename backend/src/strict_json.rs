@@ -0,0 +1,108 @@
+//! JSON extractor for ingestion that can reject duplicate object keys.
+//!
+//! `serde_json` silently keeps the last value when a key repeats. With
+//! `REJECT_DUPLICATE_KEYS` enabled, the raw body is scanned first and any object
+//! (at any depth) with a repeated key is rejected with `400`.
+
+use crate::{ApiError, AppState};
+use axum::body::{Body, Bytes};
+use axum::extract::{FromRequest, Request};
+use axum::Json;
+use serde::de::{self, DeserializeOwned, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+
+pub struct IngestJson<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest<AppState> for IngestJson<T> {
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &AppState) -> Result<Self, Self::Rejection> {
+        if !state.reject_duplicate_keys {
+            let Json(value) = Json::<T>::from_request(req, state).await?;
+            return Ok(Self(value));
+        }
+
+        let headers = req.headers().clone();
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(|e| ApiError::new(e.status(), e.body_text()))?;
+
+        // Syntax errors are left for `Json` below so they keep its usual status codes
+        check_duplicate_keys(&bytes)?;
+
+        let mut req = Request::new(Body::from(bytes));
+        *req.headers_mut() = headers;
+        let Json(value) = Json::<T>::from_request(req, state).await?;
+        Ok(Self(value))
+    }
+}
+
+/// Checks one JSON document for repeated keys, for bodies read without this extractor.
+/// Syntax errors are not reported here; the caller's own parse reports them.
+pub fn check_duplicate_keys(bytes: &[u8]) -> Result<(), ApiError> {
+    match serde_json::from_slice::<DuplicateKeyCheck>(bytes) {
+        Err(e) if e.is_data() => Err(ApiError::bad_request(e.to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Walks a JSON document, failing on the first object that repeats a key.
+struct DuplicateKeyCheck;
+
+impl<'de> Deserialize<'de> for DuplicateKeyCheck {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(DuplicateKeyVisitor)
+    }
+}
+
+struct DuplicateKeyVisitor;
+
+impl<'de> Visitor<'de> for DuplicateKeyVisitor {
+    type Value = DuplicateKeyCheck;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any JSON value")
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<Self::Value, E> {
+        Ok(DuplicateKeyCheck)
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<Self::Value, E> {
+        Ok(DuplicateKeyCheck)
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<Self::Value, E> {
+        Ok(DuplicateKeyCheck)
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<Self::Value, E> {
+        Ok(DuplicateKeyCheck)
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<Self::Value, E> {
+        Ok(DuplicateKeyCheck)
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E> {
+        Ok(DuplicateKeyCheck)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        while seq.next_element::<DuplicateKeyCheck>()?.is_some() {}
+        Ok(DuplicateKeyCheck)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut seen = HashSet::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if !seen.insert(key.clone()) {
+                return Err(de::Error::custom(format!("duplicate key '{}'", key)));
+            }
+            map.next_value::<DuplicateKeyCheck>()?;
+        }
+        Ok(DuplicateKeyCheck)
+    }
+}
@@ -0,0 +1,97 @@
+//! Live tailing of newly stored logs over server-sent events.
+//!
+//! Every stored log is broadcast to the open `GET /logs/tail` streams. Each stream holds
+//! a broadcast receiver and a connection for as long as it is open, so their number is
+//! capped both per client address (`TAIL_MAX_CONNECTIONS_PER_CLIENT`) and server-wide
+//! (`TAIL_MAX_CONNECTIONS`).
+
+use crate::LogEntry;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Logs buffered per stream before a slow consumer starts missing entries.
+const CHANNEL_CAPACITY: usize = 1024;
+
+pub enum TailLimit {
+    /// The client already holds its maximum number of streams
+    Client(usize),
+    /// The server holds its maximum number of streams
+    Server(usize),
+}
+
+pub struct TailHub {
+    sender: broadcast::Sender<Arc<LogEntry>>,
+    max_total: usize,
+    max_per_client: usize,
+    active: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl TailHub {
+    pub fn from_env() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            sender,
+            max_total: crate::env_or("TAIL_MAX_CONNECTIONS", 100),
+            max_per_client: crate::env_or("TAIL_MAX_CONNECTIONS_PER_CLIENT", 5),
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Broadcasts a stored log; a no-op while nobody is tailing.
+    pub fn publish(&self, entry: &LogEntry) {
+        if self.sender.receiver_count() > 0 {
+            let _ = self.sender.send(Arc::new(entry.clone()));
+        }
+    }
+
+    /// Opens a stream for `client`, unless that would exceed a connection limit.
+    pub fn subscribe(self: &Arc<Self>, client: IpAddr) -> Result<TailSubscription, TailLimit> {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        let total: usize = active.values().sum();
+        if total >= self.max_total {
+            return Err(TailLimit::Server(self.max_total));
+        }
+        let held = active.entry(client).or_insert(0);
+        if *held >= self.max_per_client {
+            return Err(TailLimit::Client(self.max_per_client));
+        }
+        *held += 1;
+
+        Ok(TailSubscription {
+            receiver: self.sender.subscribe(),
+            _slot: TailSlot {
+                hub: self.clone(),
+                client,
+            },
+        })
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active.lock().unwrap_or_else(|e| e.into_inner()).values().sum()
+    }
+}
+
+pub struct TailSubscription {
+    pub receiver: broadcast::Receiver<Arc<LogEntry>>,
+    _slot: TailSlot,
+}
+
+/// Frees the client's connection slot when the stream is dropped.
+struct TailSlot {
+    hub: Arc<TailHub>,
+    client: IpAddr,
+}
+
+impl Drop for TailSlot {
+    fn drop(&mut self) {
+        let mut active = self.hub.active.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(held) = active.get_mut(&self.client) {
+            *held -= 1;
+            if *held == 0 {
+                active.remove(&self.client);
+            }
+        }
+    }
+}
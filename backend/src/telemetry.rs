@@ -0,0 +1,172 @@
+//! In-process instrumentation rendered in the Prometheus text exposition format.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds (in seconds) for insert latency buckets.
+const INSERT_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+pub struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, name: &str, help: &str, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, bucket.load(Ordering::Relaxed));
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn render(&self, name: &str, help: &str, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, self.0.load(Ordering::Relaxed));
+    }
+}
+
+/// Renders a gauge whose value is owned elsewhere and read at scrape time.
+pub fn render_gauge(name: &str, help: &str, value: u64, out: &mut String) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// A counter split by the values of a single label, all known up front so every series
+/// is exported (as zero) before its first increment.
+pub struct LabeledCounter {
+    label: &'static str,
+    values: &'static [&'static str],
+    counts: Vec<AtomicU64>,
+}
+
+impl LabeledCounter {
+    fn new(label: &'static str, values: &'static [&'static str]) -> Self {
+        Self {
+            label,
+            values,
+            counts: values.iter().map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Increments the series for `value`; unknown values are ignored.
+    pub fn inc(&self, value: &str) {
+        if let Some(i) = self.values.iter().position(|v| *v == value) {
+            self.counts[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn render(&self, name: &str, help: &str, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (value, count) in self.values.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, self.label, value, count.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// Reasons an ingestion attempt can be rejected for.
+pub const REJECTION_REASONS: &[&str] = &[
+    "malformed",
+    "too_large",
+    "empty_service",
+    "bad_level",
+    "empty_message",
+    "missing_metadata",
+    "bad_metadata_type",
+    "over_quota",
+    "storage_full",
+];
+
+pub struct Telemetry {
+    pub insert_duration: Histogram,
+    pub bus_published: Counter,
+    pub bus_dropped: Counter,
+    pub rejected: LabeledCounter,
+    pub shed: LabeledCounter,
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        Self {
+            insert_duration: Histogram::new(INSERT_BUCKETS),
+            bus_published: Counter::default(),
+            bus_dropped: Counter::default(),
+            rejected: LabeledCounter::new("reason", REJECTION_REASONS),
+            shed: LabeledCounter::new("level", crate::LEVELS),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.insert_duration.render(
+            "tidelogs_insert_duration_seconds",
+            "Time spent inserting a single log entry into the database.",
+            &mut out,
+        );
+        self.bus_published.render(
+            "tidelogs_bus_published_total",
+            "Log entries published to the message bus.",
+            &mut out,
+        );
+        self.bus_dropped.render(
+            "tidelogs_bus_dropped_total",
+            "Log entries dropped instead of being published to the message bus.",
+            &mut out,
+        );
+        self.rejected.render(
+            "tidelogs_rejected_total",
+            "Ingestion attempts rejected, by reason.",
+            &mut out,
+        );
+        self.shed.render(
+            "tidelogs_shed_total",
+            "Valid logs dropped by load shedding instead of being stored, by level.",
+            &mut out,
+        );
+        out
+    }
+}